@@ -1,4 +1,5 @@
-use std::collections::HashSet;
+use crate::{linalg, stats};
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Triangle {
@@ -47,7 +48,480 @@ pub fn align(
     triangles
 }
 
-fn generate_all_triangles(
+/// Maps `(x, y)` to `(a * x - b * y + tx, b * x + a * y + ty)`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Similarity {
+    pub a: f32,
+    pub b: f32,
+    pub tx: f32,
+    pub ty: f32,
+}
+
+impl Similarity {
+    pub const IDENTITY: Self = Self {
+        a: 1.0,
+        b: 0.0,
+        tx: 0.0,
+        ty: 0.0,
+    };
+
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            self.a * x - self.b * y + self.tx,
+            self.b * x + self.a * y + self.ty,
+        )
+    }
+
+    pub fn inverse(&self) -> Self {
+        let d = self.a * self.a + self.b * self.b;
+        let a = self.a / d;
+        let b = -self.b / d;
+        Self {
+            a,
+            b,
+            tx: -(a * self.tx - b * self.ty),
+            ty: -(b * self.tx + a * self.ty),
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.a.hypot(self.b)
+    }
+}
+
+/// Polynomial field distortion applied before the [`Similarity`], modeled after the
+/// SIP convention: `x + scale * sum(a_pq * u^p * v^q)` for `2 <= p + q <= order`,
+/// where `(u, v)` are coordinates relative to `center` divided by `scale`.
+///
+/// https://fits.gsfc.nasa.gov/registry/sip/SIP_distortion_v1_0.pdf
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Distortion {
+    pub order: usize,
+    pub center: (f32, f32),
+    pub scale: f32,
+    pub a: Vec<f32>,
+    pub b: Vec<f32>,
+}
+
+impl Distortion {
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        let u = (x - self.center.0) / self.scale;
+        let v = (y - self.center.1) / self.scale;
+        let mut dx = 0.0;
+        let mut dy = 0.0;
+        for (((p, q), a), b) in distortion_terms(self.order).zip(&self.a).zip(&self.b) {
+            let term = u.powi(p) * v.powi(q);
+            dx += a * term;
+            dy += b * term;
+        }
+        (x + dx * self.scale, y + dy * self.scale)
+    }
+}
+
+pub const MAX_DISTORTION_ORDER: usize = 4;
+
+// (p, q) exponents of every distortion term up to `order`
+fn distortion_terms(order: usize) -> impl Iterator<Item = (i32, i32)> {
+    (2..=order as i32).flat_map(|n| (0..=n).map(move |p| (p, n - p)))
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Transform {
+    pub similarity: Similarity,
+    pub distortion: Option<Distortion>,
+}
+
+impl Transform {
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x, y) = match &self.distortion {
+            Some(distortion) => distortion.apply(x, y),
+            None => (x, y),
+        };
+        self.similarity.apply(x, y)
+    }
+
+    /// Inverse of [`Transform::apply`].
+    pub fn invert(&self, x: f32, y: f32) -> (f32, f32) {
+        let (sx, sy) = self.similarity.inverse().apply(x, y);
+        let Some(distortion) = &self.distortion else {
+            return (sx, sy);
+        };
+        // fixed point iteration, converges while the distortion is small
+        let (mut x, mut y) = (sx, sy);
+        for _ in 0..5 {
+            let (dx, dy) = distortion.apply(x, y);
+            x -= dx - sx;
+            y -= dy - sy;
+        }
+        (x, y)
+    }
+
+    /// The same transform for points offset by `offset` in both axes, e.g. from
+    /// `local_max_points` to the raw frame.
+    pub fn offset(&self, offset: f32) -> Self {
+        let mut transform = self.clone();
+        let (ox, oy) = self.similarity.apply(offset, offset);
+        transform.similarity.tx += offset - (ox - self.similarity.tx);
+        transform.similarity.ty += offset - (oy - self.similarity.ty);
+        if let Some(distortion) = &mut transform.distortion {
+            distortion.center.0 += offset;
+            distortion.center.1 += offset;
+        }
+        transform
+    }
+}
+
+impl From<Similarity> for Transform {
+    fn from(similarity: Similarity) -> Self {
+        Self {
+            similarity,
+            distortion: None,
+        }
+    }
+}
+
+/// Distance in pixels between transformed points and their matches.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Residuals {
+    pub count: usize,
+    pub rms: f32,
+    pub median: f32,
+    pub max: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Registration {
+    /// Maps `points` into the coordinates of `reference`.
+    pub transform: Transform,
+    /// `(points index, reference index)`
+    pub pairs: Vec<(usize, usize)>,
+    pub residuals: Residuals,
+}
+
+/// Finds the transform from `points` to `reference`, solving for polynomial
+/// distortion up to `order` (1 being a similarity).
+pub fn register(
+    width: usize,
+    height: usize,
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    threshold: f32,
+    order: usize,
+) -> Option<Registration> {
+    let triangles = align(width, height, points, reference, threshold);
+    let pairs = correspondences(&triangles);
+    let initial = fit_transform(points, reference, &pairs, 1)?;
+
+    // refine with every detection, not just the brightest used for triangles
+    let pairs = match_points(points, reference, &initial.transform, MATCH_RADIUS);
+    let registration = fit_transform(points, reference, &pairs, order)?;
+    let pairs = match_points(points, reference, &registration.transform, MATCH_RADIUS);
+    fit_transform(points, reference, &pairs, order)
+}
+
+/// Residuals of every distortion order fit to the same matches, used to pick the
+/// lowest order that absorbs the field distortion.
+pub fn distortion_orders(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+) -> Vec<(usize, Residuals)> {
+    (1..=MAX_DISTORTION_ORDER)
+        .filter_map(|order| {
+            fit_transform(points, reference, pairs, order).map(|r| (order, r.residuals))
+        })
+        .collect()
+}
+
+const MATCH_RADIUS: f32 = 3.0;
+
+/// Pairs each point with the nearest reference point within `radius` after the
+/// transform, keeping only the closest pair for each reference point.
+pub fn match_points(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    transform: &Transform,
+    radius: f32,
+) -> Vec<(usize, usize)> {
+    let mut nearest: HashMap<usize, (usize, f32)> = HashMap::new();
+    for (i, (x, y, _)) in points.iter().enumerate() {
+        let (x, y) = transform.apply(*x, *y);
+        let closest = reference
+            .iter()
+            .enumerate()
+            .map(|(j, (rx, ry, _))| (j, (rx - x).hypot(ry - y)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((j, distance)) = closest
+            && distance <= radius
+        {
+            let entry = nearest.entry(j).or_insert((i, distance));
+            if distance < entry.1 {
+                *entry = (i, distance);
+            }
+        }
+    }
+    let mut pairs: Vec<_> = nearest.into_iter().map(|(j, (i, _))| (i, j)).collect();
+    pairs.sort();
+    pairs
+}
+
+/// Point correspondences voted on by the vertices of matched triangles.
+pub fn correspondences(triangles: &[(Triangle, Triangle)]) -> Vec<(usize, usize)> {
+    let mut votes: HashMap<(usize, usize), usize> = HashMap::new();
+    for (t1, t2) in triangles.iter() {
+        for (i, j) in t1.point_indices.iter().zip(t2.point_indices.iter()) {
+            *votes.entry((*i, *j)).or_default() += 1;
+        }
+    }
+    let mut votes: Vec<_> = votes.into_iter().collect();
+    votes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    // a single vote is indistinguishable from a coincidental match
+    let min_votes = 2;
+    let mut used1 = HashSet::new();
+    let mut used2 = HashSet::new();
+    let mut pairs = Vec::new();
+    for ((i, j), count) in votes {
+        if count < min_votes {
+            break;
+        }
+        if used1.insert(i) && used2.insert(j) {
+            pairs.push((i, j));
+        }
+    }
+    pairs
+}
+
+// https://en.wikipedia.org/wiki/Random_sample_consensus
+//
+/// Largest subset of `pairs` that agrees with a similarity through any two of them,
+/// since least squares clipping breaks down once outliers are common. Tries every
+/// pair of pairs instead of random samples, there are few correspondences.
+pub fn consensus(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+) -> Vec<(usize, usize)> {
+    // loose enough for field distortion far from the sampled pairs
+    let tolerance = 2.0 * MATCH_RADIUS;
+    let mut best = Vec::new();
+    for i in 0..pairs.len() {
+        for j in i + 1..pairs.len() {
+            let Some(similarity) = fit_similarity(points, reference, &[pairs[i], pairs[j]]) else {
+                continue;
+            };
+            let inliers: Vec<_> = pairs
+                .iter()
+                .filter(|(p, r)| {
+                    let (x, y) = similarity.apply(points[*p].0, points[*p].1);
+                    (reference[*r].0 - x).hypot(reference[*r].1 - y) <= tolerance
+                })
+                .copied()
+                .collect();
+            if inliers.len() > best.len() {
+                best = inliers;
+            }
+        }
+    }
+    best
+}
+
+/// Least squares fit with iterative outlier rejection.
+pub fn fit_transform(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+    order: usize,
+) -> Option<Registration> {
+    assert!((1..=MAX_DISTORTION_ORDER).contains(&order));
+
+    let fit = |pairs: &[(usize, usize)]| {
+        if order > 1 {
+            fit_distortion(points, reference, pairs, order)
+        } else {
+            fit_similarity(points, reference, pairs).map(Transform::from)
+        }
+    };
+    let mut pairs = pairs.to_vec();
+    let mut transform = fit(&pairs)?;
+    for _ in 0..3 {
+        let distances = pair_distances(&transform, points, reference, &pairs);
+        let mut sorted = distances.clone();
+        let median = stats::median(&mut sorted);
+        let cutoff = (3.0 * median).max(0.5);
+        let inliers: Vec<_> = pairs
+            .iter()
+            .zip(distances.iter())
+            .filter(|(_, d)| **d <= cutoff)
+            .map(|(p, _)| *p)
+            .collect();
+        if inliers.len() == pairs.len() {
+            break;
+        }
+        pairs = inliers;
+        // the residuals below describe the transform fit to exactly these pairs
+        transform = fit(&pairs)?;
+    }
+
+    let distances = pair_distances(&transform, points, reference, &pairs);
+    let residuals = residuals(&distances);
+    Some(Registration {
+        transform,
+        pairs,
+        residuals,
+    })
+}
+
+// https://en.wikipedia.org/wiki/Procrustes_analysis
+fn fit_similarity(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+) -> Option<Similarity> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let (mut cx1, mut cy1, mut cx2, mut cy2) = (0.0, 0.0, 0.0, 0.0);
+    for (i, j) in pairs.iter() {
+        cx1 += points[*i].0 as f64 / n;
+        cy1 += points[*i].1 as f64 / n;
+        cx2 += reference[*j].0 as f64 / n;
+        cy2 += reference[*j].1 as f64 / n;
+    }
+    let (mut sa, mut sb, mut norm) = (0.0, 0.0, 0.0);
+    for (i, j) in pairs.iter() {
+        let x1 = points[*i].0 as f64 - cx1;
+        let y1 = points[*i].1 as f64 - cy1;
+        let x2 = reference[*j].0 as f64 - cx2;
+        let y2 = reference[*j].1 as f64 - cy2;
+        sa += x1 * x2 + y1 * y2;
+        sb += x1 * y2 - y1 * x2;
+        norm += x1 * x1 + y1 * y1;
+    }
+    if norm == 0.0 {
+        return None;
+    }
+    let a = sa / norm;
+    let b = sb / norm;
+    Some(Similarity {
+        a: a as f32,
+        b: b as f32,
+        tx: (cx2 - (a * cx1 - b * cy1)) as f32,
+        ty: (cy2 - (b * cx1 + a * cy1)) as f32,
+    })
+}
+
+// Solves the similarity and the distortion together. With `R` the rotation and
+// scale of the similarity, `S(p + D(p)) = S(p) + R D(p)`, which is linear in the
+// similarity and in the coefficients of `R D`, so one least squares fits both.
+fn fit_distortion(
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+    order: usize,
+) -> Option<Transform> {
+    let terms: Vec<_> = distortion_terms(order).collect();
+    if pairs.len() * 2 < 4 + 2 * terms.len() {
+        return None;
+    }
+    let n = pairs.len() as f32;
+    let cx = pairs.iter().map(|(i, _)| points[*i].0).sum::<f32>() / n;
+    let cy = pairs.iter().map(|(i, _)| points[*i].1).sum::<f32>() / n;
+    let scale = pairs
+        .iter()
+        .map(|(i, _)| (points[*i].0 - cx).abs().max((points[*i].1 - cy).abs()))
+        .fold(1.0, f32::max);
+
+    // unknowns: a, b, tx, ty in scaled coordinates, then R D for x and for y
+    let k = terms.len();
+    let mut rows = Vec::with_capacity(2 * pairs.len());
+    let mut rhs = Vec::with_capacity(2 * pairs.len());
+    for (i, j) in pairs.iter() {
+        let u = ((points[*i].0 - cx) / scale) as f64;
+        let v = ((points[*i].1 - cy) / scale) as f64;
+        let monomials: Vec<_> = terms.iter().map(|(p, q)| u.powi(*p) * v.powi(*q)).collect();
+
+        let mut row = vec![0.0; 4 + 2 * k];
+        row[..4].copy_from_slice(&[u, -v, 1.0, 0.0]);
+        row[4..4 + k].copy_from_slice(&monomials);
+        rows.push(row);
+        rhs.push(((reference[*j].0 - cx) / scale) as f64);
+
+        let mut row = vec![0.0; 4 + 2 * k];
+        row[..4].copy_from_slice(&[v, u, 0.0, 1.0]);
+        row[4 + k..].copy_from_slice(&monomials);
+        rows.push(row);
+        rhs.push(((reference[*j].1 - cy) / scale) as f64);
+    }
+    let solution = linalg::least_squares(&rows, &rhs)?;
+    let (a, b) = (solution[0], solution[1]);
+    let d = a * a + b * b;
+    if d == 0.0 {
+        return None;
+    }
+    let (cx64, cy64, scale64) = (cx as f64, cy as f64, scale as f64);
+    // back from scaled coordinates around the center
+    let tx = solution[2] * scale64 + cx64 - (a * cx64 - b * cy64);
+    let ty = solution[3] * scale64 + cy64 - (b * cx64 + a * cy64);
+    let (rx, ry) = (&solution[4..4 + k], &solution[4 + k..]);
+
+    Some(Transform {
+        similarity: Similarity {
+            a: a as f32,
+            b: b as f32,
+            tx: tx as f32,
+            ty: ty as f32,
+        },
+        distortion: Some(Distortion {
+            order,
+            center: (cx, cy),
+            scale,
+            // D = R^-1 (R D)
+            a: (0..k)
+                .map(|t| ((a * rx[t] + b * ry[t]) / d) as f32)
+                .collect(),
+            b: (0..k)
+                .map(|t| ((a * ry[t] - b * rx[t]) / d) as f32)
+                .collect(),
+        }),
+    })
+}
+
+fn pair_distances(
+    transform: &Transform,
+    points: &[(f32, f32, f32)],
+    reference: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+) -> Vec<f32> {
+    pairs
+        .iter()
+        .map(|(i, j)| {
+            let (x, y) = transform.apply(points[*i].0, points[*i].1);
+            (reference[*j].0 - x).hypot(reference[*j].1 - y)
+        })
+        .collect()
+}
+
+fn residuals(distances: &[f32]) -> Residuals {
+    if distances.is_empty() {
+        return Residuals::default();
+    }
+    let count = distances.len();
+    let rms = (distances.iter().map(|d| d * d).sum::<f32>() / count as f32).sqrt();
+    let max = distances.iter().copied().fold(0.0, f32::max);
+    let median = stats::median(&mut distances.to_vec());
+    Residuals {
+        count,
+        rms,
+        median,
+        max,
+    }
+}
+
+/// Every triangle between the first `take` points, with coordinates normalized by
+/// `width` and `height`.
+pub fn generate_all_triangles(
     width: usize,
     height: usize,
     points: &[(f32, f32, f32)],
@@ -93,12 +567,14 @@ fn generate_all_triangles(
                 let p2p3 = ((p2x - p3x) * (p2x - p3x) + (p2y - p3y) * (p2y - p3y)).sqrt();
                 let p1p3 = ((p1x - p3x) * (p1x - p3x) + (p1y - p3y) * (p1y - p3y)).sqrt();
 
-                // sort edges
-                let mut edge_lengths = [p1p2, p2p3, p1p3];
-                edge_lengths.sort_by(|a, b| a.total_cmp(b));
+                // sort edges, keeping each point opposite to its edge so that
+                // matched triangles also match their points
+                let mut edges = [(p1p2, k), (p2p3, i), (p1p3, j)];
+                edges.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let edge_lengths = edges.map(|(length, _)| length);
+                let point_indices = edges.map(|(_, index)| index);
                 let mut edge_luminance = [p1l, p2l, p3l];
                 edge_luminance.sort_by(|a, b| a.total_cmp(b));
-                let point_indices = [i, j, k];
 
                 triangles.push(Triangle {
                     edge_lengths,
@@ -0,0 +1,311 @@
+use crate::image::{self, Image};
+use crate::metrics::Star;
+use crate::{linalg, stats};
+use tint::Srgb;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Model {
+    /// 2D polynomial of the given degree, for smooth gradients.
+    Polynomial(usize),
+    // https://en.wikipedia.org/wiki/Thin_plate_spline
+    //
+    /// Thin plate spline through the samples, for complex gradients. Higher
+    /// smoothing trades fidelity to the samples for a smoother surface.
+    Rbf { smoothing: f32 },
+}
+
+impl Model {
+    /// `polynomial <degree>` or `rbf <smoothing>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let words: Vec<_> = value.split_whitespace().collect();
+        match words[..] {
+            ["polynomial", degree] => degree
+                .parse()
+                .map(Self::Polynomial)
+                .map_err(|_| format!("{degree} is not a degree")),
+            ["rbf", smoothing] => match smoothing.parse::<f32>() {
+                Ok(smoothing) if smoothing >= 0.0 => Ok(Self::Rbf { smoothing }),
+                _ => Err(format!("{smoothing} is not a smoothing")),
+            },
+            _ => Err(format!(
+                "expected polynomial <degree> or rbf <smoothing>, got {value}"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Correction {
+    /// For additive gradients such as light pollution.
+    Subtract,
+    /// For multiplicative gradients such as vignetting.
+    Divide,
+}
+
+impl Correction {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "subtract" => Ok(Self::Subtract),
+            "divide" => Ok(Self::Divide),
+            value => Err(format!("expected subtract or divide, got {value}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Settings {
+    pub model: Model,
+    pub correction: Correction,
+    /// Samples per side.
+    pub grid: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Sample {
+    pub x: f32,
+    pub y: f32,
+    /// Median of each channel within the sample box.
+    pub values: [f32; 3],
+    /// Rejected for lying on a star or bright structure.
+    pub rejected: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Extraction {
+    pub samples: Vec<Sample>,
+    pub model: [Image<f32>; 3],
+    /// Flat background level of each channel after the correction.
+    pub level: [f32; 3],
+    /// Model stretched to its range for display.
+    pub preview: Image<Srgb>,
+}
+
+/// Fits and removes the background of `channels` in place. `stars` are in pixels of
+/// a frame `star_scale` times smaller than `channels`.
+pub fn extract(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    settings: Settings,
+) -> Extraction {
+    let samples = place_samples(channels, stars, star_scale, settings.grid);
+    let accepted: Vec<_> = samples.iter().filter(|s| !s.rejected).collect();
+
+    let model: [Image<f32>; 3] = std::array::from_fn(|c| {
+        let points: Vec<_> = accepted.iter().map(|s| (s.x, s.y, s.values[c])).collect();
+        render_model(
+            &points,
+            settings.model,
+            channels[c].width,
+            channels[c].height,
+        )
+    });
+
+    let mut level = [0.0; 3];
+    for ((channel, model), level) in channels.iter_mut().zip(model.iter()).zip(level.iter_mut()) {
+        let mut values = model.pixels.clone();
+        // keep the overall level so that later stretches behave the same
+        let pedestal = stats::median(&mut values);
+        *level = pedestal;
+        for (v, m) in channel.pixels.iter_mut().zip(model.pixels.iter()) {
+            *v = match settings.correction {
+                Correction::Subtract => *v - m + pedestal,
+                Correction::Divide => *v / m.max(f32::EPSILON) * pedestal,
+            };
+        }
+    }
+
+    let preview = std::array::from_fn(|c| {
+        let min = model[c].pixels.iter().copied().fold(f32::MAX, f32::min);
+        let max = model[c].pixels.iter().copied().fold(f32::MIN, f32::max);
+        let range = (max - min).max(f32::EPSILON);
+        Image {
+            pixels: model[c].pixels.iter().map(|v| (v - min) / range).collect(),
+            width: model[c].width,
+            height: model[c].height,
+        }
+    });
+    Extraction {
+        samples,
+        preview: image::channels_to_srgb(&preview),
+        model,
+        level,
+    }
+}
+
+fn place_samples(
+    channels: &[Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    grid: usize,
+) -> Vec<Sample> {
+    assert!(grid > 0);
+    let width = channels[0].width;
+    let height = channels[0].height;
+    let cell_width = width as f32 / grid as f32;
+    let cell_height = height as f32 / grid as f32;
+    let half = (cell_width.min(cell_height) / 6.0).max(2.0) as i32;
+
+    let mut samples = Vec::with_capacity(grid * grid);
+    let mut values = Vec::new();
+    for gy in 0..grid {
+        for gx in 0..grid {
+            let x = (gx as f32 + 0.5) * cell_width;
+            let y = (gy as f32 + 0.5) * cell_height;
+            let on_star = stars.iter().any(|s| {
+                let radius = (3.0 * s.fwhm).max(4.0) * star_scale + half as f32;
+                (s.x * star_scale - x).hypot(s.y * star_scale - y) < radius
+            });
+
+            let sample = std::array::from_fn(|c| {
+                values.clear();
+                for sy in (y as i32 - half).max(0)..(y as i32 + half).min(height as i32) {
+                    for sx in (x as i32 - half).max(0)..(x as i32 + half).min(width as i32) {
+                        let v = channels[c].pixels[sy as usize * width + sx as usize];
+                        if !v.is_nan() {
+                            values.push(v);
+                        }
+                    }
+                }
+                stats::median(&mut values)
+            });
+            samples.push(Sample {
+                x,
+                y,
+                values: sample,
+                rejected: on_star,
+            });
+        }
+    }
+
+    // samples far above the rest sit on nebulosity or unresolved stars
+    let sigma = 3.0;
+    let mut luminance: Vec<_> = samples
+        .iter()
+        .filter(|s| !s.rejected)
+        .map(|s| s.values.iter().sum::<f32>())
+        .collect();
+    let median = stats::median(&mut luminance);
+    let deviation = stats::mad(&mut luminance, median);
+    for sample in samples.iter_mut() {
+        let l: f32 = sample.values.iter().sum();
+        if (l - median).abs() > sigma * deviation.max(f32::EPSILON) {
+            sample.rejected = true;
+        }
+    }
+    samples
+}
+
+/// Evaluates the model on a coarse grid and interpolates, since a spline with
+/// hundreds of samples is too slow to evaluate at every pixel.
+fn render_model(
+    points: &[(f32, f32, f32)],
+    model: Model,
+    width: usize,
+    height: usize,
+) -> Image<f32> {
+    let step = 16;
+    let coarse_width = width.div_ceil(step) + 1;
+    let coarse_height = height.div_ceil(step) + 1;
+    let surface = fit(points, model, width, height);
+    let coarse = Image {
+        pixels: (0..coarse_width * coarse_height)
+            .map(|i| {
+                let x = ((i % coarse_width) * step) as f32;
+                let y = ((i / coarse_width) * step) as f32;
+                surface(x, y)
+            })
+            .collect(),
+        width: coarse_width,
+        height: coarse_height,
+    };
+    Image {
+        pixels: (0..width * height)
+            .map(|i| {
+                let x = (i % width) as f32 / step as f32;
+                let y = (i / width) as f32 / step as f32;
+                coarse.sample_bilinear(x, y).unwrap_or(0.0)
+            })
+            .collect(),
+        width,
+        height,
+    }
+}
+
+/// Surface through `(x, y, value)` points. Falls back to the median level if the
+/// samples cannot constrain the model.
+fn fit(
+    points: &[(f32, f32, f32)],
+    model: Model,
+    width: usize,
+    height: usize,
+) -> Box<dyn Fn(f32, f32) -> f32> {
+    // normalized coordinates keep the systems well conditioned
+    let scale = width.max(height) as f64;
+    let normalize = move |x: f32, y: f32| (x as f64 / scale, y as f64 / scale);
+    let mut values: Vec<_> = points.iter().map(|p| p.2).collect();
+    let level = stats::median(&mut values);
+
+    match model {
+        Model::Polynomial(degree) => {
+            let terms = move |x: f64, y: f64| {
+                (0..=degree as i32)
+                    .flat_map(move |n| (0..=n).map(move |p| x.powi(p) * y.powi(n - p)))
+                    .collect::<Vec<_>>()
+            };
+            let rows: Vec<_> = points
+                .iter()
+                .map(|(x, y, _)| {
+                    let (x, y) = normalize(*x, *y);
+                    terms(x, y)
+                })
+                .collect();
+            let rhs: Vec<_> = points.iter().map(|p| p.2 as f64).collect();
+            match linalg::least_squares(&rows, &rhs) {
+                Some(coefficients) => Box::new(move |x, y| {
+                    let (x, y) = normalize(x, y);
+                    terms(x, y)
+                        .iter()
+                        .zip(coefficients.iter())
+                        .map(|(t, c)| t * c)
+                        .sum::<f64>() as f32
+                }),
+                None => Box::new(move |_, _| level),
+            }
+        }
+        Model::Rbf { smoothing } => {
+            let kernel = |r2: f64| if r2 > 0.0 { 0.5 * r2 * r2.ln() } else { 0.0 };
+            let centers: Vec<_> = points.iter().map(|(x, y, _)| normalize(*x, *y)).collect();
+            let n = centers.len();
+            let size = n + 3;
+
+            // [K + smoothing * I, P; P^T, 0] [w; c] = [v; 0]
+            let mut a = vec![0.0; size * size];
+            let mut b = vec![0.0; size];
+            for (i, (xi, yi)) in centers.iter().enumerate() {
+                for (j, (xj, yj)) in centers.iter().enumerate() {
+                    a[i * size + j] = kernel((xi - xj).powi(2) + (yi - yj).powi(2));
+                }
+                a[i * size + i] += smoothing as f64;
+                for (k, p) in [1.0, *xi, *yi].into_iter().enumerate() {
+                    a[i * size + n + k] = p;
+                    a[(n + k) * size + i] = p;
+                }
+                b[i] = points[i].2 as f64;
+            }
+            match linalg::solve(a, b) {
+                Some(solution) => Box::new(move |x, y| {
+                    let (x, y) = normalize(x, y);
+                    let affine = solution[n] + solution[n + 1] * x + solution[n + 2] * y;
+                    let spline: f64 = centers
+                        .iter()
+                        .zip(solution.iter())
+                        .map(|((cx, cy), w)| w * kernel((x - cx).powi(2) + (y - cy).powi(2)))
+                        .sum();
+                    (affine + spline) as f32
+                }),
+                None => Box::new(move |_, _| level),
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/// A star from a local catalog file, positions in degrees.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub ra: f64,
+    pub dec: f64,
+    pub magnitude: f32,
+    /// B-V color index.
+    pub b_v: Option<f32>,
+}
+
+/// Reads `ra,dec,mag[,b_v]` lines, skipping blank lines, `#` comments and a header
+/// row. Entries are sorted brightest first.
+pub fn read(path: &str) -> Result<Vec<Entry>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        let number = |index: usize| fields.get(index).and_then(|f| f.parse::<f64>().ok());
+        match (number(0), number(1), number(2)) {
+            (Some(ra), Some(dec), Some(magnitude)) => entries.push(Entry {
+                ra,
+                dec,
+                magnitude: magnitude as f32,
+                b_v: number(3).map(|v| v as f32),
+            }),
+            _ if entries.is_empty() && i == 0 => {}
+            _ => return Err(format!("{path}:{}: expected `ra,dec,mag[,b_v]`", i + 1)),
+        }
+    }
+    entries.sort_by(|a, b| a.magnitude.total_cmp(&b.magnitude));
+    Ok(entries)
+}
+
+/// Mean direction of `entries` as `(ra, dec)` in degrees.
+pub fn center(entries: &[Entry]) -> (f64, f64) {
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+    for entry in entries.iter() {
+        let (ra, dec) = (entry.ra.to_radians(), entry.dec.to_radians());
+        x += dec.cos() * ra.cos();
+        y += dec.cos() * ra.sin();
+        z += dec.sin();
+    }
+    (
+        y.atan2(x).to_degrees().rem_euclid(360.0),
+        z.atan2(x.hypot(y)).to_degrees(),
+    )
+}
+
+// https://mathworld.wolfram.com/GnomonicProjection.html
+//
+/// Standard coordinates `(xi, eta)` in degrees of `(ra, dec)` on the plane tangent
+/// to the sky at `center`, `None` on the far hemisphere.
+pub fn project(center: (f64, f64), ra: f64, dec: f64) -> Option<(f64, f64)> {
+    let (ra0, dec0) = (center.0.to_radians(), center.1.to_radians());
+    let (ra, dec) = (ra.to_radians(), dec.to_radians());
+    let cos_c = dec0.sin() * dec.sin() + dec0.cos() * dec.cos() * (ra - ra0).cos();
+    if cos_c <= 0.0 {
+        return None;
+    }
+    let xi = dec.cos() * (ra - ra0).sin() / cos_c;
+    let eta = (dec0.cos() * dec.sin() - dec0.sin() * dec.cos() * (ra - ra0).cos()) / cos_c;
+    Some((xi.to_degrees(), eta.to_degrees()))
+}
+
+/// Inverse of [`project`].
+pub fn unproject(center: (f64, f64), xi: f64, eta: f64) -> (f64, f64) {
+    let (ra0, dec0) = (center.0.to_radians(), center.1.to_radians());
+    let (xi, eta) = (xi.to_radians(), eta.to_radians());
+    let rho = xi.hypot(eta);
+    if rho == 0.0 {
+        return center;
+    }
+    let c = rho.atan();
+    let dec = (c.cos() * dec0.sin() + eta * c.sin() * dec0.cos() / rho).asin();
+    let ra = ra0 + (xi * c.sin()).atan2(rho * dec0.cos() * c.cos() - eta * dec0.sin() * c.sin());
+    (ra.to_degrees().rem_euclid(360.0), dec.to_degrees())
+}
+
+/// Angle between two positions in degrees.
+pub fn separation(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (ra1, dec1) = (a.0.to_radians(), a.1.to_radians());
+    let (ra2, dec2) = (b.0.to_radians(), b.1.to_radians());
+    let cos = dec1.sin() * dec2.sin() + dec1.cos() * dec2.cos() * (ra1 - ra2).cos();
+    cos.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projection_round_trip() {
+        for center in [(0.0, 0.0), (359.5, 10.0), (120.0, -60.0), (45.0, 88.0)] {
+            for (dra, ddec) in [(0.0, 0.0), (0.7, -0.3), (-1.5, 1.0), (0.001, 0.002)] {
+                let (ra, dec) = ((center.0 + dra) % 360.0, center.1 + ddec);
+                let (xi, eta) = project(center, ra, dec).unwrap();
+                let back = unproject(center, xi, eta);
+                let dra = (back.0 - ra + 180.0).rem_euclid(360.0) - 180.0;
+                assert!(
+                    dra.abs() < 1e-9 && (back.1 - dec).abs() < 1e-9,
+                    "{back:?} {ra} {dec}"
+                );
+            }
+        }
+        // east is positive xi, north positive eta
+        let (xi, eta) = project((10.0, 0.0), 10.5, 0.0).unwrap();
+        assert!(xi > 0.0 && eta.abs() < 1e-12);
+        let (xi, eta) = project((10.0, 0.0), 10.0, 0.5).unwrap();
+        assert!(xi.abs() < 1e-12 && eta > 0.0);
+        assert_eq!(project((10.0, 0.0), 190.0, 0.0), None);
+    }
+
+    #[test]
+    fn separations() {
+        assert!((separation((0.0, 0.0), (90.0, 0.0)) - 90.0).abs() < 1e-9);
+        assert!((separation((10.0, 89.0), (190.0, 89.0)) - 2.0).abs() < 1e-9);
+        assert!((separation((359.9, 0.0), (0.1, 0.0)) - 0.2).abs() < 1e-9);
+    }
+}
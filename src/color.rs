@@ -0,0 +1,204 @@
+use crate::catalog::{self, Entry};
+use crate::image::Image;
+use crate::metrics::Star;
+use crate::{ALIGN_THRESHOLD, align, linalg, stats};
+
+/// B-V of the star that should come out white, a G2V star like the Sun.
+const WHITE_REFERENCE: f32 = 0.65;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// Background level of each channel before neutralization.
+    pub background: [f32; 3],
+    /// Factor applied to each channel above the background.
+    pub white_balance: [f32; 3],
+    /// Stars that constrained the white balance.
+    pub stars: usize,
+}
+
+// https://pixinsight.com/doc/tools/PhotometricColorCalibration/PhotometricColorCalibration.html
+//
+/// Neutralizes the background and white balances `channels` so that a star with the
+/// color of the Sun is white, from the instrumental colors of `stars` matched to
+/// catalog B-V indices. `stars` are in pixels of a frame `star_scale` times
+/// smaller than `channels`.
+pub fn calibrate(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    catalog: &[Entry],
+) -> Result<Calibration, String> {
+    let matches = match_catalog(
+        channels[0].width,
+        channels[0].height,
+        stars,
+        star_scale,
+        catalog,
+    )
+    .ok_or("no catalog stars matched the detections")?;
+
+    let background: [f32; 3] = std::array::from_fn(|c| {
+        let stride = (channels[c].pixels.len() / 100_000).max(1);
+        let mut values: Vec<_> = channels[c]
+            .pixels
+            .iter()
+            .step_by(stride)
+            .copied()
+            .filter(|v| !v.is_nan())
+            .collect();
+        stats::median(&mut values)
+    });
+
+    // instrumental color indices against catalog color
+    let mut colors = Vec::new();
+    for (star, entry) in matches.iter() {
+        let (star, entry) = (&stars[*star], &catalog[*entry]);
+        let Some(b_v) = entry.b_v else {
+            continue;
+        };
+        let radius = (2.0 * star.fwhm).max(3.0) * star_scale;
+        let flux: Option<Vec<f32>> = (0..3)
+            .map(|c| {
+                aperture_flux(
+                    &channels[c],
+                    star.x * star_scale,
+                    star.y * star_scale,
+                    radius,
+                    background[c],
+                )
+            })
+            .collect();
+        if let Some(flux) = flux
+            && flux.iter().all(|f| *f > 0.0)
+        {
+            colors.push((
+                b_v,
+                (flux[0] / flux[1]).log10(),
+                (flux[2] / flux[1]).log10(),
+            ));
+        }
+    }
+    let min_stars = 3;
+    if colors.len() < min_stars {
+        return Err(format!(
+            "only {} matched stars have a usable color",
+            colors.len()
+        ));
+    }
+
+    let rows: Vec<_> = colors
+        .iter()
+        .map(|(b_v, _, _)| vec![1.0, *b_v as f64])
+        .collect();
+    let fit = |rhs: Vec<f64>| {
+        let line = linalg::least_squares(&rows, &rhs)?;
+        Some(line[0] + line[1] * WHITE_REFERENCE as f64)
+    };
+    let red = fit(colors.iter().map(|c| c.1 as f64).collect()).ok_or("singular color fit")?;
+    let blue = fit(colors.iter().map(|c| c.2 as f64).collect()).ok_or("singular color fit")?;
+    let mut white_balance = [10f64.powf(-red) as f32, 1.0, 10f64.powf(-blue) as f32];
+    // never brighten a channel into clipping
+    let max = white_balance.iter().copied().fold(0.0, f32::max);
+    white_balance = white_balance.map(|w| w / max);
+
+    let pedestal = background.iter().sum::<f32>() / 3.0;
+    for ((channel, background), white) in channels
+        .iter_mut()
+        .zip(background.iter())
+        .zip(white_balance.iter())
+    {
+        for v in channel.pixels.iter_mut() {
+            *v = (*v - background) * white + pedestal;
+        }
+    }
+
+    Ok(Calibration {
+        background,
+        white_balance,
+        stars: colors.len(),
+    })
+}
+
+/// `(star, entry)` pairs found by matching the star patterns of the detections and
+/// the catalog projected onto the sky, trying both parities since optics may
+/// mirror the field.
+fn match_catalog(
+    width: usize,
+    height: usize,
+    stars: &[Star],
+    star_scale: f32,
+    catalog: &[Entry],
+) -> Option<Vec<(usize, usize)>> {
+    // brightest first, with equal luminance so that only geometry is matched
+    let mut order: Vec<_> = (0..stars.len()).collect();
+    order.sort_by(|a, b| stars[*b].flux.total_cmp(&stars[*a].flux));
+    let detections: Vec<_> = order
+        .iter()
+        .map(|i| (stars[*i].x * star_scale, stars[*i].y * star_scale, 1.0))
+        .collect();
+
+    let center = catalog::center(catalog);
+    // entries listed twice would make triangles with edges of zero length, in
+    // degrees
+    let resolution = 1e-6;
+    let mut seen = std::collections::HashSet::new();
+    let projected: Vec<_> = catalog
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| catalog::project(center, e.ra, e.dec).map(|p| (i, p)))
+        .filter(|(_, (xi, eta))| {
+            seen.insert((
+                (xi / resolution).round() as i64,
+                (eta / resolution).round() as i64,
+            ))
+        })
+        .collect();
+
+    // isotropic normalization, the pattern is rotated relative to the frame
+    let size = width.max(height);
+    let min_pairs = 5;
+    [1.0, -1.0]
+        .into_iter()
+        .filter_map(|parity| {
+            let points: Vec<_> = projected
+                .iter()
+                .map(|(_, (xi, eta))| ((parity * xi) as f32, *eta as f32, 1.0))
+                .collect();
+            align::register(size, size, &points, &detections, ALIGN_THRESHOLD, 1)
+        })
+        .max_by_key(|registration| registration.pairs.len())
+        .filter(|registration| registration.pairs.len() >= min_pairs)
+        .map(|registration| {
+            registration
+                .pairs
+                .iter()
+                .map(|(entry, star)| (order[*star], projected[*entry].0))
+                .collect()
+        })
+}
+
+/// Background subtracted sum within `radius`, `None` if the aperture leaves the
+/// image, is masked or saturated.
+fn aperture_flux(image: &Image<f32>, x: f32, y: f32, radius: f32, background: f32) -> Option<f32> {
+    let saturated = 0.99;
+    let r = radius.ceil() as i32;
+    let (cx, cy) = (x.round() as i32, y.round() as i32);
+    let mut flux = 0.0;
+    for dy in -r..=r {
+        for dx in -r..=r {
+            if ((dx * dx + dy * dy) as f32).sqrt() > radius {
+                continue;
+            }
+            let (px, py) = (cx + dx, cy + dy);
+            if px < 0 || py < 0 || px >= image.width as i32 || py >= image.height as i32 {
+                return None;
+            }
+            let v = image.pixels[py as usize * image.width + px as usize];
+            if v.is_nan() || v >= saturated {
+                return None;
+            }
+            flux += v - background;
+        }
+    }
+    Some(flux)
+}
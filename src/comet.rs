@@ -0,0 +1,200 @@
+use crate::align::Transform;
+use crate::fits;
+use crate::image::{self, Image, ImageMemory};
+use crate::integrate::{self, Integration};
+use crate::wcs::Wcs;
+use std::collections::HashMap;
+
+/// The object marked in a frame, in pixels of its raw frame.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Mark {
+    pub frame: usize,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Comet {
+    /// The motion is interpolated between the earliest and latest marks.
+    pub marks: Vec<Mark>,
+    /// Motion in arcseconds per hour in right ascension, times the cosine of the
+    /// declination as ephemerides list it, and in declination. Used instead of a
+    /// second mark.
+    pub rate: Option<(f32, f32)>,
+    /// Radius around the nucleus rejected from the star stack.
+    pub radius: f32,
+}
+
+impl Comet {
+    /// Replaces the mark in `frame`.
+    pub fn mark(&mut self, frame: usize, x: f32, y: f32) {
+        self.marks.retain(|m| m.frame != frame);
+        self.marks.push(Mark { frame, x, y });
+    }
+}
+
+/// Position of the object in reference frame pixels for every registered frame,
+/// from the observation times in the frame headers.
+pub fn positions(
+    images: &ImageMemory,
+    comet: &Comet,
+) -> Result<HashMap<usize, (f32, f32)>, String> {
+    let time = |frame: usize| {
+        images.headers[frame]
+            .time()
+            .ok_or_else(|| format!("{} has no DATE-OBS", images.paths[frame]))
+    };
+    let reference_position = |mark: &Mark| -> Result<(f64, (f32, f32)), String> {
+        let transform = frame_transform(images, mark.frame)
+            .ok_or_else(|| format!("frame {} failed to register", mark.frame))?;
+        Ok((time(mark.frame)?, transform.apply(mark.x, mark.y)))
+    };
+
+    let mut marks = comet
+        .marks
+        .iter()
+        .map(reference_position)
+        .collect::<Result<Vec<_>, String>>()?;
+    marks.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let hour = 3600.0;
+    let (t0, (x0, y0), rate) = match (marks.first(), marks.last(), comet.rate) {
+        (Some(first), _, Some(rate)) => (first.0, first.1, pixel_rate(images, first.1, rate)?),
+        (Some(first), Some(last), None) if last.0 > first.0 => {
+            let hours = ((last.0 - first.0) / hour) as f32;
+            let rate = (
+                (last.1.0 - first.1.0) / hours,
+                (last.1.1 - first.1.1) / hours,
+            );
+            (first.0, first.1, rate)
+        }
+        _ => return Err("mark the object in two frames taken at different times".to_string()),
+    };
+
+    images
+        .registrations
+        .keys()
+        .map(|&frame| {
+            let hours = ((time(frame)? - t0) / hour) as f32;
+            Ok((frame, (x0 + rate.0 * hours, y0 + rate.1 * hours)))
+        })
+        .collect()
+}
+
+// The motion over the hour after `position`, in reference frame pixels, through
+// the solution of the reference frame or else its image scale with north up.
+fn pixel_rate(
+    images: &ImageMemory,
+    position: (f32, f32),
+    (ra_rate, dec_rate): (f32, f32),
+) -> Result<(f32, f32), String> {
+    let (x, y) = (position.0 as f64, position.1 as f64);
+    let reference = images.reference();
+    let wcs = match images.solutions.get(&reference) {
+        Some(wcs) => wcs.clone(),
+        None => {
+            let scale = image_scale(&images.headers[reference])
+                .ok_or("plate solve the reference frame or give its image scale to use a rate")?
+                / 3600.0;
+            // east to the left
+            Wcs {
+                crval: (0.0, 0.0),
+                crpix: (x, y),
+                cd: [[-scale, 0.0], [0.0, -scale]],
+                sip: None,
+            }
+        }
+    };
+    let (ra, dec) = wcs.pixel_to_sky(x, y);
+    let ra = ra + ra_rate as f64 / 3600.0 / dec.to_radians().cos();
+    let dec = dec + dec_rate as f64 / 3600.0;
+    let (x1, y1) = wcs
+        .sky_to_pixel(ra, dec)
+        .ok_or("the rate leaves the sky of the reference frame")?;
+    Ok(((x1 - x) as f32, (y1 - y) as f32))
+}
+
+/// Arcseconds per pixel given directly or by the pixel size and focal length.
+fn image_scale(header: &fits::Header) -> Option<f64> {
+    let real = |key: &str| header.get(key).and_then(fits::Value::as_f64);
+    real("PIXSCALE").or_else(|| {
+        // micrometers over millimeters
+        let (size, focal_length) = (real("XPIXSZ")?, real("FOCALLEN")?);
+        (focal_length > 0.0).then(|| 206.264_806 * size / focal_length)
+    })
+}
+
+/// Stacks aligned on the stars, with the object rejected, and on the object.
+pub fn integrate(
+    images: &ImageMemory,
+    comet: &Comet,
+) -> Result<(Integration, Integration), String> {
+    let positions = positions(images, comet)?;
+    let position = |frame: usize| positions.get(&frame).copied();
+    let weights = integrate::frame_weights(images)?;
+    let reference = images.reference();
+    let (width, height) = (
+        images.raw[reference][0].width,
+        images.raw[reference][0].height,
+    );
+    let origin = position(reference).ok_or("the reference frame failed to register")?;
+    let reference_estimates = integrate::reference_estimates(images);
+
+    let mut stars = Vec::with_capacity(weights.len());
+    let mut objects = Vec::with_capacity(weights.len());
+    for (frame, _) in weights.iter() {
+        let (mut channels, transform) =
+            integrate::prepare_frame(images, *frame, &reference_estimates);
+        let (x, y) = position(*frame).ok_or("frame failed to register")?;
+
+        // hold the object where it was in the reference frame
+        let mut following = transform.clone();
+        following.similarity.tx -= x - origin.0;
+        following.similarity.ty -= y - origin.1;
+        objects.push(integrate::warp(&channels, &following, width, height));
+
+        let (fx, fy) = transform.invert(x, y);
+        mask_object(&mut channels, fx, fy, comet.radius);
+        stars.push(integrate::warp(&channels, &transform, width, height));
+    }
+
+    let frame_weights: Vec<_> = weights.iter().map(|(_, w)| *w).collect();
+    // stars trail in the stack that follows the object
+    let stack = |layers: &[[Image<f32>; 3]], alignment: &str, star_scale: Option<f32>| {
+        let channels = std::array::from_fn(|c| {
+            let layers: Vec<_> = layers.iter().map(|l| &l[c]).collect();
+            integrate::combine(&layers, &frame_weights, images.rejection)
+        });
+        let mut header = integrate::header(images, &images.paths, &weights);
+        header.history(&format!("aligned on the {alignment}"));
+        Integration {
+            preview: image::channels_to_srgb(&channels),
+            channels,
+            weight_maps: Vec::new(),
+            weights: weights.clone(),
+            header,
+            wcs: integrate::reference_wcs(images, 1.0),
+            star_scale,
+        }
+    };
+    Ok((
+        stack(&stars, "stars", Some(1.0)),
+        stack(&objects, "moving object", None),
+    ))
+}
+
+fn frame_transform(images: &ImageMemory, frame: usize) -> Option<Transform> {
+    let offset = images.processed[&frame].log_offset();
+    Some(images.registrations.get(&frame)?.transform.offset(offset))
+}
+
+// NaN so that the object never reaches the star stack
+fn mask_object(channels: &mut [Image<f32>; 3], x: f32, y: f32, radius: f32) {
+    for channel in channels.iter_mut() {
+        let width = channel.width;
+        for (i, v) in channel.pixels.iter_mut().enumerate() {
+            if ((i % width) as f32 - x).hypot((i / width) as f32 - y) <= radius {
+                *v = f32::NAN;
+            }
+        }
+    }
+}
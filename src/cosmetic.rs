@@ -0,0 +1,210 @@
+use crate::image::{self, Cfa, Image};
+use crate::{metrics, stats};
+
+/// Hot and cold pixels of a master dark, flagged where they deviate from the
+/// median by more than `sigma` robust standard deviations.
+pub fn defect_map(dark: &Image<f32>, sigma: f32) -> Image<bool> {
+    // a dark without read noise has a deviation of 0, where a pixel one 16 bit
+    // level off is no defect
+    let min_deviation = 4.0 / 65535.0;
+    let mut values = dark.pixels.clone();
+    let median = stats::median(&mut values);
+    let deviation = stats::mad(&mut values, median).max(min_deviation);
+    Image {
+        pixels: dark
+            .pixels
+            .iter()
+            .map(|v| (v - median).abs() > sigma * deviation)
+            .collect(),
+        width: dark.width,
+        height: dark.height,
+    }
+}
+
+/// Median of every dark in `directory`, `None` if there are none.
+pub fn master_dark(directory: &str) -> Result<Option<Image<f32>>, String> {
+    if !std::fs::exists(directory).unwrap() {
+        return Ok(None);
+    }
+    let paths = image::frame_paths(directory);
+    let darks = paths
+        .iter()
+        .map(|path| Ok(image::luminance(&image::load(path)?.0)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let Some(first) = darks.first() else {
+        return Ok(None);
+    };
+    if let Some(i) = darks
+        .iter()
+        .position(|d| d.width != first.width || d.height != first.height)
+    {
+        return Err(format!(
+            "{} does not match the size of the other darks",
+            paths[i]
+        ));
+    }
+
+    let mut stack = vec![0.0; darks.len()];
+    let pixels = (0..first.pixels.len())
+        .map(|i| {
+            for (s, dark) in stack.iter_mut().zip(darks.iter()) {
+                *s = dark.pixels[i];
+            }
+            stats::median(&mut stack)
+        })
+        .collect();
+    Ok(Some(Image {
+        pixels,
+        width: first.width,
+        height: first.height,
+    }))
+}
+
+// https://www.astro.yale.edu/dokkum/lacosmic/
+//
+// Cosmic rays and hot pixels are sharper than any star the optics can produce:
+// flag pixels whose Laplacian is significant against the noise and large compared
+// to the fine structure around them, which is high for stars and flat for defects.
+// `step` is the distance between pixels of the same color, 2 for mosaics.
+pub fn detect_cosmics(image: &Image<f32>, noise: f32, step: usize) -> Image<bool> {
+    let sigma_clip = 5.0;
+    let object_limit = 2.0;
+
+    let mut mask = Image {
+        pixels: vec![false; image.pixels.len()],
+        width: image.width,
+        height: image.height,
+    };
+    let margin = 3 * step;
+    if image.width <= 2 * margin || image.height <= 2 * margin {
+        return mask;
+    }
+
+    // Laplacian of pure noise has a standard deviation of sqrt(4^2 + 4) * noise
+    let laplacian_noise = 20f32.sqrt() * noise.max(f32::EPSILON);
+    let p = |x: usize, y: usize| image.pixels[y * image.width + x];
+    let mut window = Vec::with_capacity(49);
+    let mut median = |x: usize, y: usize, radius: usize| {
+        window.clear();
+        for dy in -(radius as i32)..=radius as i32 {
+            for dx in -(radius as i32)..=radius as i32 {
+                let sx = (x as i32 + dx * step as i32) as usize;
+                let sy = (y as i32 + dy * step as i32) as usize;
+                window.push(p(sx, sy));
+            }
+        }
+        stats::median(&mut window)
+    };
+
+    for y in margin..image.height - margin {
+        for x in margin..image.width - margin {
+            let v = p(x, y);
+            let neighbours = p(x - step, y) + p(x + step, y) + p(x, y - step) + p(x, y + step);
+            let laplacian = (4.0 * v - neighbours).max(0.0);
+            if laplacian / laplacian_noise < sigma_clip {
+                continue;
+            }
+            let fine_structure = median(x, y, 1) - median(x, y, 3);
+            if laplacian / fine_structure.max(noise).max(f32::EPSILON) > object_limit {
+                mask.pixels[y * image.width + x] = true;
+            }
+        }
+    }
+    mask
+}
+
+/// Replaces every flagged pixel by the median of its unflagged neighbours of the
+/// same color.
+pub fn correct(channels: &mut [Image<f32>; 3], defects: &Image<bool>, step: usize) {
+    assert_eq!(channels[0].pixels.len(), defects.pixels.len());
+    let width = defects.width as i32;
+    let height = defects.height as i32;
+    let mut neighbours = [const { Vec::new() }; 3];
+    for y in 0..height {
+        for x in 0..width {
+            if !defects.pixels[(y * width + x) as usize] {
+                continue;
+            }
+            neighbours.iter_mut().for_each(Vec::clear);
+            for radius in 1..=2 {
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let sx = x + dx * step as i32;
+                        let sy = y + dy * step as i32;
+                        if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                            continue;
+                        }
+                        let i = (sy * width + sx) as usize;
+                        if defects.pixels[i] {
+                            continue;
+                        }
+                        for (values, channel) in neighbours.iter_mut().zip(channels.iter()) {
+                            values.push(channel.pixels[i]);
+                        }
+                    }
+                }
+                // only look further out when the neighbourhood is all defects
+                if !neighbours[0].is_empty() {
+                    break;
+                }
+            }
+            if !neighbours[0].is_empty() {
+                for (values, channel) in neighbours.iter_mut().zip(channels.iter_mut()) {
+                    channel.pixels[(y * width + x) as usize] = stats::median(values);
+                }
+            }
+        }
+    }
+}
+
+/// Removes `defects` from the master dark and any detected cosmic rays.
+pub fn correct_frame(
+    channels: &mut [Image<f32>; 3],
+    defects: Option<&Image<bool>>,
+    cfa: Option<Cfa>,
+) {
+    let step = if cfa.is_some() { 2 } else { 1 };
+    let (width, height) = (channels[0].width, channels[0].height);
+    if let Some(defects) = defects {
+        if defects.width == width && defects.height == height {
+            correct(channels, defects, step);
+        } else {
+            println!("defect map does not match the frame size");
+        }
+    }
+
+    // large events take more than one pass
+    let iterations = 2;
+    for _ in 0..iterations {
+        let luminance = image::luminance(channels);
+        let (_, noise) = metrics::background(&luminance);
+        let cosmics = detect_cosmics(&luminance, noise, step);
+        if !cosmics.pixels.iter().any(|c| *c) {
+            break;
+        }
+        correct(channels, &cosmics, step);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantized_dark() {
+        // every pixel on the same level but a few one level up and one hot pixel
+        let level = 1.0 / 65535.0;
+        let mut pixels = vec![100.0 * level; 100];
+        pixels[10] += level;
+        pixels[20] += level;
+        pixels[30] = 0.5;
+        let dark = Image {
+            pixels,
+            width: 10,
+            height: 10,
+        };
+        let defects = defect_map(&dark, 5.0);
+        let flagged: Vec<_> = (0..100).filter(|i| defects.pixels[*i]).collect();
+        assert_eq!(flagged, [30]);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::image::Image;
+use crate::metrics::Star;
+use crate::starmask::{self, StarMask};
+use crate::{fft, process, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Psf {
+    /// Median of the normalized profiles of the detected stars.
+    Stars,
+    Gaussian {
+        fwhm: f32,
+    },
+    // https://en.wikipedia.org/wiki/Moffat_distribution
+    Moffat {
+        fwhm: f32,
+        beta: f32,
+    },
+}
+
+/// Covers the cores of stars, fading out over their wings.
+const STAR_PROTECTION: StarMask = StarMask {
+    radius: 1.0,
+    grow: 0,
+    feather: 2.0,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Deconvolution {
+    pub psf: Psf,
+    pub iterations: usize,
+    /// Weight of the total variation term that keeps noise from being amplified.
+    pub regularization: f32,
+    /// How strongly dark rings below the local minimum of the input are pulled
+    /// back, from 0 to 1.
+    pub deringing: f32,
+    /// Keeps the input around stars, whose cores deconvolution over sharpens.
+    pub protect_stars: bool,
+}
+
+impl std::fmt::Display for Deconvolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} psf, {} iterations, regularization {}, deringing {}, star protection {}",
+            self.psf, self.iterations, self.regularization, self.deringing, self.protect_stars
+        )
+    }
+}
+
+/// Deconvolves every channel in place. `stars` are in pixels of a frame
+/// `star_scale` times smaller than `channels`.
+pub fn deconvolve(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    deconvolution: Deconvolution,
+) -> Result<(), String> {
+    let psf = match deconvolution.psf {
+        Psf::Stars => star_psf(channels, stars, star_scale)?,
+        Psf::Gaussian { fwhm } => model_psf(fwhm, |r| {
+            let sigma = fwhm / (2.0 * (2.0 * std::f32::consts::LN_2).sqrt());
+            (-r * r / (2.0 * sigma * sigma)).exp()
+        }),
+        Psf::Moffat { fwhm, beta } => model_psf(fwhm, |r| {
+            let alpha = fwhm / (2.0 * (2f32.powf(1.0 / beta) - 1.0).sqrt());
+            (1.0 + (r / alpha) * (r / alpha)).powf(-beta)
+        }),
+    };
+    let protection = deconvolution.protect_stars.then(|| {
+        starmask::mask(
+            channels[0].width,
+            channels[0].height,
+            stars,
+            star_scale,
+            STAR_PROTECTION,
+        )
+    });
+
+    for channel in channels.iter_mut() {
+        let mut output = richardson_lucy(
+            channel,
+            &psf,
+            deconvolution.iterations,
+            deconvolution.regularization,
+        );
+        if deconvolution.deringing > 0.0 {
+            dering(&mut output, channel, psf.width, deconvolution.deringing);
+        }
+        if let Some(protection) = &protection {
+            for ((v, original), p) in output
+                .pixels
+                .iter_mut()
+                .zip(channel.pixels.iter())
+                .zip(protection.pixels.iter())
+            {
+                *v = *v * (1.0 - p) + original * p;
+            }
+        }
+        *channel = output;
+    }
+    Ok(())
+}
+
+// https://en.wikipedia.org/wiki/Richardson%E2%80%93Lucy_deconvolution
+// https://doi.org/10.1002/jemt.20294
+//
+// Richardson-Lucy with total variation regularization. Both correlations run
+// through the frequency domain, the blur is a correlation with the flipped psf and
+// its adjoint with the psf itself.
+fn richardson_lucy(
+    image: &Image<f32>,
+    psf: &Image<f32>,
+    iterations: usize,
+    regularization: f32,
+) -> Image<f32> {
+    // the algorithm assumes positive data, NaN marks pixels outside of the stack
+    let epsilon = 1e-6;
+    let observed = Image {
+        pixels: image
+            .pixels
+            .iter()
+            .map(|v| if v.is_nan() { epsilon } else { v.max(epsilon) })
+            .collect(),
+        width: image.width,
+        height: image.height,
+    };
+    let flipped = Image {
+        pixels: psf.pixels.iter().rev().copied().collect(),
+        width: psf.width,
+        height: psf.height,
+    };
+
+    let blur = fft::Correlation::new(image.width, image.height, &flipped);
+    let adjoint = fft::Correlation::new(image.width, image.height, psf);
+
+    let mut estimate = observed.clone();
+    for _ in 0..iterations {
+        let blurred = blur.apply(&estimate);
+        let ratio = Image {
+            pixels: observed
+                .pixels
+                .iter()
+                .zip(blurred.pixels.iter())
+                .map(|(o, b)| o / b.max(epsilon))
+                .collect(),
+            width: image.width,
+            height: image.height,
+        };
+        let correction = adjoint.apply(&ratio);
+        let divergence = if regularization > 0.0 {
+            total_variation(&estimate)
+        } else {
+            vec![0.0; estimate.pixels.len()]
+        };
+        for ((v, c), d) in estimate
+            .pixels
+            .iter_mut()
+            .zip(correction.pixels.iter())
+            .zip(divergence.iter())
+        {
+            // large steps against the gradient would flip the sign
+            let denominator = (1.0 - regularization * d).max(0.5);
+            *v = (*v * c / denominator).max(epsilon);
+        }
+    }
+
+    for (v, original) in estimate.pixels.iter_mut().zip(image.pixels.iter()) {
+        if original.is_nan() {
+            *v = f32::NAN;
+        }
+    }
+    estimate
+}
+
+// div(grad(u) / |grad(u)|)
+fn total_variation(image: &Image<f32>) -> Vec<f32> {
+    let (width, height) = (image.width, image.height);
+    let at = |x: usize, y: usize| image.pixels[y.min(height - 1) * width + x.min(width - 1)];
+    // normalized forward differences
+    let epsilon = 1e-4;
+    let gradient: Vec<(f32, f32)> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let dx = at(x + 1, y) - at(x, y);
+            let dy = at(x, y + 1) - at(x, y);
+            let norm = (dx * dx + dy * dy + epsilon * epsilon).sqrt();
+            (dx / norm, dy / norm)
+        })
+        .collect();
+    // backward differences of the normalized gradient
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let left = if x > 0 { gradient[i - 1].0 } else { 0.0 };
+            let up = if y > 0 { gradient[i - width].1 } else { 0.0 };
+            gradient[i].0 - left + gradient[i].1 - up
+        })
+        .collect()
+}
+
+// Pulls values below the local minimum of `original` back towards it, the dark
+// halos around stars are deconvolution overshooting.
+fn dering(output: &mut Image<f32>, original: &Image<f32>, size: usize, amount: f32) {
+    let minimum = process::erode(original, size);
+    for (v, min) in output.pixels.iter_mut().zip(minimum.pixels.iter()) {
+        if *v < *min {
+            *v += amount.clamp(0.0, 1.0) * (min - *v);
+        }
+    }
+}
+
+fn model_psf(fwhm: f32, profile: impl Fn(f32) -> f32) -> Image<f32> {
+    let size = (3.0 * fwhm).ceil() as usize | 1;
+    let half = (size / 2) as f32;
+    let mut psf = Image {
+        pixels: (0..size * size)
+            .map(|i| profile(((i % size) as f32 - half).hypot((i / size) as f32 - half)))
+            .collect(),
+        width: size,
+        height: size,
+    };
+    normalize(&mut psf);
+    psf
+}
+
+/// Per pixel median of background subtracted, unit flux cutouts of the stars.
+fn star_psf(
+    channels: &[Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+) -> Result<Image<f32>, String> {
+    let mut fwhms: Vec<_> = stars.iter().map(|s| s.fwhm).collect();
+    let fwhm = stats::median(&mut fwhms) * star_scale;
+    let size = (3.0 * fwhm).ceil() as usize | 1;
+    let half = (size / 2) as f32;
+
+    // bright but unsaturated stars have the cleanest profiles
+    let saturated = 0.99;
+    let min_snr = 20.0;
+    let luminance = Image {
+        pixels: (0..channels[0].pixels.len())
+            .map(|i| channels.iter().map(|c| c.pixels[i]).sum::<f32>() / 3.0)
+            .collect(),
+        width: channels[0].width,
+        height: channels[0].height,
+    };
+    let mut cutouts = Vec::new();
+    for star in stars.iter().filter(|s| s.snr >= min_snr) {
+        let (cx, cy) = (star.x * star_scale, star.y * star_scale);
+        let cutout: Option<Vec<f32>> = (0..size * size)
+            .map(|i| {
+                let x = cx + (i % size) as f32 - half;
+                let y = cy + (i / size) as f32 - half;
+                luminance
+                    .sample_bilinear(x, y)
+                    .filter(|v| !v.is_nan() && *v < saturated)
+            })
+            .collect();
+        let Some(mut cutout) = cutout else {
+            continue;
+        };
+        // background from the border of the cutout
+        let mut border: Vec<_> = (0..size * size)
+            .filter(|i| {
+                i % size == 0 || i % size == size - 1 || i / size == 0 || i / size == size - 1
+            })
+            .map(|i| cutout[i])
+            .collect();
+        let background = stats::median(&mut border);
+        let flux: f32 = cutout.iter().map(|v| v - background).sum();
+        if flux <= 0.0 {
+            continue;
+        }
+        for v in cutout.iter_mut() {
+            *v = (*v - background) / flux;
+        }
+        cutouts.push(cutout);
+    }
+    let min_stars = 5;
+    if cutouts.len() < min_stars {
+        return Err(format!(
+            "only {} stars are usable for the psf",
+            cutouts.len()
+        ));
+    }
+
+    let mut values = Vec::with_capacity(cutouts.len());
+    let mut psf = Image {
+        pixels: (0..size * size)
+            .map(|i| {
+                values.clear();
+                values.extend(cutouts.iter().map(|c| c[i]));
+                stats::median(&mut values).max(0.0)
+            })
+            .collect(),
+        width: size,
+        height: size,
+    };
+    normalize(&mut psf);
+    Ok(psf)
+}
+
+fn normalize(psf: &mut Image<f32>) {
+    let sum: f32 = psf.pixels.iter().sum();
+    for v in psf.pixels.iter_mut() {
+        *v /= sum;
+    }
+}
@@ -0,0 +1,333 @@
+use crate::image::Image;
+use crate::stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Method {
+    // https://www.eso.org/sci/software/esomidas/doc/user/18NOV/volb/node317.html
+    //
+    /// Soft thresholds `layers` à trous wavelet layers at `threshold` times the noise
+    /// of each layer.
+    Wavelet { layers: usize, threshold: f32 },
+    // https://www.ipol.im/pub/art/2011/bcm_nlm/
+    //
+    /// Averages pixels whose surrounding `patch` radius looks alike within a
+    /// `search` radius. `strength` scales the noise to the filtering parameter.
+    NonLocalMeans {
+        strength: f32,
+        patch: usize,
+        search: usize,
+    },
+}
+
+/// Every offset within the search radius is a pass over the image.
+const MAX_SEARCH: usize = 10;
+
+impl Method {
+    /// `wavelet <layers> <threshold>` or `nlm <strength> <patch> <search>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let words: Vec<_> = value.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<f32>()
+                .ok()
+                .filter(|v| *v >= 0.0)
+                .ok_or_else(|| format!("{word} is not a positive number"))
+        };
+        let count = |word: &str| {
+            word.parse::<usize>()
+                .map_err(|_| format!("{word} is not a count"))
+        };
+        match words[..] {
+            ["wavelet", layers, threshold] => Ok(Self::Wavelet {
+                layers: count(layers)?,
+                threshold: number(threshold)?,
+            }),
+            ["nlm", strength, patch, search] => {
+                let search = count(search)?;
+                if search > MAX_SEARCH {
+                    return Err(format!("search radius above {MAX_SEARCH}"));
+                }
+                Ok(Self::NonLocalMeans {
+                    strength: number(strength)?,
+                    patch: count(patch)?,
+                    search,
+                })
+            }
+            _ => Err(format!(
+                "expected wavelet <layers> <threshold> or nlm <strength> <patch> <search>, got {value}"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NoiseReduction {
+    pub method: Method,
+    /// Smooths faint areas more than bright structure above the background.
+    pub mask: bool,
+}
+
+impl std::fmt::Display for NoiseReduction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.method {
+            Method::Wavelet { layers, threshold } => {
+                write!(f, "wavelet {layers} layers threshold {threshold}")?
+            }
+            Method::NonLocalMeans {
+                strength,
+                patch,
+                search,
+            } => write!(
+                f,
+                "non-local means strength {strength} patch {patch} search {search}"
+            )?,
+        }
+        write!(f, ", luminance mask {}", self.mask)
+    }
+}
+
+/// Denoises every channel in place. `background` is the level of each channel's
+/// background when it has been modeled, otherwise the median is assumed.
+pub fn denoise(
+    channels: &mut [Image<f32>; 3],
+    background: Option<[f32; 3]>,
+    noise_reduction: NoiseReduction,
+) {
+    let mask = noise_reduction
+        .mask
+        .then(|| luminance_mask(channels, background));
+    for channel in channels.iter_mut() {
+        // NaN marks pixels outside of the stack and would spread through the filters
+        let mut values: Vec<_> = channel
+            .pixels
+            .iter()
+            .copied()
+            .filter(|v| !v.is_nan())
+            .collect();
+        let fill = stats::median(&mut values);
+        let input = Image {
+            pixels: channel
+                .pixels
+                .iter()
+                .map(|v| if v.is_nan() { fill } else { *v })
+                .collect(),
+            width: channel.width,
+            height: channel.height,
+        };
+
+        let denoised = match noise_reduction.method {
+            Method::Wavelet { layers, threshold } => wavelet(&input, layers, threshold),
+            Method::NonLocalMeans {
+                strength,
+                patch,
+                search,
+            } => non_local_means(&input, strength, patch, search),
+        };
+        for (i, v) in channel.pixels.iter_mut().enumerate() {
+            if v.is_nan() {
+                continue;
+            }
+            let amount = mask.as_ref().map_or(1.0, |m| m.pixels[i]);
+            *v += amount * (denoised.pixels[i] - *v);
+        }
+    }
+}
+
+/// 1 at the background, falling towards 0 as the luminance rises above it.
+fn luminance_mask(channels: &[Image<f32>; 3], background: Option<[f32; 3]>) -> Image<f32> {
+    let signal: Vec<_> = (0..channels[0].pixels.len())
+        .map(|i| channels.iter().map(|c| c.pixels[i]).sum::<f32>() / 3.0)
+        .collect();
+    let mut values: Vec<_> = signal.iter().copied().filter(|v| !v.is_nan()).collect();
+    let median = stats::median(&mut values);
+    // nebulosity biases the median, the modeled level only saw background samples
+    let level = background.map_or(median, |b| b.iter().sum::<f32>() / 3.0);
+    let noise = stats::mad(&mut values, median).max(f32::EPSILON);
+    // signal at which half of the smoothing remains
+    let half = 5.0 * noise;
+    Image {
+        pixels: signal
+            .iter()
+            .map(|s| 1.0 / (1.0 + ((s - level).max(0.0) / half)))
+            .map(|m| if m.is_nan() { 0.0 } else { m })
+            .collect(),
+        width: channels[0].width,
+        height: channels[0].height,
+    }
+}
+
+// https://en.wikipedia.org/wiki/Stationary_wavelet_transform
+fn wavelet(image: &Image<f32>, layers: usize, threshold: f32) -> Image<f32> {
+    let mut smooth = image.clone();
+    let mut output = vec![0.0; image.pixels.len()];
+    for layer in 0..layers {
+        let next = b3_spline(&smooth, 1 << layer);
+        let mut detail: Vec<_> = smooth
+            .pixels
+            .iter()
+            .zip(next.pixels.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        let mut values = detail.clone();
+        let median = stats::median(&mut values);
+        let noise = stats::mad(&mut values, median);
+        // soft thresholding
+        for d in detail.iter_mut() {
+            *d = d.signum() * (d.abs() - threshold * noise).max(0.0);
+        }
+        for (o, d) in output.iter_mut().zip(detail.iter()) {
+            *o += d;
+        }
+        smooth = next;
+    }
+    for (o, s) in output.iter_mut().zip(smooth.pixels.iter()) {
+        *o += s;
+    }
+    Image {
+        pixels: output,
+        width: image.width,
+        height: image.height,
+    }
+}
+
+// Separable `[1, 4, 6, 4, 1] / 16` with `step - 1` holes between the taps.
+fn b3_spline(image: &Image<f32>, step: usize) -> Image<f32> {
+    let kernel = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+    let (width, height) = (image.width as i32, image.height as i32);
+    let step = step as i32;
+    let pass = |pixels: &[f32], horizontal: bool| -> Vec<f32> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        let offset = (k as i32 - 2) * step;
+                        let (sx, sy) = if horizontal {
+                            ((x + offset).clamp(0, width - 1), y)
+                        } else {
+                            (x, (y + offset).clamp(0, height - 1))
+                        };
+                        w * pixels[(sy * width + sx) as usize]
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    let rows = pass(&image.pixels, true);
+    Image {
+        pixels: pass(&rows, false),
+        width: image.width,
+        height: image.height,
+    }
+}
+
+// https://doi.org/10.1109/ISBI.2008.4541250
+//
+// One offset at a time: the patch distances of every pixel to its neighbour at that
+// offset are box sums of the squared difference image, read from its integral
+// image in constant time whatever the patch size.
+fn non_local_means(image: &Image<f32>, strength: f32, patch: usize, search: usize) -> Image<f32> {
+    let (width, height) = (image.width as i32, image.height as i32);
+    let (patch, search) = (patch as i32, search as i32);
+    let at = |x: i32, y: i32| {
+        image.pixels[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize]
+    };
+
+    let mut values = image.pixels.clone();
+    let median = stats::median(&mut values);
+    let noise = stats::mad(&mut values, median);
+    let h2 = (strength * noise).powi(2).max(f32::EPSILON);
+    let patch_size = ((2 * patch + 1) * (2 * patch + 1)) as f32;
+
+    // the integral covers the patches of border pixels too, one row and column of
+    // zeros ahead
+    let (padded_width, padded_height) = (width + 2 * patch + 1, height + 2 * patch + 1);
+    let mut integral = vec![0.0f64; (padded_width * padded_height) as usize];
+    let mut sum = vec![0.0; image.pixels.len()];
+    let mut total = vec![0.0; image.pixels.len()];
+    for dy in -search..=search {
+        for dx in -search..=search {
+            for y in 1..padded_height {
+                let mut row = 0.0;
+                for x in 1..padded_width {
+                    let (ix, iy) = (x - 1 - patch, y - 1 - patch);
+                    let d = at(ix, iy) - at(ix + dx, iy + dy);
+                    row += (d * d) as f64;
+                    let i = (y * padded_width + x) as usize;
+                    integral[i] = integral[i - padded_width as usize] + row;
+                }
+            }
+            let side = 2 * patch + 1;
+            let corner = |x: i32, y: i32| integral[(y * padded_width + x) as usize];
+            for y in 0..height {
+                for x in 0..width {
+                    let distance =
+                        corner(x + side, y + side) - corner(x, y + side) - corner(x + side, y)
+                            + corner(x, y);
+                    // patches differing only by noise are weighted fully
+                    let distance = (distance as f32 / patch_size - 2.0 * noise * noise).max(0.0);
+                    let weight = (-distance / h2).exp();
+                    let i = (y * width + x) as usize;
+                    sum[i] += weight * at(x + dx, y + dy);
+                    total[i] += weight;
+                }
+            }
+        }
+    }
+
+    Image {
+        pixels: sum.iter().zip(total.iter()).map(|(s, t)| s / t).collect(),
+        width: image.width,
+        height: image.height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_local_means_matches_direct_sums() {
+        let (width, height) = (13i32, 9i32);
+        let image = Image {
+            pixels: (0..width * height)
+                .map(|i| ((i * 7919) % 101) as f32 / 100.0)
+                .collect(),
+            width: width as usize,
+            height: height as usize,
+        };
+        let (strength, patch, search) = (1.0, 1i32, 2i32);
+        let fast = non_local_means(&image, strength, patch as usize, search as usize);
+
+        let at = |x: i32, y: i32| {
+            image.pixels[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize]
+        };
+        let mut values = image.pixels.clone();
+        let median = stats::median(&mut values);
+        let noise = stats::mad(&mut values, median);
+        let h2 = (strength * noise).powi(2);
+        for y in 0..height {
+            for x in 0..width {
+                let (mut sum, mut total) = (0.0, 0.0);
+                for sy in y - search..=y + search {
+                    for sx in x - search..=x + search {
+                        let mut distance = 0.0;
+                        for py in -patch..=patch {
+                            for px in -patch..=patch {
+                                let d = at(x + px, y + py) - at(sx + px, sy + py);
+                                distance += d * d;
+                            }
+                        }
+                        let distance = (distance / 9.0 - 2.0 * noise * noise).max(0.0);
+                        let weight = (-distance / h2).exp();
+                        sum += weight * at(sx, sy);
+                        total += weight;
+                    }
+                }
+                let fast = fast.pixels[(y * width + x) as usize];
+                assert!((fast - sum / total).abs() < 1e-4, "{fast} {}", sum / total);
+            }
+        }
+    }
+}
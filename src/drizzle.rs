@@ -0,0 +1,162 @@
+// https://en.wikipedia.org/wiki/Drizzle_(image_processing)
+// https://www.stsci.edu/files/live/sites/www/files/home/scientific-community/software/drizzlepac/_documents/drizzlepac-handbook.pdf
+
+use crate::fits;
+use crate::image::{self, Cfa, Image, ImageMemory};
+use crate::integrate::{self, Integration};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Drizzle {
+    /// Output pixels per reference pixel.
+    pub scale: f32,
+    /// Side of each dropped input pixel as a fraction of the input pixel.
+    pub pixfrac: f32,
+}
+
+impl Drizzle {
+    /// `<scale> <pixfrac>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let numbers: Vec<_> = value.split_whitespace().map(str::parse::<f32>).collect();
+        let [Ok(scale), Ok(pixfrac)] = numbers[..] else {
+            return Err(format!("expected scale and pixfrac, got {value}"));
+        };
+        let drizzle = Self { scale, pixfrac };
+        drizzle.validate()?;
+        Ok(drizzle)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.scale.is_nan() || self.scale <= 0.0 {
+            return Err(format!("scale {} is not positive", self.scale));
+        }
+        if self.pixfrac.is_nan() || self.pixfrac <= 0.0 || self.pixfrac > 1.0 {
+            return Err(format!("pixfrac {} is not in (0, 1]", self.pixfrac));
+        }
+        Ok(())
+    }
+}
+
+/// Drops every accepted frame onto an output grid `scale` times finer than the
+/// reference frame.
+///
+/// Frames with a [`Cfa`] are drizzled from their raw mosaic: each pixel only lands
+/// in the channel of its filter, instead of being interpolated by debayering.
+pub fn drizzle(images: &ImageMemory, drizzle: Drizzle) -> Result<Integration, String> {
+    drizzle.validate()?;
+
+    let weights = integrate::frame_weights(images)?;
+    let reference = &images.raw[images.reference()][0];
+    let reference_estimates = integrate::reference_estimates(images);
+    let width = (reference.width as f32 * drizzle.scale).round() as usize;
+    let height = (reference.height as f32 * drizzle.scale).round() as usize;
+
+    let empty = || Image {
+        pixels: vec![0.0; width * height],
+        width,
+        height,
+    };
+    let mut output: [Image<f32>; 3] = std::array::from_fn(|_| empty());
+    let mut weight_maps: [Image<f32>; 3] = std::array::from_fn(|_| empty());
+
+    for (frame, weight) in weights.iter() {
+        let (channels, transform) = integrate::prepare_frame(images, *frame, &reference_estimates);
+        let cfa = Cfa::from_header(&images.headers[*frame]);
+        let drop_size = drizzle.pixfrac * transform.similarity.scale() * drizzle.scale;
+        let (frame_width, frame_height) = (channels[0].width, channels[0].height);
+        for y in 0..frame_height {
+            for x in 0..frame_width {
+                let i = y * frame_width + x;
+                let (ox, oy) = transform.apply(x as f32, y as f32);
+                // pixel centers are at integer coordinates, edges at half pixels
+                let center = ((ox + 0.5) * drizzle.scale, (oy + 0.5) * drizzle.scale);
+                let mut drop = |c: usize, value: f32| {
+                    // masked
+                    if value.is_nan() {
+                        return;
+                    }
+                    drop_pixel(
+                        &mut output[c],
+                        &mut weight_maps[c],
+                        center,
+                        drop_size,
+                        value,
+                        *weight,
+                    )
+                };
+                match cfa {
+                    // mosaic frames are grey, every channel holds the raw value
+                    Some(cfa) => drop(cfa.channel(x, y), channels[0].pixels[i]),
+                    None => {
+                        for (c, channel) in channels.iter().enumerate() {
+                            drop(c, channel.pixels[i]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for (channel, weight_map) in output.iter_mut().zip(weight_maps.iter()) {
+        for (v, w) in channel.pixels.iter_mut().zip(weight_map.pixels.iter()) {
+            *v = if *w > 0.0 { *v / w } else { 0.0 };
+        }
+    }
+
+    let mut header = integrate::header(images, &images.paths, &weights);
+    header.push(
+        "DRIZSCAL",
+        fits::Value::Real(drizzle.scale as f64),
+        "drizzle output scale",
+    );
+    header.push(
+        "PIXFRAC",
+        fits::Value::Real(drizzle.pixfrac as f64),
+        "drizzle drop size",
+    );
+
+    Ok(Integration {
+        preview: image::channels_to_srgb(&output),
+        channels: output,
+        weight_maps: weight_maps.into(),
+        weights,
+        header,
+        wcs: integrate::reference_wcs(images, drizzle.scale),
+        star_scale: Some(drizzle.scale),
+    })
+}
+
+/// Accumulates a square drop of side `size` centered on `center`, weighted by the
+/// fraction of the drop overlapping each output pixel. The footprint is kept axis
+/// aligned, which is exact for translations and close for small rotations.
+pub fn drop_pixel(
+    output: &mut Image<f32>,
+    weight_map: &mut Image<f32>,
+    center: (f32, f32),
+    size: f32,
+    value: f32,
+    weight: f32,
+) {
+    let half = size / 2.0;
+    let xmin = center.0 - half;
+    let xmax = center.0 + half;
+    let ymin = center.1 - half;
+    let ymax = center.1 + half;
+    if xmax <= 0.0 || ymax <= 0.0 || xmin >= output.width as f32 || ymin >= output.height as f32 {
+        return;
+    }
+
+    let area = size * size;
+    for py in ymin.floor().max(0.0) as usize..(ymax.ceil() as usize).min(output.height) {
+        let overlap_y = (ymax.min(py as f32 + 1.0) - ymin.max(py as f32)).max(0.0);
+        for px in xmin.floor().max(0.0) as usize..(xmax.ceil() as usize).min(output.width) {
+            let overlap_x = (xmax.min(px as f32 + 1.0) - xmin.max(px as f32)).max(0.0);
+            let w = weight * overlap_x * overlap_y / area;
+            if w <= 0.0 {
+                continue;
+            }
+            let i = py * output.width + px;
+            weight_map.pixels[i] += w;
+            output.pixels[i] += value * w;
+        }
+    }
+}
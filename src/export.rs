@@ -0,0 +1,259 @@
+// https://www.rfc-editor.org/rfc/rfc4180
+// https://www.rfc-editor.org/rfc/rfc8259
+
+use crate::image::ImageMemory;
+use crate::metrics::MEASURE_RADIUS;
+use std::fmt::Write;
+
+/// Peak value of any channel at which a star is clipped.
+const SATURATED: f32 = 0.99;
+/// Eccentricity above which a detection is likely a pair, a trail or noise.
+const ELONGATED: f32 = 0.6;
+
+enum Field {
+    Integer(usize),
+    /// Measurements, written with the shortest decimal that reads back the same.
+    Float(f32),
+    /// Sky coordinates, which need more precision than a `f32`.
+    Double(f64),
+    Text(String),
+    List(Vec<&'static str>),
+}
+
+/// Writes every detection of every frame to `detections.csv` and
+/// `detections.json`, and the star pairs of every registration to
+/// `correspondences.csv` and `correspondences.json`, all in `directory`.
+/// Returns the number of detections and correspondences.
+pub fn write(directory: &str, images: &ImageMemory) -> Result<(usize, usize), String> {
+    std::fs::create_dir_all(directory).map_err(|err| format!("{directory}: {err}"))?;
+    let detections = detections(images);
+    write_table(
+        &format!("{directory}/detections"),
+        &[
+            "frame",
+            "path",
+            "index",
+            "x",
+            "y",
+            "ra",
+            "dec",
+            "flux",
+            "fwhm",
+            "eccentricity",
+            "snr",
+            "flags",
+        ],
+        &detections,
+    )?;
+    let correspondences = correspondences(images);
+    write_table(
+        &format!("{directory}/correspondences"),
+        &[
+            "frame",
+            "index",
+            "reference_frame",
+            "reference_index",
+            "x",
+            "y",
+            "reference_x",
+            "reference_y",
+            "residual",
+        ],
+        &correspondences,
+    )?;
+    Ok((detections.len(), correspondences.len()))
+}
+
+// One row per measured local maximum, in raw frame pixels.
+fn detections(images: &ImageMemory) -> Vec<Vec<Field>> {
+    let reference = images.reference();
+    let mut rows = Vec::new();
+    for frame in 0..images.raw.len() {
+        let processed = &images.processed[&frame];
+        let raw = &images.raw[frame];
+        let (width, height) = (raw[0].width, raw[0].height);
+        let registration = images.registrations.get(&frame);
+        let wcs = images.solutions.get(&frame);
+        for (index, star) in processed.stars.iter().enumerate() {
+            let mut flags = Vec::new();
+            if star.flux <= 0.0 {
+                flags.push("unmeasured");
+            }
+            let radius = MEASURE_RADIUS as f32;
+            if star.x < radius
+                || star.y < radius
+                || star.x >= width as f32 - radius
+                || star.y >= height as f32 - radius
+            {
+                flags.push("edge");
+            }
+            let (cx, cy) = (star.x.round() as i64, star.y.round() as i64);
+            let saturated = (cy - 1..=cy + 1)
+                .flat_map(|y| (cx - 1..=cx + 1).map(move |x| (x, y)))
+                .filter(|(x, y)| (0..width as i64).contains(x) && (0..height as i64).contains(y))
+                .any(|(x, y)| {
+                    let i = y as usize * width + x as usize;
+                    raw.iter().any(|channel| channel.pixels[i] >= SATURATED)
+                });
+            if saturated {
+                flags.push("saturated");
+            }
+            if star.eccentricity > ELONGATED {
+                flags.push("elongated");
+            }
+            // only other frames matching a star make it a match, not the reference
+            // registered to itself
+            let matched = if frame == reference {
+                images
+                    .registrations
+                    .iter()
+                    .filter(|(other, _)| **other != reference)
+                    .any(|(_, r)| r.pairs.iter().any(|(_, j)| *j == index))
+            } else {
+                registration.is_some_and(|r| r.pairs.iter().any(|(i, _)| *i == index))
+            };
+            if matched {
+                flags.push("matched");
+            }
+
+            let (ra, dec) = wcs
+                .map(|wcs| wcs.pixel_to_sky(star.x as f64, star.y as f64))
+                .unwrap_or((f64::NAN, f64::NAN));
+            rows.push(vec![
+                Field::Integer(frame),
+                Field::Text(images.paths[frame].clone()),
+                Field::Integer(index),
+                Field::Float(star.x),
+                Field::Float(star.y),
+                Field::Double(ra),
+                Field::Double(dec),
+                Field::Float(star.flux),
+                Field::Float(star.fwhm),
+                Field::Float(star.eccentricity),
+                Field::Float(star.snr),
+                Field::List(flags),
+            ]);
+        }
+    }
+    rows
+}
+
+// One row per star paired by a registration, with the distance between the
+// transformed star and its reference star.
+fn correspondences(images: &ImageMemory) -> Vec<Vec<Field>> {
+    let reference = images.reference();
+    let reference_stars = &images.processed[&reference].stars;
+    let mut frames: Vec<_> = images.registrations.keys().copied().collect();
+    frames.sort();
+    let mut rows = Vec::new();
+    for frame in frames.into_iter().filter(|f| *f != reference) {
+        let processed = &images.processed[&frame];
+        let registration = &images.registrations[&frame];
+        let transform = registration.transform.offset(processed.log_offset());
+        for (i, j) in registration.pairs.iter() {
+            let (star, reference_star) = (&processed.stars[*i], &reference_stars[*j]);
+            let (x, y) = transform.apply(star.x, star.y);
+            rows.push(vec![
+                Field::Integer(frame),
+                Field::Integer(*i),
+                Field::Integer(reference),
+                Field::Integer(*j),
+                Field::Float(star.x),
+                Field::Float(star.y),
+                Field::Float(reference_star.x),
+                Field::Float(reference_star.y),
+                Field::Float((x - reference_star.x).hypot(y - reference_star.y)),
+            ]);
+        }
+    }
+    rows
+}
+
+// `path.csv` with a header line and `path.json` as an array of objects. NaN is an
+// empty CSV field and a JSON null.
+fn write_table(path: &str, columns: &[&str], rows: &[Vec<Field>]) -> Result<(), String> {
+    let mut csv = columns.join(",");
+    csv.push('\n');
+    let mut json = String::from("[\n");
+    for (r, row) in rows.iter().enumerate() {
+        json.push_str("  {");
+        for (c, (column, field)) in columns.iter().zip(row.iter()).enumerate() {
+            if c > 0 {
+                csv.push(',');
+                json.push_str(", ");
+            }
+            write!(json, "\"{column}\": ").unwrap();
+            match field {
+                Field::Integer(v) => {
+                    write!(csv, "{v}").unwrap();
+                    write!(json, "{v}").unwrap();
+                }
+                Field::Float(v) if v.is_finite() => {
+                    write!(csv, "{v}").unwrap();
+                    write!(json, "{v}").unwrap();
+                }
+                Field::Double(v) if v.is_finite() => {
+                    write!(csv, "{v}").unwrap();
+                    write!(json, "{v}").unwrap();
+                }
+                Field::Float(_) | Field::Double(_) => json.push_str("null"),
+                Field::Text(v) => {
+                    if v.contains([',', '"', '\n']) {
+                        write!(csv, "\"{}\"", v.replace('"', "\"\"")).unwrap();
+                    } else {
+                        csv.push_str(v);
+                    }
+                    json.push_str(&json_string(v));
+                }
+                Field::List(values) => {
+                    csv.push_str(&values.join("|"));
+                    let values: Vec<_> = values.iter().map(|v| json_string(v)).collect();
+                    write!(json, "[{}]", values.join(", ")).unwrap();
+                }
+            }
+        }
+        csv.push('\n');
+        json.push('}');
+        if r + 1 < rows.len() {
+            json.push(',');
+        }
+        json.push('\n');
+    }
+    json.push_str("]\n");
+
+    for (extension, contents) in [("csv", csv), ("json", json)] {
+        let path = format!("{path}.{extension}");
+        std::fs::write(&path, contents).map_err(|err| format!("{path}: {err}"))?;
+    }
+    Ok(())
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("data/m31.fits"), "\"data/m31.fits\"");
+        assert_eq!(
+            json_string("a \"b\" \\ c\nd\te\u{1}"),
+            "\"a \\\"b\\\" \\\\ c\\nd\\u0009e\\u0001\""
+        );
+        assert_eq!(json_string("ångström"), "\"ångström\"");
+    }
+}
@@ -0,0 +1,157 @@
+use crate::image::Image;
+
+// https://en.wikipedia.org/wiki/Cooley%E2%80%93Tukey_FFT_algorithm
+//
+// Iterative radix 2 transform in place, unnormalized in both directions. The length
+// of `data` is a power of two.
+fn fft(data: &mut [(f32, f32)], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let half = length / 2;
+        let angle = sign * std::f64::consts::TAU / length as f64;
+        let twiddles: Vec<_> = (0..half)
+            .map(|k| {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                (cos as f32, sin as f32)
+            })
+            .collect();
+        for block in data.chunks_exact_mut(length) {
+            let (low, high) = block.split_at_mut(half);
+            for ((a, b), (wr, wi)) in low.iter_mut().zip(high.iter_mut()).zip(twiddles.iter()) {
+                let t = (b.0 * wr - b.1 * wi, b.0 * wi + b.1 * wr);
+                *b = (a.0 - t.0, a.1 - t.1);
+                *a = (a.0 + t.0, a.1 + t.1);
+            }
+        }
+        length *= 2;
+    }
+}
+
+// Rows, then columns.
+fn fft_2d(data: &mut [(f32, f32)], width: usize, height: usize, inverse: bool) {
+    for row in data.chunks_exact_mut(width) {
+        fft(row, inverse);
+    }
+    let mut column = vec![(0.0, 0.0); height];
+    for x in 0..width {
+        for (y, v) in column.iter_mut().enumerate() {
+            *v = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for (y, v) in column.iter().enumerate() {
+            data[y * width + x] = *v;
+        }
+    }
+}
+
+// https://en.wikipedia.org/wiki/Convolution_theorem
+//
+/// Correlation of images of one size with a fixed kernel, the same as
+/// [`crate::process::conv_same`] but through the frequency domain, so that its cost
+/// does not grow with the kernel. The kernel is transformed once for every image.
+pub struct Correlation {
+    width: usize,
+    height: usize,
+    /// Power of two sizes large enough that the transform never wraps.
+    padded: (usize, usize),
+    offset: (usize, usize),
+    /// Conjugate spectrum of the kernel, with the normalization of the inverse.
+    spectrum: Vec<(f32, f32)>,
+}
+
+impl Correlation {
+    pub fn new(width: usize, height: usize, kernel: &Image<f32>) -> Self {
+        let padded = (
+            (width + kernel.width - 1).next_power_of_two(),
+            (height + kernel.height - 1).next_power_of_two(),
+        );
+        let mut spectrum = vec![(0.0, 0.0); padded.0 * padded.1];
+        for (y, row) in kernel.pixels.chunks_exact(kernel.width).enumerate() {
+            for (x, v) in row.iter().enumerate() {
+                spectrum[y * padded.0 + x] = (*v, 0.0);
+            }
+        }
+        fft_2d(&mut spectrum, padded.0, padded.1, false);
+        let scale = 1.0 / spectrum.len() as f32;
+        for v in spectrum.iter_mut() {
+            *v = (v.0 * scale, -v.1 * scale);
+        }
+        Self {
+            width,
+            height,
+            padded,
+            offset: (kernel.width / 2, kernel.height / 2),
+            spectrum,
+        }
+    }
+
+    /// Edge pixels are repeated, the output keeps the size of `image`.
+    pub fn apply(&self, image: &Image<f32>) -> Image<f32> {
+        assert_eq!((image.width, image.height), (self.width, self.height));
+        let (width, height) = self.padded;
+        let (px, py) = self.offset;
+        let mut data: Vec<_> = (0..width * height)
+            .map(|i| {
+                let x = (i % width).saturating_sub(px).min(self.width - 1);
+                let y = (i / width).saturating_sub(py).min(self.height - 1);
+                (image.pixels[y * self.width + x], 0.0)
+            })
+            .collect();
+        fft_2d(&mut data, width, height, false);
+        for (v, k) in data.iter_mut().zip(self.spectrum.iter()) {
+            *v = (v.0 * k.0 - v.1 * k.1, v.0 * k.1 + v.1 * k.0);
+        }
+        fft_2d(&mut data, width, height, true);
+        Image {
+            pixels: (0..self.width * self.height)
+                .map(|i| data[(i / self.width) * width + i % self.width].0)
+                .collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process;
+
+    #[test]
+    fn matches_direct_correlation() {
+        let image = Image {
+            pixels: (0..37 * 23)
+                .map(|i| ((i * 7919) % 101) as f32 / 100.0)
+                .collect(),
+            width: 37,
+            height: 23,
+        };
+        // asymmetric, so that a convolution instead of a correlation shows
+        let kernel = Image {
+            pixels: (0..5 * 3).map(|i| (i + 1) as f32 / 120.0).collect(),
+            width: 5,
+            height: 3,
+        };
+        let direct = process::conv_same(&image, &kernel);
+        let fft = Correlation::new(image.width, image.height, &kernel).apply(&image);
+        assert_eq!((fft.width, fft.height), (direct.width, direct.height));
+        for (a, b) in fft.pixels.iter().zip(direct.pixels.iter()) {
+            assert!((a - b).abs() < 1e-5, "{a} {b}");
+        }
+    }
+}
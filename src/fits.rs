@@ -0,0 +1,617 @@
+// https://fits.gsfc.nasa.gov/fits_standard.html
+
+use crate::image::Image;
+
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Value {
+    Logical(bool),
+    Integer(i64),
+    Real(f64),
+    String(String),
+}
+
+impl Value {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Integer(v) => Some(*v as f64),
+            Self::Real(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value == "T" || value == "F" {
+            Some(Self::Logical(value == "T"))
+        } else if let Ok(v) = value.parse() {
+            Some(Self::Integer(v))
+        } else {
+            // fortran style exponents
+            value.replace('D', "E").parse().ok().map(Self::Real)
+        }
+    }
+
+    fn format(&self) -> String {
+        match self {
+            // fixed format: right justified to column 30
+            Self::Logical(v) => format!("{:>20}", if *v { "T" } else { "F" }),
+            Self::Integer(v) => format!("{v:>20}"),
+            Self::Real(v) => format!("{:>20}", format!("{v:.10E}")),
+            Self::String(v) => format!("'{:<8}'", v.replace('\'', "''")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Card {
+    pub key: String,
+    /// `None` for commentary cards.
+    pub value: Option<Value>,
+    pub comment: String,
+}
+
+impl Card {
+    // https://fits.gsfc.nasa.gov/registry/continue_keyword.html
+    //
+    // One or more cards: strings too long for a card are split into CONTINUE
+    // cards, commentary into cards with the same key and comments are cut short.
+    fn format(&self) -> String {
+        let pad = |card: String| {
+            let card: String = card.chars().take(CARD_SIZE).collect();
+            format!("{card:<CARD_SIZE$}")
+        };
+        let with_comment = |mut card: String| {
+            if !self.comment.is_empty() {
+                card.push_str(" / ");
+                card.push_str(&self.comment);
+            }
+            pad(card)
+        };
+        match &self.value {
+            // 8 key, 2 indicator, 2 quotes and an ampersand
+            Some(Value::String(v)) if v.replace('\'', "''").len() > CARD_SIZE - 12 => {
+                let pieces = split_escaped(v, CARD_SIZE - 13);
+                let mut cards = String::new();
+                for (i, piece) in pieces.iter().enumerate() {
+                    let key = if i == 0 {
+                        format!("{:<8}= ", self.key)
+                    } else {
+                        format!("{:<10}", "CONTINUE")
+                    };
+                    if i + 1 < pieces.len() {
+                        cards.push_str(&pad(format!("{key}'{piece}&'")));
+                    } else {
+                        cards.push_str(&with_comment(format!("{key}'{piece}'")));
+                    }
+                }
+                cards
+            }
+            Some(value) => with_comment(format!("{:<8}= {}", self.key, value.format())),
+            None => {
+                let text: Vec<_> = self.comment.chars().collect();
+                if text.is_empty() {
+                    return pad(self.key.clone());
+                }
+                text.chunks(CARD_SIZE - 8)
+                    .map(|line| pad(format!("{:<8}{}", self.key, String::from_iter(line))))
+                    .collect()
+            }
+        }
+    }
+}
+
+// Pieces of `value` with quotes escaped, each at most `size` long, never splitting
+// an escaped quote.
+fn split_escaped(value: &str, size: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    for c in value.chars() {
+        let escaped = if c == '\'' {
+            "''".to_string()
+        } else {
+            c.to_string()
+        };
+        let last = pieces.last_mut().unwrap();
+        if last.len() + escaped.len() > size {
+            pieces.push(escaped);
+        } else {
+            last.push_str(&escaped);
+        }
+    }
+    pieces
+}
+
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Header {
+    pub cards: Vec<Card>,
+}
+
+impl Header {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.cards
+            .iter()
+            .find(|card| card.key == key)
+            .and_then(|card| card.value.as_ref())
+    }
+
+    /// Middle of the exposure in seconds since the Unix epoch, from `DATE-OBS` and
+    /// `EXPTIME`.
+    pub fn time(&self) -> Option<f64> {
+        let start = parse_date(self.get("DATE-OBS")?.as_str()?)?;
+        let exposure = self.get("EXPTIME").and_then(Value::as_f64).unwrap_or(0.0);
+        Some(start + exposure / 2.0)
+    }
+
+    pub fn push(&mut self, key: &str, value: Value, comment: &str) {
+        assert!(key.len() <= 8);
+        self.cards.push(Card {
+            key: key.to_string(),
+            value: Some(value),
+            comment: comment.to_string(),
+        });
+    }
+
+    pub fn history(&mut self, text: &str) {
+        self.cards.push(Card {
+            key: "HISTORY".to_string(),
+            value: None,
+            comment: text.to_string(),
+        });
+    }
+}
+
+/// Writes `channels` as a single 32 bit float HDU, rows top to bottom.
+pub fn write(path: &str, channels: &[Image<f32>], header: &Header) -> std::io::Result<()> {
+    assert!(!channels.is_empty());
+    let width = channels[0].width;
+    let height = channels[0].height;
+    assert!(
+        channels
+            .iter()
+            .all(|c| c.width == width && c.height == height)
+    );
+
+    let mut primary = Header::default();
+    primary.push("SIMPLE", Value::Logical(true), "");
+    primary.push("BITPIX", Value::Integer(-32), "");
+    if channels.len() == 1 {
+        primary.push("NAXIS", Value::Integer(2), "");
+    } else {
+        primary.push("NAXIS", Value::Integer(3), "");
+    }
+    primary.push("NAXIS1", Value::Integer(width as i64), "");
+    primary.push("NAXIS2", Value::Integer(height as i64), "");
+    if channels.len() > 1 {
+        primary.push("NAXIS3", Value::Integer(channels.len() as i64), "");
+    }
+    primary.push("ROWORDER", Value::String("TOP-DOWN".to_string()), "");
+
+    let mut bytes = Vec::new();
+    for card in primary.cards.iter().chain(header.cards.iter()) {
+        bytes.extend_from_slice(card.format().as_bytes());
+    }
+    bytes.extend_from_slice(format!("{:<CARD_SIZE$}", "END").as_bytes());
+    bytes.resize(bytes.len().next_multiple_of(BLOCK_SIZE), b' ');
+
+    for channel in channels.iter() {
+        for pixel in channel.pixels.iter() {
+            bytes.extend_from_slice(&pixel.to_be_bytes());
+        }
+    }
+    bytes.resize(bytes.len().next_multiple_of(BLOCK_SIZE), 0);
+
+    std::fs::write(path, bytes)
+}
+
+// https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+//
+// `YYYY-MM-DD[THH:MM:SS[.sss]]` to seconds since the Unix epoch.
+fn parse_date(date: &str) -> Option<f64> {
+    let (date, time) = date
+        .trim()
+        .split_once('T')
+        .unwrap_or((date.trim(), "0:0:0"));
+    let mut date = date.split('-').map(|v| v.parse::<i64>().ok());
+    let (y, m, d) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':').map(|v| v.parse::<f64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days as f64 * 86400.0 + hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+fn parse_card(card: &str) -> Card {
+    let key = card.get(..8).unwrap_or(card).trim().to_string();
+    let rest = if key == "CONTINUE" {
+        card.get(8..)
+            .filter(|rest| rest.trim_start().starts_with('\''))
+    } else {
+        card.get(10..).filter(|_| card.get(8..10) == Some("= "))
+    };
+    let Some(rest) = rest else {
+        return Card {
+            key,
+            value: None,
+            comment: card.get(8..).unwrap_or("").trim_end().to_string(),
+        };
+    };
+
+    let rest = rest.trim_start();
+    let (value, comment) = if let Some(string) = rest.strip_prefix('\'') {
+        // quotes within strings are escaped by doubling them
+        let mut value = String::new();
+        let mut chars = string.char_indices().peekable();
+        let mut end = string.len();
+        while let Some((i, c)) = chars.next() {
+            if c == '\'' {
+                if chars.peek().is_some_and(|(_, c)| *c == '\'') {
+                    chars.next();
+                } else {
+                    end = i + 1;
+                    break;
+                }
+            }
+            value.push(c);
+        }
+        let comment = string[end..].trim_start().strip_prefix('/').unwrap_or("");
+        (Some(Value::String(value.trim_end().to_string())), comment)
+    } else {
+        let (value, comment) = rest.split_once('/').unwrap_or((rest, ""));
+        (Value::parse(value), comment)
+    };
+    Card {
+        key,
+        value,
+        comment: comment.trim().to_string(),
+    }
+}
+
+/// Reads the primary HDU, returning one image per plane. Integer data is scaled
+/// from the full range of its type to `[0, 1]`, signed unless `BZERO` offsets it
+/// to unsigned values. Floating point data is already physical and is read as is,
+/// so that frames of one session keep the same scale.
+pub fn read(path: &str) -> std::io::Result<(Header, Vec<Image<f32>>)> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let bytes = std::fs::read(path)?;
+
+    let mut header = Header::default();
+    let mut offset = 0;
+    loop {
+        let card = bytes
+            .get(offset..offset + CARD_SIZE)
+            .ok_or_else(|| invalid("missing END card"))?;
+        offset += CARD_SIZE;
+        let card = std::str::from_utf8(card).map_err(|_| invalid("header is not ascii"))?;
+        if card.trim_end() == "END" {
+            break;
+        }
+        let card = parse_card(card);
+        // a string ending in `&` continues in the next CONTINUE card
+        if let (Some(Value::String(continued)), Some(previous)) =
+            (&card.value, header.cards.last_mut())
+            && card.key == "CONTINUE"
+            && let Some(Value::String(value)) = &mut previous.value
+            && value.ends_with('&')
+        {
+            value.pop();
+            value.push_str(continued);
+            previous.comment.push_str(&card.comment);
+            continue;
+        }
+        header.cards.push(card);
+    }
+    let offset = offset.next_multiple_of(BLOCK_SIZE);
+
+    let int = |key: &str| {
+        header
+            .get(key)
+            .and_then(Value::as_f64)
+            .map(|v| v as usize)
+            .ok_or_else(|| invalid(&format!("missing {key}")))
+    };
+    let bitpix = header
+        .get("BITPIX")
+        .and_then(Value::as_f64)
+        .ok_or_else(|| invalid("missing BITPIX"))? as i64;
+    let naxis = int("NAXIS")?;
+    if !(2..=3).contains(&naxis) {
+        return Err(invalid("only 2 and 3 dimensional images are supported"));
+    }
+    let width = int("NAXIS1")?;
+    let height = int("NAXIS2")?;
+    let planes = if naxis == 3 { int("NAXIS3")? } else { 1 };
+    let bzero = header.get("BZERO").and_then(Value::as_f64).unwrap_or(0.0);
+    let bscale = header.get("BSCALE").and_then(Value::as_f64).unwrap_or(1.0);
+
+    if ![8, 16, 32, -32, -64].contains(&bitpix) {
+        return Err(invalid("unsupported BITPIX"));
+    }
+    let bytes_per_value = bitpix.unsigned_abs() as usize / 8;
+    let count = width
+        .checked_mul(height)
+        .and_then(|v| v.checked_mul(planes))
+        .filter(|count| *count > 0)
+        .ok_or_else(|| invalid("invalid image size"))?;
+    let data = count
+        .checked_mul(bytes_per_value)
+        .and_then(|size| bytes.get(offset..offset.checked_add(size)?))
+        .ok_or_else(|| invalid("truncated data"))?;
+    let mut values: Vec<f64> = data
+        .chunks_exact(bytes_per_value)
+        .map(|b| match bitpix {
+            8 => b[0] as f64,
+            16 => i16::from_be_bytes(b.try_into().unwrap()) as f64,
+            32 => i32::from_be_bytes(b.try_into().unwrap()) as f64,
+            -32 => f32::from_be_bytes(b.try_into().unwrap()) as f64,
+            _ => f64::from_be_bytes(b.try_into().unwrap()),
+        })
+        .map(|v| bzero + bscale * v)
+        .collect();
+
+    // BITPIX 8 is unsigned, 16 and 32 are signed
+    let range = match bitpix {
+        8 => Some((u8::MIN as f64, u8::MAX as f64)),
+        16 => Some((i16::MIN as f64, i16::MAX as f64)),
+        32 => Some((i32::MIN as f64, i32::MAX as f64)),
+        _ => None,
+    };
+    if let Some((min, max)) = range {
+        let (a, b) = (bzero + bscale * min, bzero + bscale * max);
+        let (low, high) = (a.min(b), a.max(b));
+        for v in values.iter_mut() {
+            *v = (*v - low) / (high - low);
+        }
+    }
+
+    let images = values
+        .chunks_exact(width * height)
+        .map(|plane| Image {
+            pixels: plane.iter().map(|v| *v as f32).collect(),
+            width,
+            height,
+        })
+        .collect();
+    Ok((header, images))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(
+        name: &str,
+        channels: &[Image<f32>],
+        header: &Header,
+    ) -> (Header, Vec<Image<f32>>) {
+        let path = std::env::temp_dir().join(format!("spack-{}-{name}.fits", std::process::id()));
+        let path = path.to_str().unwrap();
+        write(path, channels, header).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let read = read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(bytes.len() % BLOCK_SIZE, 0);
+        read
+    }
+
+    // header cards followed by `data`, as written by other software
+    fn raw_file(name: &str, cards: &[(&str, Value)], data: &[u8]) -> String {
+        let mut header = Header::default();
+        for (key, value) in cards {
+            header.push(key, value.clone(), "");
+        }
+        let mut bytes: Vec<u8> = header
+            .cards
+            .iter()
+            .flat_map(|c| c.format().into_bytes())
+            .collect();
+        bytes.extend_from_slice(format!("{:<CARD_SIZE$}", "END").as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(BLOCK_SIZE), b' ');
+        bytes.extend_from_slice(data);
+        bytes.resize(bytes.len().next_multiple_of(BLOCK_SIZE), 0);
+        let path = std::env::temp_dir().join(format!("spack-{}-{name}.fits", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn float_round_trip() {
+        let plane = |offset: f32| Image {
+            pixels: (0..12).map(|i| offset + i as f32 * 1e-5).collect(),
+            width: 4,
+            height: 3,
+        };
+        let mut channels = [plane(0.0), plane(0.25), plane(0.5)];
+        // a hot pixel must not change the scale of the frame
+        channels[2].pixels[5] = 40000.0;
+        channels[1].pixels[0] = -3.5;
+        let mut header = Header::default();
+        header.push("EXPTIME", Value::Real(120.0), "[s]");
+        header.push(
+            "DATE-OBS",
+            Value::String("2024-03-01T22:10:05.5".to_string()),
+            "",
+        );
+        header.push("BAYERPAT", Value::String("RGGB".to_string()), "");
+        header.push("XBAYROFF", Value::Integer(1), "");
+        header.push("SNAPSHOT", Value::Logical(false), "");
+
+        let (read, planes) = round_trip("float", &channels, &header);
+        assert_eq!(read.get("NAXIS3").and_then(Value::as_f64), Some(3.0));
+        for card in header.cards.iter() {
+            assert_eq!(read.get(&card.key), card.value.as_ref());
+        }
+        assert_eq!(
+            read.cards
+                .iter()
+                .find(|c| c.key == "EXPTIME")
+                .unwrap()
+                .comment,
+            "[s]"
+        );
+        // read unscaled, with every bit of precision
+        assert_eq!(planes.len(), 3);
+        for (plane, channel) in planes.iter().zip(channels.iter()) {
+            assert_eq!((plane.width, plane.height), (4, 3));
+            assert_eq!(plane.pixels, channel.pixels);
+        }
+    }
+
+    #[test]
+    fn integer_data() {
+        // unsigned 16 bit data is stored signed with BZERO
+        let values: [u16; 4] = [0, 1, 32768, 65535];
+        let data: Vec<u8> = values
+            .iter()
+            .flat_map(|v| ((*v as i32 - 32768) as i16).to_be_bytes())
+            .collect();
+        let cards = [
+            ("SIMPLE", Value::Logical(true)),
+            ("BITPIX", Value::Integer(16)),
+            ("NAXIS", Value::Integer(2)),
+            ("NAXIS1", Value::Integer(2)),
+            ("NAXIS2", Value::Integer(2)),
+            ("BZERO", Value::Real(32768.0)),
+        ];
+        let path = raw_file("integer", &cards, &data);
+        let (_, planes) = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected: Vec<_> = values.iter().map(|v| *v as f32 / 65535.0).collect();
+        assert_eq!(planes[0].pixels, expected);
+    }
+
+    #[test]
+    fn signed_integer_data() {
+        let values: [i16; 4] = [i16::MIN, -1, 0, i16::MAX];
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let cards = [
+            ("SIMPLE", Value::Logical(true)),
+            ("BITPIX", Value::Integer(16)),
+            ("NAXIS", Value::Integer(2)),
+            ("NAXIS1", Value::Integer(2)),
+            ("NAXIS2", Value::Integer(2)),
+        ];
+        let path = raw_file("signed", &cards, &data);
+        let (_, planes) = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected: Vec<_> = values
+            .iter()
+            .map(|v| (*v as f64 + 32768.0) as f32 / 65535.0)
+            .collect();
+        assert_eq!(planes[0].pixels, expected);
+        assert!(planes[0].pixels.iter().all(|v| (0.0..=1.0).contains(v)));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let header = |bitpix: i64, naxis1: i64| {
+            [
+                ("SIMPLE", Value::Logical(true)),
+                ("BITPIX", Value::Integer(bitpix)),
+                ("NAXIS", Value::Integer(2)),
+                ("NAXIS1", Value::Integer(naxis1)),
+                ("NAXIS2", Value::Integer(2)),
+            ]
+        };
+        for (name, cards, data) in [
+            ("bitpix0", header(0, 2), vec![0; 16]),
+            ("bitpix4", header(4, 2), vec![0; 16]),
+            ("bitpix24", header(24, 2), vec![0; 16]),
+            ("truncated", header(8, 4000), vec![0; 16]),
+            ("empty", header(8, 0), vec![]),
+            ("negative", header(8, -2), vec![]),
+        ] {
+            let path = raw_file(name, &cards, &data);
+            assert!(read(&path).is_err(), "{name}");
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let path = raw_file("noend", &[("SIMPLE", Value::Logical(true))], &[]);
+        let bytes = std::fs::read(&path).unwrap();
+        let without_end: Vec<u8> = bytes[..BLOCK_SIZE]
+            .chunks(CARD_SIZE)
+            .filter(|card| !card.starts_with(b"END"))
+            .flatten()
+            .copied()
+            .collect();
+        std::fs::write(&path, without_end).unwrap();
+        assert!(read(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0.0));
+        assert_eq!(parse_date("1970-01-02T00:00:01.5"), Some(86401.5));
+        // leap day and a date before the epoch
+        assert_eq!(parse_date("2000-03-01T00:00:00"), Some(951868800.0));
+        assert_eq!(parse_date("2024-02-29T12:00:00"), Some(1709208000.0));
+        assert_eq!(parse_date("1969-12-31T23:59:59"), Some(-1.0));
+        assert_eq!(parse_date("2024-02"), None);
+        assert_eq!(parse_date("2024-02-29T12"), None);
+        assert_eq!(parse_date("yesterday"), None);
+
+        let mut header = Header::default();
+        header.push(
+            "DATE-OBS",
+            Value::String("1970-01-01T00:01:00".to_string()),
+            "",
+        );
+        header.push("EXPTIME", Value::Integer(30), "");
+        assert_eq!(header.time(), Some(75.0));
+    }
+
+    #[test]
+    fn long_cards() {
+        let long = format!("data/{}'s frame.fits", "m31 ".repeat(40));
+        let mut header = Header::default();
+        header.push("FILENAME", Value::String(long.clone()), "source");
+        header.push(
+            "OBJECT",
+            Value::String("M 31".to_string()),
+            &"x".repeat(100),
+        );
+        header.history(&"a".repeat(150));
+
+        let formatted: Vec<_> = header.cards.iter().map(Card::format).collect();
+        for card in formatted.iter() {
+            assert_eq!(card.len() % CARD_SIZE, 0);
+        }
+        assert!(formatted[0].len() > CARD_SIZE);
+        assert!(formatted[0][CARD_SIZE..].starts_with("CONTINUE  '"));
+        assert_eq!(formatted[1].len(), CARD_SIZE);
+        assert_eq!(formatted[2].len(), 3 * CARD_SIZE);
+
+        let image = Image {
+            pixels: vec![0.5; 4],
+            width: 2,
+            height: 2,
+        };
+        let (read, _) = round_trip("long", &[image], &header);
+        assert_eq!(
+            read.get("FILENAME").and_then(Value::as_str),
+            Some(&long[..])
+        );
+        assert_eq!(read.get("OBJECT").and_then(Value::as_str), Some("M 31"));
+        let history: String = read
+            .cards
+            .iter()
+            .filter(|c| c.key == "HISTORY")
+            .map(|c| c.comment.clone())
+            .collect();
+        assert_eq!(history, "a".repeat(150));
+    }
+}
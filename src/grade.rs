@@ -0,0 +1,230 @@
+use crate::{metrics::FrameMetrics, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Metric {
+    StarCount,
+    Fwhm,
+    Eccentricity,
+    Background,
+    Snr,
+    /// RMS distance between matched stars after registration.
+    AlignmentResidual,
+}
+
+impl Metric {
+    pub const ALL: [Self; 6] = [
+        Self::StarCount,
+        Self::Fwhm,
+        Self::Eccentricity,
+        Self::Background,
+        Self::Snr,
+        Self::AlignmentResidual,
+    ];
+
+    pub fn value(&self, grade: &FrameGrade) -> f32 {
+        match self {
+            Self::StarCount => grade.metrics.star_count as f32,
+            Self::Fwhm => grade.metrics.fwhm,
+            Self::Eccentricity => grade.metrics.eccentricity,
+            Self::Background => grade.metrics.background,
+            Self::Snr => grade.metrics.snr,
+            Self::AlignmentResidual => grade.residual.unwrap_or(f32::INFINITY),
+        }
+    }
+
+    pub fn higher_is_better(&self) -> bool {
+        matches!(self, Self::StarCount | Self::Snr)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::StarCount => "stars",
+            Self::Fwhm => "fwhm",
+            Self::Eccentricity => "ecc",
+            Self::Background => "bg",
+            Self::Snr => "snr",
+            Self::AlignmentResidual => "residual",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Rule {
+    /// Reject frames where `metric > factor * median`.
+    AboveMedian { metric: Metric, factor: f32 },
+    /// Reject frames where `metric < factor * median`.
+    BelowMedian { metric: Metric, factor: f32 },
+    /// Keep the best `fraction` of frames ranked by `metric`.
+    KeepBest { metric: Metric, fraction: f32 },
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AboveMedian { metric, factor } => {
+                write!(f, "{} > {factor}x median", metric.name())
+            }
+            Self::BelowMedian { metric, factor } => {
+                write!(f, "{} < {factor}x median", metric.name())
+            }
+            Self::KeepBest { metric, fraction } => {
+                write!(f, "not best {:.0}% {}", fraction * 100.0, metric.name())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FrameGrade {
+    pub frame: usize,
+    pub metrics: FrameMetrics,
+    /// `None` if the frame failed to register.
+    pub residual: Option<f32>,
+    /// Rules the frame failed, empty if accepted.
+    pub rejected_by: Vec<Rule>,
+}
+
+impl FrameGrade {
+    pub fn rejected(&self) -> bool {
+        self.residual.is_none() || !self.rejected_by.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Report {
+    pub grades: Vec<FrameGrade>,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame")?;
+        for metric in Metric::ALL {
+            write!(f, " {:>9}", metric.name())?;
+        }
+        writeln!(f, "  status")?;
+        for grade in self.grades.iter() {
+            write!(f, "{:>5}", grade.frame)?;
+            for metric in Metric::ALL {
+                write!(f, " {:>9.3}", metric.value(grade))?;
+            }
+            if grade.residual.is_none() {
+                writeln!(f, "  rejected: failed to register")?;
+            } else if grade.rejected_by.is_empty() {
+                writeln!(f, "  accepted")?;
+            } else {
+                let reasons: Vec<_> = grade.rejected_by.iter().map(|r| r.to_string()).collect();
+                writeln!(f, "  rejected: {}", reasons.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `frames` is `(metrics, alignment residual)` for each frame.
+pub fn grade(frames: &[(FrameMetrics, Option<f32>)], rules: &[Rule]) -> Report {
+    let mut grades: Vec<_> = frames
+        .iter()
+        .enumerate()
+        .map(|(frame, (metrics, residual))| FrameGrade {
+            frame,
+            metrics: *metrics,
+            residual: *residual,
+            rejected_by: Vec::new(),
+        })
+        .collect();
+
+    for rule in rules.iter() {
+        match *rule {
+            Rule::AboveMedian { metric, factor } | Rule::BelowMedian { metric, factor } => {
+                let mut values: Vec<_> = grades
+                    .iter()
+                    .map(|g| metric.value(g))
+                    .filter(|v| v.is_finite())
+                    .collect();
+                let limit = factor * stats::median(&mut values);
+                for grade in grades.iter_mut() {
+                    let value = metric.value(grade);
+                    let reject = match rule {
+                        Rule::AboveMedian { .. } => value > limit,
+                        _ => value < limit,
+                    };
+                    if reject {
+                        grade.rejected_by.push(*rule);
+                    }
+                }
+            }
+            Rule::KeepBest { metric, fraction } => {
+                let mut ranked: Vec<_> = (0..grades.len()).collect();
+                ranked.sort_by(|a, b| {
+                    let a = metric.value(&grades[*a]);
+                    let b = metric.value(&grades[*b]);
+                    if metric.higher_is_better() {
+                        b.total_cmp(&a)
+                    } else {
+                        a.total_cmp(&b)
+                    }
+                });
+                let keep = (grades.len() as f32 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+                for i in ranked.into_iter().skip(keep) {
+                    grades[i].rejected_by.push(*rule);
+                }
+            }
+        }
+    }
+
+    Report { grades }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(star_count: usize, fwhm: f32) -> (FrameMetrics, Option<f32>) {
+        let metrics = FrameMetrics {
+            star_count,
+            fwhm,
+            ..Default::default()
+        };
+        (metrics, Some(0.2))
+    }
+
+    fn rejected(report: &Report) -> Vec<usize> {
+        report
+            .grades
+            .iter()
+            .filter(|g| g.rejected())
+            .map(|g| g.frame)
+            .collect()
+    }
+
+    #[test]
+    fn rules() {
+        let frames = [
+            frame(100, 2.0),
+            frame(90, 2.2),
+            frame(40, 2.1),
+            frame(110, 4.0),
+            (FrameMetrics::default(), None),
+        ];
+        let above = Rule::AboveMedian {
+            metric: Metric::Fwhm,
+            factor: 1.5,
+        };
+        let below = Rule::BelowMedian {
+            metric: Metric::StarCount,
+            factor: 0.5,
+        };
+        let best = Rule::KeepBest {
+            metric: Metric::StarCount,
+            fraction: 0.4,
+        };
+        assert_eq!(rejected(&grade(&frames, &[above])), [3, 4]);
+        assert_eq!(rejected(&grade(&frames, &[below])), [2, 4]);
+        assert_eq!(rejected(&grade(&frames, &[best])), [1, 2, 4]);
+
+        let report = grade(&frames, &[above, below, best]);
+        assert_eq!(report.grades[3].rejected_by, [above]);
+        assert_eq!(report.grades[2].rejected_by, [below, best]);
+        assert!(report.grades[0].rejected_by.is_empty());
+    }
+}
@@ -1,48 +1,632 @@
-use crate::process;
+use crate::{
+    ALIGN_THRESHOLD, DARK_DIR, DATA_DIR, align, background, catalog, color, comet, cosmetic,
+    deconvolve, denoise, drizzle, fits, grade, integrate, metrics, mosaic, normalize, photometry,
+    process, solve, starless, starmask, stretch, trail, wcs, weight,
+};
 use std::collections::HashMap;
 use tint::{Color, LinearRgb, Srgb};
 
+const NO_REFERENCE_STARS: &str = "the stack is not in the geometry of the reference frame";
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ImageMemory {
-    pub raw: Vec<Image<Srgb>>,
+    pub paths: Vec<String>,
+    /// Empty for formats without metadata.
+    pub headers: Vec<fits::Header>,
+    /// Hot and cold pixels from the master dark, corrected in every frame.
+    pub defects: Option<Image<bool>>,
+    /// Cosmetically corrected frames as linear channels, FITS data as read.
+    pub raw: Vec<[Image<f32>; 3]>,
     pub processed: HashMap<usize, ProcessedImage>,
     pub selected_image: usize,
+    /// Best frame according to [`metrics::select_reference`].
+    pub auto_reference: usize,
+    pub reference_override: Option<usize>,
+    pub distortion_order: usize,
+    /// Transforms from each frame into the reference frame, missing if the frame
+    /// failed to register.
+    pub registrations: HashMap<usize, align::Registration>,
+    pub grading_rules: Vec<grade::Rule>,
+    /// Rejected frames are excluded from stacking.
+    pub grades: grade::Report,
+    pub weighting: weight::Weighting,
+    /// Applied before rejection.
+    pub normalization: normalize::Normalization,
+    pub rejection: Option<integrate::Rejection>,
+    pub drizzle: drizzle::Drizzle,
+    pub stack: Option<integrate::Integration>,
+    pub background: background::Settings,
+    /// Last background removed from `stack`.
+    pub extraction: Option<background::Extraction>,
+    pub stretch: stretch::Stretch,
+    pub deconvolution: deconvolve::Deconvolution,
+    pub noise_reduction: denoise::NoiseReduction,
+    pub star_reduction: starmask::StarReduction,
+    /// Mask of the last star reduction.
+    pub star_mask: Option<Image<Srgb>>,
+    pub starless: starless::Starless,
+    /// Stars removed from the stack, until they are restored.
+    pub stars: Option<starless::Stars>,
+    pub comet: comet::Comet,
+    pub mosaic: mosaic::Mosaic,
+    pub photometry: photometry::Photometry,
+    /// Astrometric solution of each solved frame.
+    pub solutions: HashMap<usize, wcs::Wcs>,
 }
 
 impl Default for ImageMemory {
     fn default() -> Self {
-        let raw: Vec<_> = std::fs::read_dir("data")
-            .unwrap()
-            .take(5)
-            .map(|entry| {
-                let path = entry.unwrap().path();
-                let path_str = path.to_str().unwrap();
-                Image::from_path(path_str)
-            })
-            .collect();
+        let mut paths = frame_paths(DATA_DIR);
+        paths.truncate(5);
+        let defects = match cosmetic::master_dark(DARK_DIR) {
+            Ok(dark) => dark.map(|dark| cosmetic::defect_map(&dark, 5.0)),
+            Err(err) => {
+                println!("skipping the master dark: {err}");
+                None
+            }
+        };
+        let mut frames = Vec::new();
+        paths.retain(|path| match load_corrected(path, defects.as_ref()) {
+            Ok(frame) => {
+                frames.push(frame);
+                true
+            }
+            Err(err) => {
+                println!("skipping {err}");
+                false
+            }
+        });
+        let (raw, headers): (Vec<_>, Vec<_>) = frames.into_iter().unzip();
         if raw.is_empty() {
             panic!("no images in data directory");
         }
-        let processed = raw.iter().map(process_image).enumerate().collect();
+        let processed: HashMap<_, _> = raw.iter().map(process_image).enumerate().collect();
+        // frames solved elsewhere
+        let solutions = headers
+            .iter()
+            .enumerate()
+            .filter_map(|(frame, header)| Some((frame, wcs::Wcs::from_header(header)?)))
+            .collect();
+        let metrics: Vec<_> = (0..raw.len()).map(|i| processed[&i].metrics).collect();
+        let auto_reference = metrics::select_reference(&metrics);
 
-        Self {
+        let mut memory = Self {
+            paths,
+            headers,
+            defects,
             raw,
             processed,
             selected_image: 0,
+            auto_reference,
+            reference_override: None,
+            distortion_order: 1,
+            registrations: HashMap::new(),
+            grading_rules: vec![grade::Rule::AboveMedian {
+                metric: grade::Metric::Fwhm,
+                factor: 1.5,
+            }],
+            grades: grade::Report::default(),
+            weighting: weight::Weighting::InverseVariance,
+            normalization: normalize::Normalization::AdditiveScaling,
+            rejection: Some(integrate::Rejection {
+                low: 3.0,
+                high: 3.0,
+            }),
+            drizzle: drizzle::Drizzle {
+                scale: 2.0,
+                pixfrac: 0.7,
+            },
+            stack: None,
+            background: background::Settings {
+                model: background::Model::Polynomial(2),
+                correction: background::Correction::Subtract,
+                grid: 16,
+            },
+            extraction: None,
+            stretch: stretch::Stretch::Arcsinh {
+                black_point: 0.0,
+                factor: 100.0,
+            },
+            deconvolution: deconvolve::Deconvolution {
+                psf: deconvolve::Psf::Stars,
+                iterations: 20,
+                regularization: 0.002,
+                deringing: 0.8,
+                protect_stars: true,
+            },
+            noise_reduction: denoise::NoiseReduction {
+                method: denoise::Method::Wavelet {
+                    layers: 4,
+                    threshold: 3.0,
+                },
+                mask: true,
+            },
+            star_reduction: starmask::StarReduction {
+                mask: starmask::StarMask {
+                    radius: 1.5,
+                    grow: 1,
+                    feather: 1.5,
+                },
+                size: 3,
+                iterations: 1,
+                amount: 0.7,
+            },
+            star_mask: None,
+            starless: starless::Starless {
+                mask: starmask::StarMask {
+                    radius: 1.5,
+                    grow: 2,
+                    feather: 0.0,
+                },
+                inpainting: starless::Inpainting::Diffusion { iterations: 50 },
+                blend: starless::Blend::Add,
+            },
+            stars: None,
+            comet: comet::Comet {
+                radius: 20.0,
+                ..Default::default()
+            },
+            mosaic: mosaic::Mosaic {
+                feather: 100.0,
+                match_backgrounds: true,
+            },
+            photometry: photometry::Photometry {
+                aperture: photometry::Aperture {
+                    radius: 2.0,
+                    inner: 4.0,
+                    outer: 6.0,
+                },
+                stars: Vec::new(),
+            },
+            solutions,
+        };
+        memory.register_all();
+        memory
+    }
+}
+
+/// Sorted frames in `directory`.
+pub fn frame_paths(directory: &str) -> Vec<String> {
+    let mut paths: Vec<_> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        // skip the cache and subdirectories
+        .filter(|path| {
+            path.is_file()
+                && !path
+                    .file_name()
+                    .is_some_and(|name| name.to_str().unwrap().starts_with('.'))
+        })
+        .map(|path| path.to_str().unwrap().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+impl ImageMemory {
+    /// Registers against the current reference unless `frame` already was, then
+    /// grades every frame again.
+    pub fn add_frame(&mut self, frame: NewFrame) -> usize {
+        let index = self.raw.len();
+        self.processed.insert(index, frame.processed);
+        self.paths.push(frame.path);
+        if let Some(wcs) = wcs::Wcs::from_header(&frame.header) {
+            self.solutions.insert(index, wcs);
+        }
+        self.headers.push(frame.header);
+        self.raw.push(frame.raw);
+        if let Some(registration) = frame.registration.or_else(|| self.register(index)) {
+            self.registrations.insert(index, registration);
+        }
+        self.update_trails();
+        self.regrade();
+        index
+    }
+
+    /// Marks or unmarks the star nearest `(x, y)` in `frame` for photometry.
+    pub fn mark_photometry(&mut self, frame: usize, x: f32, y: f32) -> Result<bool, String> {
+        let registration = self
+            .registrations
+            .get(&frame)
+            .ok_or_else(|| format!("frame {frame} failed to register"))?;
+        let offset = self.processed[&frame].log_offset();
+        let (x, y) = registration.transform.offset(offset).apply(x, y);
+        let reference = self.reference();
+        self.photometry
+            .toggle(&self.processed[&reference].stars, x, y)
+    }
+
+    /// Plate solves every frame against `index`, building it from `catalog` first
+    /// if it does not exist. Returns the number of solved frames.
+    pub fn plate_solve(&mut self, index: &str, catalog: &str) -> Result<usize, String> {
+        let index = if std::fs::exists(index).unwrap_or(false) {
+            solve::Index::read(index)?
+        } else {
+            let built = solve::Index::build(
+                &catalog::read(catalog)?,
+                crate::INDEX_RADIUS,
+                crate::INDEX_STARS,
+            );
+            built.write(index)?;
+            println!(
+                "built {index} with {} tiles and {} triangles",
+                built.tiles.len(),
+                built.triangles.len()
+            );
+            built
+        };
+
+        for frame in 0..self.raw.len() {
+            let (width, height) = (self.raw[frame][0].width, self.raw[frame][0].height);
+            match solve::solve(&index, width, height, &self.processed[&frame].stars) {
+                Some(wcs) => {
+                    let (ra, dec) = wcs.pixel_to_sky(width as f64 / 2.0, height as f64 / 2.0);
+                    println!("{}: ra {ra:.5} dec {dec:.5}, {wcs}", self.paths[frame]);
+                    self.solutions.insert(frame, wcs);
+                }
+                None => println!("{}: no solution", self.paths[frame]),
+            }
+        }
+        Ok(self.solutions.len())
+    }
+
+    /// Removes the background gradient from the stack.
+    pub fn extract_background(&mut self) -> Result<(), String> {
+        let reference = self.reference();
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let star_scale = stack.star_scale.ok_or(NO_REFERENCE_STARS)?;
+        let extraction = background::extract(
+            &mut stack.channels,
+            &self.processed[&reference].stars,
+            star_scale,
+            self.background,
+        );
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack.header.history(&format!(
+            "background {:?} {:?} with {} samples",
+            self.background.correction,
+            self.background.model,
+            extraction.samples.iter().filter(|s| !s.rejected).count()
+        ));
+        self.extraction = Some(extraction);
+        Ok(())
+    }
+
+    /// Photometric color calibration of the stack against the stars in `catalog`.
+    pub fn calibrate_color(&mut self, catalog: &str) -> Result<(), String> {
+        let entries = catalog::read(catalog)?;
+        let reference = self.reference();
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let star_scale = stack.star_scale.ok_or(NO_REFERENCE_STARS)?;
+        let calibration = color::calibrate(
+            &mut stack.channels,
+            &self.processed[&reference].stars,
+            star_scale,
+            &entries,
+        )?;
+        stack.preview = channels_to_srgb(&stack.channels);
+        let [r, g, b] = calibration.white_balance;
+        stack.header.history(&format!(
+            "color calibration from {} stars, white balance {r:.4} {g:.4} {b:.4}",
+            calibration.stars
+        ));
+        println!(
+            "calibrated color from {} stars: background {:?}, white balance {:?}",
+            calibration.stars, calibration.background, calibration.white_balance
+        );
+        Ok(())
+    }
+
+    /// Deconvolves the linear stack with [`ImageMemory::deconvolution`].
+    pub fn deconvolve_stack(&mut self) -> Result<(), String> {
+        let reference = self.reference();
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let star_scale = stack.star_scale.ok_or(NO_REFERENCE_STARS)?;
+        deconvolve::deconvolve(
+            &mut stack.channels,
+            &self.processed[&reference].stars,
+            star_scale,
+            self.deconvolution,
+        )?;
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack
+            .header
+            .history(&format!("deconvolution {}", self.deconvolution));
+        Ok(())
+    }
+
+    /// Denoises the stack, masked against the background left by
+    /// [`ImageMemory::extract_background`] if it was run.
+    pub fn denoise_stack(&mut self) -> Result<(), String> {
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let background = self.extraction.as_ref().map(|e| e.level);
+        denoise::denoise(&mut stack.channels, background, self.noise_reduction);
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack
+            .header
+            .history(&format!("noise reduction {}", self.noise_reduction));
+        Ok(())
+    }
+
+    /// Shrinks the stars of the stack by selective erosion.
+    pub fn reduce_stars(&mut self) -> Result<(), String> {
+        let reference = self.reference();
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let star_scale = stack.star_scale.ok_or(NO_REFERENCE_STARS)?;
+        let mask = starmask::reduce(
+            &mut stack.channels,
+            &self.processed[&reference].stars,
+            star_scale,
+            self.star_reduction,
+        );
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack
+            .header
+            .history(&format!("star reduction {}", self.star_reduction));
+        self.star_mask = Some(Image {
+            pixels: mask
+                .pixels
+                .iter()
+                .map(|v| Srgb::from_luminance(*v))
+                .collect(),
+            width: mask.width,
+            height: mask.height,
+        });
+        Ok(())
+    }
+
+    /// Replaces the stack, dropping what was derived from the previous one.
+    pub fn set_stack(&mut self, stack: integrate::Integration) {
+        self.stack = Some(stack);
+        self.extraction = None;
+        self.star_mask = None;
+        self.stars = None;
+    }
+
+    /// Leaves the stack starless, keeping the stars to restore later.
+    pub fn remove_stars(&mut self) -> Result<(), String> {
+        if self.stars.is_some() {
+            return Err("the stars were already removed".to_string());
+        }
+        let reference = self.reference();
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let star_scale = stack.star_scale.ok_or(NO_REFERENCE_STARS)?;
+        let stars = starless::separate(
+            &mut stack.channels,
+            &self.processed[&reference].stars,
+            star_scale,
+            self.starless,
+        );
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack
+            .header
+            .history(&format!("star removal {}", self.starless));
+        self.stars = Some(stars);
+        Ok(())
+    }
+
+    pub fn restore_stars(&mut self) -> Result<(), String> {
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        let stars = self.stars.as_ref().ok_or("the stars were not removed")?;
+        // kept for a stack of the size they were removed from
+        starless::recombine(&mut stack.channels, &stars.channels, self.starless.blend)?;
+        self.stars = None;
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack
+            .header
+            .history(&format!("stars restored with {:?}", self.starless.blend));
+        Ok(())
+    }
+
+    /// Permanently stretches the stack, recording the parameters in its header.
+    pub fn stretch_stack(&mut self) -> Result<(), String> {
+        let stack = self.stack.as_mut().ok_or("nothing is stacked")?;
+        stretch::stretch(&mut stack.channels, self.stretch)?;
+        stack.preview = channels_to_srgb(&stack.channels);
+        stack.header.history(&format!("stretch {}", self.stretch));
+        Ok(())
+    }
+
+    pub fn reference(&self) -> usize {
+        self.reference_override.unwrap_or(self.auto_reference)
+    }
+
+    pub fn set_reference_override(&mut self, reference: Option<usize>) {
+        self.reference_override = reference;
+        self.register_all();
+    }
+
+    pub fn set_distortion_order(&mut self, order: usize) {
+        self.distortion_order = order.clamp(1, align::MAX_DISTORTION_ORDER);
+        self.register_all();
+    }
+
+    /// Residuals of every distortion order fit to the matches of `frame`.
+    pub fn distortion_orders(
+        &self,
+        frame: usize,
+    ) -> Result<Vec<(usize, align::Residuals)>, String> {
+        let reference = self.reference();
+        if frame == reference {
+            return Err(format!("image {frame} is the reference"));
+        }
+        let registration = self
+            .registrations
+            .get(&frame)
+            .ok_or_else(|| format!("image {frame} failed to register"))?;
+        Ok(align::distortion_orders(
+            &self.processed[&frame].local_max_points,
+            &self.processed[&reference].local_max_points,
+            &registration.pairs,
+        ))
+    }
+
+    fn register_all(&mut self) {
+        self.registrations = (0..self.raw.len())
+            .filter_map(|i| self.register(i).map(|registration| (i, registration)))
+            .collect();
+        self.update_trails();
+        self.regrade();
+    }
+
+    // Masks the trails of every frame that moved under the current registrations.
+    // Frames whose mask changed are detected and registered again, every frame if
+    // it was the reference, as their stars index the registrations.
+    fn update_trails(&mut self) {
+        let trails: Vec<_> = (0..self.raw.len())
+            .map(|i| {
+                let processed = &self.processed[&i];
+                let transform = self
+                    .registrations
+                    .get(&i)
+                    .map(|r| r.transform.offset(processed.log_offset()));
+                (processed.trails.as_slice(), transform)
+            })
+            .collect();
+        let changed: Vec<_> = trail::moving(&trails)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, masked)| *masked != self.processed[i].masked)
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+
+        let reference = self.reference();
+        let all = changed.iter().any(|(i, _)| *i == reference);
+        let frames: Vec<_> = changed.iter().map(|(i, _)| *i).collect();
+        for (i, masked) in changed {
+            let trails = self.processed[&i].trails.clone();
+            let processed = detect_stars(&self.raw[i], trails, masked);
+            self.processed.insert(i, processed);
         }
+        for i in 0..self.raw.len() {
+            if !all && !frames.contains(&i) {
+                continue;
+            }
+            match self.register(i) {
+                Some(registration) => self.registrations.insert(i, registration),
+                None => self.registrations.remove(&i),
+            };
+        }
+    }
+
+    fn register(&self, frame: usize) -> Option<align::Registration> {
+        let reference = self.reference();
+        if frame == reference {
+            return Some(align::Registration {
+                transform: align::Similarity::IDENTITY.into(),
+                pairs: Vec::new(),
+                residuals: align::Residuals::default(),
+            });
+        }
+        let processed = &self.processed[&frame];
+        align::register(
+            processed.log.width,
+            processed.log.height,
+            &processed.local_max_points,
+            &self.processed[&reference].local_max_points,
+            ALIGN_THRESHOLD,
+            self.distortion_order,
+        )
+    }
+
+    pub fn regrade(&mut self) {
+        let frames: Vec<_> = (0..self.raw.len())
+            .map(|i| {
+                (
+                    self.processed[&i].metrics,
+                    self.registrations.get(&i).map(|r| r.residuals.rms),
+                )
+            })
+            .collect();
+        self.grades = grade::grade(&frames, &self.grading_rules);
+    }
+}
+
+/// A frame read from disk, ready for [`ImageMemory::add_frame`].
+pub struct NewFrame {
+    pub path: String,
+    pub raw: [Image<f32>; 3],
+    pub header: fits::Header,
+    pub processed: ProcessedImage,
+    pub registration: Option<align::Registration>,
+}
+
+impl NewFrame {
+    /// Loads, corrects and processes `path` and registers it against the
+    /// detections of the reference frame, without an [`ImageMemory`] so that it can
+    /// run on another thread.
+    pub fn read(
+        path: &str,
+        defects: Option<&Image<bool>>,
+        reference: &[(f32, f32, f32)],
+        distortion_order: usize,
+    ) -> Result<Self, String> {
+        let (raw, header) = load_corrected(path, defects)?;
+        let processed = process_image(&raw);
+        let registration = align::register(
+            processed.log.width,
+            processed.log.height,
+            &processed.local_max_points,
+            reference,
+            ALIGN_THRESHOLD,
+            distortion_order,
+        );
+        Ok(Self {
+            path: path.to_string(),
+            raw,
+            header,
+            processed,
+            registration,
+        })
     }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ProcessedImage {
+    /// Display preview of the frame, detection runs on the linear data.
     pub raw: Image<Srgb>,
     pub log: Image<Srgb>,
     pub dilate: Image<Srgb>,
     pub local_max: Image<Srgb>,
     pub local_max_points: Vec<(f32, f32, f32)>,
+    /// Measured at each of `local_max_points`.
+    pub stars: Vec<metrics::Star>,
+    pub metrics: metrics::FrameMetrics,
+    /// Every trail detected in the frame.
+    pub trails: Vec<trail::Segment>,
+    /// Trails masked out of detection and integration, all of them until the
+    /// registrations tell those that stay in place in the sky apart.
+    pub masked: Vec<bool>,
 }
 
-pub fn process_image(image: &Image<Srgb>) -> ProcessedImage {
+impl ProcessedImage {
+    /// Offset from `local_max_points` to the raw frame, the LoG shrinks the image by
+    /// its kernel size.
+    pub fn log_offset(&self) -> f32 {
+        (self.raw.width - self.log.width) as f32 / 2.0
+    }
+
+    pub fn masked_trails(&self) -> Vec<trail::Segment> {
+        trail::flagged(&self.trails, &self.masked)
+    }
+}
+
+pub fn process_image(channels: &[Image<f32>; 3]) -> ProcessedImage {
+    let luminance = luminance(channels);
+    let (background, noise) = metrics::background(&luminance);
+    let trails = trail::detect(&luminance, background, noise);
+    let masked = vec![true; trails.len()];
+    detect_stars(channels, trails, masked)
+}
+
+/// Detects and measures the stars of a frame away from its `masked` trails.
+pub fn detect_stars(
+    channels: &[Image<f32>; 3],
+    trails: Vec<trail::Segment>,
+    masked: Vec<bool>,
+) -> ProcessedImage {
     fn f32_to_srgb(image: &Image<f32>) -> Image<Srgb> {
         assert_eq!(image.pixels.len(), image.width * image.height);
         Image {
@@ -60,10 +644,23 @@ pub fn process_image(image: &Image<Srgb>) -> ProcessedImage {
     let dilate_size = (3.0 * sigma).ceil() as usize;
     let luminance_percentile = 0.9999;
 
-    let raw = image.clone();
-    let log_f32: Image<f32> = process::laplacian_of_gaussian(&raw, sigma);
+    let raw = channels_to_srgb(channels);
+    let luminance = luminance(channels);
+    let log_f32: Image<f32> = process::laplacian_of_gaussian(&luminance, sigma);
     let dilate_f32: Image<f32> = process::dilate(&log_f32, dilate_size);
-    let local_max_points = process::peak_local_max(&log_f32, &dilate_f32, luminance_percentile);
+    let (background, noise) = metrics::background(&luminance);
+    let offset = (luminance.width - log_f32.width) as f32 / 2.0;
+    let masked_trails = trail::flagged(&trails, &masked);
+    let trail_mask = trail::mask(&masked_trails, log_f32.width, log_f32.height, offset);
+    let local_max_points = process::peak_local_max(
+        &log_f32,
+        &dilate_f32,
+        luminance_percentile,
+        Some(&trail_mask),
+    );
+
+    let stars = metrics::measure_stars(&luminance, &local_max_points, offset, background, noise);
+    let metrics = metrics::frame_metrics(&stars, background, noise);
 
     let log = f32_to_srgb(&log_f32);
     let dilate = f32_to_srgb(&dilate_f32);
@@ -86,6 +683,10 @@ pub fn process_image(image: &Image<Srgb>) -> ProcessedImage {
         dilate,
         local_max,
         local_max_points,
+        stars,
+        metrics,
+        trails,
+        masked,
     }
 }
 
@@ -96,20 +697,99 @@ pub struct Image<T> {
     pub height: usize,
 }
 
+/// Loads FITS frames with their header, anything else through [`Image::from_path`].
+/// Single plane data fills all three channels.
+pub fn load(path: &str) -> Result<([Image<f32>; 3], fits::Header), String> {
+    let is_fits = path
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| matches!(ext.to_lowercase().as_str(), "fits" | "fit" | "fts"));
+    if !is_fits {
+        let image = Image::from_path(path)?;
+        return Ok((linear_channels(&image), fits::Header::default()));
+    }
+
+    let (header, planes) = fits::read(path).map_err(|err| format!("{path}: {err}"))?;
+    let channels = match planes.as_slice() {
+        [l] => [l.clone(), l.clone(), l.clone()],
+        [r, g, b, ..] => [r.clone(), g.clone(), b.clone()],
+        _ => return Err(format!("{path}: {} planes", planes.len())),
+    };
+    Ok((channels, header))
+}
+
+/// [`load`] with cosmetic correction.
+pub fn load_corrected(
+    path: &str,
+    defects: Option<&Image<bool>>,
+) -> Result<([Image<f32>; 3], fits::Header), String> {
+    let (mut channels, header) = load(path)?;
+    cosmetic::correct_frame(&mut channels, defects, Cfa::from_header(&header));
+    Ok((channels, header))
+}
+
+/// Color filter array of one shot color sensors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Cfa {
+    /// Channel of each pixel in the repeating 2x2 tile, row major.
+    pub pattern: [usize; 4],
+}
+
+impl Cfa {
+    /// Reads the `BAYERPAT` pattern shifted by `XBAYROFF` and `YBAYROFF`.
+    pub fn from_header(header: &fits::Header) -> Option<Self> {
+        let pattern = header.get("BAYERPAT")?.as_str()?;
+        let channel = |c: char| match c {
+            'R' => Some(0),
+            'G' => Some(1),
+            'B' => Some(2),
+            _ => None,
+        };
+        let mut chars = pattern.trim().chars();
+        let mut pattern = [0; 4];
+        for p in pattern.iter_mut() {
+            *p = channel(chars.next()?)?;
+        }
+
+        let offset = |key: &str| {
+            header
+                .get(key)
+                .and_then(fits::Value::as_f64)
+                .map_or(0, |v| v as usize % 2)
+        };
+        let xoffset = offset("XBAYROFF");
+        let yoffset = offset("YBAYROFF");
+        Some(Self {
+            pattern: std::array::from_fn(|i| {
+                let x = (i % 2 + xoffset) % 2;
+                let y = (i / 2 + yoffset) % 2;
+                pattern[y * 2 + x]
+            }),
+        })
+    }
+
+    pub fn channel(&self, x: usize, y: usize) -> usize {
+        self.pattern[(y % 2) * 2 + x % 2]
+    }
+}
+
 impl Image<Srgb> {
-    pub fn from_path(path: &str) -> Self {
+    pub fn from_path(path: &str) -> Result<Self, String> {
         use image::GenericImageView;
         use image::Pixel;
 
-        let bytes = std::fs::read(path).unwrap();
-        let mut image = image::load_from_memory(&bytes).unwrap();
-        image.set_color_space(image::metadata::Cicp::SRGB).unwrap();
+        let bytes = std::fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+        let mut image = image::load_from_memory(&bytes).map_err(|err| format!("{path}: {err}"))?;
+        image
+            .set_color_space(image::metadata::Cicp::SRGB)
+            .map_err(|err| format!("{path}: {err}"))?;
 
         let width = image.width() as usize;
         let height = 3200;
-        assert!(image.height() as usize > 3200);
+        if image.height() as usize <= height {
+            return Err(format!("{path}: expected more than {height} rows"));
+        }
 
-        Self {
+        Ok(Self {
             pixels: image
                 .pixels()
                 .take(width * height)
@@ -120,7 +800,69 @@ impl Image<Srgb> {
                 .collect::<Vec<_>>(),
             width,
             height,
+        })
+    }
+}
+
+impl Image<f32> {
+    /// `None` outside of the image.
+    pub fn sample_bilinear(&self, x: f32, y: f32) -> Option<f32> {
+        if x < 0.0 || y < 0.0 || x > (self.width - 1) as f32 || y > (self.height - 1) as f32 {
+            return None;
         }
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let fx = x - x0 as f32;
+        let fy = y - y0 as f32;
+        let p = |x: usize, y: usize| self.pixels[y * self.width + x];
+        let top = p(x0, y0) * (1.0 - fx) + p(x1, y0) * fx;
+        let bottom = p(x0, y1) * (1.0 - fx) + p(x1, y1) * fx;
+        Some(top * (1.0 - fy) + bottom * fy)
+    }
+}
+
+/// Linear channels, the working representation of stacked data.
+pub fn linear_channels(image: &Image<Srgb>) -> [Image<f32>; 3] {
+    assert_eq!(image.pixels.len(), image.width * image.height);
+    let channel = |f: fn(&LinearRgb) -> f32| Image {
+        pixels: image.pixels.iter().map(|p| f(&p.to_linear())).collect(),
+        width: image.width,
+        height: image.height,
+    };
+    [channel(|c| c.r()), channel(|c| c.g()), channel(|c| c.b())]
+}
+
+/// Mean of the channels, what detection and photometry measure.
+pub fn luminance(channels: &[Image<f32>; 3]) -> Image<f32> {
+    let [r, g, b] = channels;
+    Image {
+        pixels: (0..r.pixels.len())
+            .map(|i| (r.pixels[i] + g.pixels[i] + b.pixels[i]) / 3.0)
+            .collect(),
+        width: r.width,
+        height: r.height,
+    }
+}
+
+pub fn channels_to_srgb(channels: &[Image<f32>; 3]) -> Image<Srgb> {
+    let [r, g, b] = channels;
+    assert_eq!(r.pixels.len(), g.pixels.len());
+    assert_eq!(r.pixels.len(), b.pixels.len());
+    Image {
+        pixels: r
+            .pixels
+            .iter()
+            .zip(g.pixels.iter())
+            .zip(b.pixels.iter())
+            .map(|((r, g), b)| {
+                LinearRgb::from_rgb(r.clamp(0.0, 1.0), g.clamp(0.0, 1.0), b.clamp(0.0, 1.0))
+                    .to_srgb()
+            })
+            .collect(),
+        width: r.width,
+        height: r.height,
     }
 }
 
@@ -156,3 +898,55 @@ impl FromLuminance for Srgb {
         LinearRgb::from_rgb(luminance, luminance, luminance).to_srgb()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("spack-{}-{name}", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_keeps_float_data() {
+        let plane = Image {
+            pixels: vec![1e-4, 2e-4, 0.5, 1.0],
+            width: 2,
+            height: 2,
+        };
+        let path = temp_path("mono.fits");
+        fits::write(
+            &path,
+            std::slice::from_ref(&plane),
+            &fits::Header::default(),
+        )
+        .unwrap();
+        let (channels, _) = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for channel in channels.iter() {
+            assert_eq!(channel.pixels, plane.pixels);
+        }
+    }
+
+    #[test]
+    fn load_errors() {
+        let plane = Image {
+            pixels: vec![0.5; 4],
+            width: 2,
+            height: 2,
+        };
+        let path = temp_path("planes.fits");
+        fits::write(&path, &[plane.clone(), plane], &fits::Header::default()).unwrap();
+        assert!(load(&path).is_err());
+        std::fs::write(&path, b"SIMPLE  =").unwrap();
+        assert!(load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_path("partial.png");
+        std::fs::write(&path, b"\x89PNG\r\n").unwrap();
+        assert!(load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load(&temp_path("missing.png")).is_err());
+    }
+}
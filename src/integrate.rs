@@ -0,0 +1,283 @@
+use crate::align::Transform;
+use crate::fits;
+use crate::image::{self, Image, ImageMemory};
+use crate::wcs::Wcs;
+use crate::{grade, normalize, trail, weight};
+use tint::Srgb;
+
+/// Sigma clipping around the median of each pixel stack.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Rejection {
+    pub low: f32,
+    pub high: f32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Integration {
+    pub channels: [Image<f32>; 3],
+    pub preview: Image<Srgb>,
+    /// Summed weight of each output pixel per channel, for integrators that spread
+    /// input pixels.
+    pub weight_maps: Vec<Image<f32>>,
+    /// `(frame, weight)` of every integrated frame.
+    pub weights: Vec<(usize, f32)>,
+    pub header: fits::Header,
+    /// Written into `header` with the stack.
+    pub wcs: Option<Wcs>,
+    /// Size of the stack over that of the reference frame, whose stars the stack
+    /// is processed with. `None` when the stack is not in its geometry.
+    pub star_scale: Option<f32>,
+}
+
+/// Stacks every accepted frame in the geometry of the reference frame.
+pub fn integrate(images: &ImageMemory) -> Result<Integration, String> {
+    let weights = frame_weights(images)?;
+    let reference = &images.raw[images.reference()][0];
+    let reference_estimates = reference_estimates(images);
+    let warped: Vec<_> = weights
+        .iter()
+        .map(|(frame, _)| {
+            let (channels, transform) = prepare_frame(images, *frame, &reference_estimates);
+            warp(&channels, &transform, reference.width, reference.height)
+        })
+        .collect();
+
+    let frame_weights: Vec<_> = weights.iter().map(|(_, w)| *w).collect();
+    let channels = std::array::from_fn(|c| {
+        let layers: Vec<_> = warped.iter().map(|w| &w[c]).collect();
+        combine(&layers, &frame_weights, images.rejection)
+    });
+
+    let mut header = header(images, &images.paths, &weights);
+    if let Some(rejection) = images.rejection {
+        header.history(&format!(
+            "sigma clipping low {} high {}",
+            rejection.low, rejection.high
+        ));
+    }
+
+    Ok(Integration {
+        preview: image::channels_to_srgb(&channels),
+        channels,
+        weight_maps: Vec::new(),
+        weights,
+        header,
+        wcs: reference_wcs(images, 1.0),
+        star_scale: Some(1.0),
+    })
+}
+
+/// Solution of the reference frame for an output `scale` times its size.
+pub fn reference_wcs(images: &ImageMemory, scale: f32) -> Option<Wcs> {
+    let wcs = images.solutions.get(&images.reference())?;
+    Some(wcs.scaled(scale as f64))
+}
+
+/// `(frame, weight)` of every accepted frame that contributes to the stack.
+pub fn frame_weights(images: &ImageMemory) -> Result<Vec<(usize, f32)>, String> {
+    accepted_weights(&images.grades, &images.weighting)
+}
+
+/// `(frame, weight)` of every frame `grades` accepts with a positive weight.
+pub fn accepted_weights(
+    grades: &grade::Report,
+    weighting: &weight::Weighting,
+) -> Result<Vec<(usize, f32)>, String> {
+    let accepted: Vec<_> = grades.grades.iter().filter(|g| !g.rejected()).collect();
+    if accepted.is_empty() {
+        return Err("every frame was rejected".to_string());
+    }
+    Ok(accepted
+        .iter()
+        .map(|g| g.frame)
+        .zip(weight::weights(&accepted, weighting)?)
+        .filter(|(_, w)| *w > 0.0)
+        .collect())
+}
+
+pub fn reference_estimates(images: &ImageMemory) -> [normalize::Estimate; 3] {
+    images.raw[images.reference()]
+        .each_ref()
+        .map(normalize::estimate)
+}
+
+/// Linear channels of `frame` normalized onto the reference, and the transform from
+/// its pixels into the reference frame.
+pub fn prepare_frame(
+    images: &ImageMemory,
+    frame: usize,
+    reference_estimates: &[normalize::Estimate; 3],
+) -> ([Image<f32>; 3], Transform) {
+    let offset = images.processed[&frame].log_offset();
+    let transform = images.registrations[&frame].transform.offset(offset);
+    let mut channels = images.raw[frame].clone();
+    normalize_frame(
+        &mut channels,
+        &images.processed[&frame].masked_trails(),
+        reference_estimates,
+        images.normalization,
+    );
+    (channels, transform)
+}
+
+/// Masks `trails` and normalizes every channel onto the reference.
+pub fn normalize_frame(
+    channels: &mut [Image<f32>; 3],
+    trails: &[trail::Segment],
+    reference_estimates: &[normalize::Estimate; 3],
+    normalization: normalize::Normalization,
+) {
+    mask_trails(channels, trails);
+    for (channel, reference) in channels.iter_mut().zip(reference_estimates) {
+        let estimate = normalize::estimate(channel);
+        normalize::normalize(channel, estimate, *reference, normalization);
+    }
+}
+
+/// Sets pixels covered by trails to NaN so that they never reach the stack.
+pub fn mask_trails(channels: &mut [Image<f32>; 3], trails: &[trail::Segment]) {
+    if trails.is_empty() {
+        return;
+    }
+    let mask = trail::mask(trails, channels[0].width, channels[0].height, 0.0);
+    for channel in channels.iter_mut() {
+        for (v, masked) in channel.pixels.iter_mut().zip(mask.pixels.iter()) {
+            if *masked {
+                *v = f32::NAN;
+            }
+        }
+    }
+}
+
+/// Cards shared by every integration method, `weights` indexing `paths`.
+pub fn header(images: &ImageMemory, paths: &[String], weights: &[(usize, f32)]) -> fits::Header {
+    let mut header = fits::Header::default();
+    header.push(
+        "NCOMBINE",
+        fits::Value::Integer(weights.len() as i64),
+        "number of integrated frames",
+    );
+    header.push(
+        "WEIGHTS",
+        fits::Value::String(images.weighting.to_string()),
+        "frame weighting",
+    );
+    header.push(
+        "NORMALIZ",
+        fits::Value::String(images.normalization.to_string()),
+        "frame normalization",
+    );
+    // one line per frame, keywords would run out past 9999 frames
+    for (frame, weight) in weights.iter() {
+        header.history(&format!("weight {weight:.6} {}", paths[*frame]));
+    }
+    header
+}
+
+/// Resamples `channels` into a `width` by `height` frame through `transform`,
+/// pixels outside of the source are NaN.
+pub fn warp(
+    channels: &[Image<f32>; 3],
+    transform: &Transform,
+    width: usize,
+    height: usize,
+) -> [Image<f32>; 3] {
+    warp_rows(channels, 0, transform, width, 0, height)
+}
+
+/// [`warp`] for output rows `y0..y1`, from `channels` holding the source rows
+/// starting at `source_y0`.
+pub fn warp_rows(
+    channels: &[Image<f32>; 3],
+    source_y0: usize,
+    transform: &Transform,
+    width: usize,
+    y0: usize,
+    y1: usize,
+) -> [Image<f32>; 3] {
+    let height = y1 - y0;
+    let mut output = std::array::from_fn(|_| Image {
+        pixels: vec![f32::NAN; width * height],
+        width,
+        height,
+    });
+    for y in y0..y1 {
+        for x in 0..width {
+            let (sx, sy) = transform.invert(x as f32, y as f32);
+            for (channel, output) in channels.iter().zip(output.iter_mut()) {
+                if let Some(v) = channel.sample_bilinear(sx, sy - source_y0 as f32) {
+                    output.pixels[(y - y0) * width + x] = v;
+                }
+            }
+        }
+    }
+    output
+}
+
+/// Weighted mean of each pixel stack, ignoring NaN.
+pub fn combine(
+    layers: &[&Image<f32>],
+    weights: &[f32],
+    rejection: Option<Rejection>,
+) -> Image<f32> {
+    assert_eq!(layers.len(), weights.len());
+    assert!(!layers.is_empty());
+    let width = layers[0].width;
+    let height = layers[0].height;
+
+    let mut output = Image {
+        pixels: vec![0.0; width * height],
+        width,
+        height,
+    };
+    let mut stack = Vec::with_capacity(layers.len());
+    for (i, pixel) in output.pixels.iter_mut().enumerate() {
+        stack.clear();
+        stack.extend(
+            layers
+                .iter()
+                .zip(weights.iter())
+                .map(|(layer, w)| (layer.pixels[i], *w))
+                .filter(|(v, _)| !v.is_nan()),
+        );
+        if let Some(rejection) = rejection {
+            sigma_clip(&mut stack, rejection);
+        }
+        *pixel = weighted_mean(&stack);
+    }
+    output
+}
+
+fn weighted_mean(stack: &[(f32, f32)]) -> f32 {
+    let weight: f32 = stack.iter().map(|(_, w)| w).sum();
+    if weight > 0.0 {
+        stack.iter().map(|(v, w)| v * w).sum::<f32>() / weight
+    } else {
+        0.0
+    }
+}
+
+// https://en.wikipedia.org/wiki/Sigma_clipping
+fn sigma_clip(stack: &mut Vec<(f32, f32)>, rejection: Rejection) {
+    // too few samples to estimate a spread
+    let min_samples = 3;
+    let max_iterations = 5;
+    for _ in 0..max_iterations {
+        if stack.len() < min_samples {
+            return;
+        }
+        let mut values: Vec<_> = stack.iter().map(|(v, _)| *v).collect();
+        let median = crate::stats::median(&mut values);
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let sigma = (values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>()
+            / (values.len() - 1) as f32)
+            .sqrt();
+        let len = stack.len();
+        stack.retain(|(v, _)| {
+            *v >= median - rejection.low * sigma && *v <= median + rejection.high * sigma
+        });
+        if stack.len() == len {
+            return;
+        }
+    }
+}
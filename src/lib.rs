@@ -1,22 +1,84 @@
 // TODO: Hand rolled png decoder: https://github.com/madler/zlib/blob/master/contrib/puff/puff.c
 
-use crate::image::ImageMemory;
+use crate::image::{Image, ImageMemory};
 use tint::Srgb;
 
 mod align;
+mod background;
+mod catalog;
+mod color;
+mod comet;
+mod cosmetic;
+mod deconvolve;
+mod denoise;
+mod drizzle;
+mod export;
+mod fft;
+mod fits;
+mod grade;
 mod image;
+mod integrate;
+mod linalg;
+mod live;
+mod metrics;
+mod mosaic;
+mod normalize;
+mod photometry;
 mod process;
 mod render;
+mod settings;
+mod solve;
+mod starless;
+mod starmask;
+mod stats;
+mod stream;
+mod stretch;
+mod trail;
+mod wcs;
+mod weight;
 
 pub const WIDTH: usize = 900;
 pub const HEIGHT: usize = 900;
 
+const DATA_DIR: &str = "data";
+const DARK_DIR: &str = "data/darks";
+/// Stacked panels of a mosaic, see [`mosaic::stitch`].
+const PANEL_DIR: &str = "panels";
+const OUTPUT_DIR: &str = "output";
+/// See [`settings::Settings`], applied whenever the file changes.
+const SETTINGS: &str = "settings.txt";
+/// Local subset of a star catalog covering the field, see [`catalog::read`].
+const CATALOG: &str = "catalog.csv";
+/// Plate solving index, built from [`CATALOG`] when missing.
+const INDEX: &str = "index.bin";
+/// Tile radius of a built index in degrees, at least the radius of the field.
+const INDEX_RADIUS: f64 = 1.0;
+const INDEX_STARS: usize = 30;
+/// Budget for bands of frames held by [`stream::integrate`].
+const STREAM_MEMORY: usize = 512 * 1024 * 1024;
+
+const ALIGN_THRESHOLD: f32 = 0.0015;
+
 #[derive(bincode::Encode, bincode::Decode)]
 pub struct Memory {
     #[bincode(with_serde)]
     images: ImageMemory,
     #[bincode(with_serde)]
     view: View,
+    #[bincode(with_serde)]
+    live: Option<live::LiveStack>,
+    /// Last cursor position in the window.
+    cursor: (f32, f32),
+    /// Display only, see [`stretch::Stf`].
+    auto_stretch: bool,
+    linked_stretch: bool,
+    #[bincode(with_serde)]
+    stf_cache: render::StfCache,
+    /// Ra and dec lines over solved images, with the coordinates under the cursor.
+    grid: bool,
+    /// Modification time of the settings last applied.
+    #[bincode(with_serde)]
+    settings: Option<std::time::SystemTime>,
     #[allow(unused)]
     alpha: f32,
 }
@@ -28,6 +90,10 @@ enum View {
     Dilate,
     LocalMax,
     AlignTriangles,
+    Stack,
+    Background,
+    StarMask,
+    Stars,
 }
 
 impl Default for Memory {
@@ -35,6 +101,13 @@ impl Default for Memory {
         Self {
             images: ImageMemory::default(),
             view: View::Raw,
+            live: None,
+            cursor: (0.0, 0.0),
+            auto_stretch: true,
+            linked_stretch: true,
+            stf_cache: Default::default(),
+            grid: false,
+            settings: None,
             alpha: 1.0,
         }
     }
@@ -49,16 +122,100 @@ impl Memory {
             self.images.selected_image - 1
         }
     }
+
+    /// Astrometric solution of the displayed image and the image it maps.
+    fn view_wcs(&self) -> Option<(&wcs::Wcs, &Image<Srgb>)> {
+        let key = self.images.selected_image;
+        match (self.view, &self.images.stack) {
+            (View::Stack | View::Background | View::StarMask | View::Stars, Some(stack)) => {
+                Some((stack.wcs.as_ref()?, &stack.preview))
+            }
+            // detection views are offset from the frame
+            (View::LoG | View::Dilate | View::LocalMax, _) => None,
+            _ => Some((
+                self.images.solutions.get(&key)?,
+                &self.images.processed.get(&key)?.raw,
+            )),
+        }
+    }
+
+    fn finish_stack(&mut self, name: &str, stack: Result<integrate::Integration, String>) {
+        let mut stack = match stack {
+            Ok(stack) => stack,
+            Err(err) => {
+                println!("failed to integrate: {err}");
+                return;
+            }
+        };
+        if let Some(wcs) = &stack.wcs {
+            wcs.write_header(&mut stack.header);
+        }
+        std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+        let path = format!("{OUTPUT_DIR}/{name}.fits");
+        fits::write(&path, &stack.channels, &stack.header).unwrap();
+        if !stack.weight_maps.is_empty() {
+            let path = format!("{OUTPUT_DIR}/{name}_weights.fits");
+            fits::write(&path, &stack.weight_maps, &stack.header).unwrap();
+        }
+        println!("integrated {} frames into {path}", stack.weights.len());
+        self.images.set_stack(stack);
+        self.view = View::Stack;
+    }
+
+    fn reload_settings(&mut self) {
+        let modified = std::fs::metadata(SETTINGS)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_none() || modified == self.settings {
+            return;
+        }
+        self.settings = modified;
+        match settings::Settings::read(SETTINGS) {
+            Ok(settings) => {
+                settings.apply(&mut self.images);
+                self.stf_cache.clear();
+                println!("applied {SETTINGS}");
+            }
+            Err(err) => println!("failed to read settings: {err}"),
+        }
+    }
+
+    fn print_distortion_orders(&self) {
+        let selected = self.images.selected_image;
+        let orders = match self.images.distortion_orders(selected) {
+            Ok(orders) => orders,
+            Err(err) => {
+                println!("{err}");
+                return;
+            }
+        };
+        for (order, residuals) in orders {
+            let current = if order == self.images.distortion_order {
+                "*"
+            } else {
+                " "
+            };
+            println!(
+                "{current} order {order}: rms {:.3}px, median {:.3}px, max {:.3}px ({} stars)",
+                residuals.rms, residuals.median, residuals.max, residuals.count
+            );
+        }
+    }
 }
 
 #[unsafe(no_mangle)]
 pub fn handle_input(glazer::PlatformInput { memory, input }: glazer::PlatformInput<Memory>) {
+    if let glazer::Input::CursorMoved { x, y } = input {
+        memory.cursor = (x, y);
+    }
     if let glazer::Input::Key {
         code,
         pressed: true,
         ..
     } = input
     {
+        // any key may change the images on screen
+        memory.stf_cache.clear();
         match code {
             glazer::KeyCode::LeftArrow => {
                 memory.images.selected_image =
@@ -67,6 +224,12 @@ pub fn handle_input(glazer::PlatformInput { memory, input }: glazer::PlatformInp
             glazer::KeyCode::RightArrow => {
                 memory.images.selected_image = memory.next_image_index();
             }
+            glazer::KeyCode::Num0 => match export::write(OUTPUT_DIR, &memory.images) {
+                Ok((detections, correspondences)) => println!(
+                    "exported {detections} detections and {correspondences} correspondences to {OUTPUT_DIR}"
+                ),
+                Err(err) => println!("failed to export detections: {err}"),
+            },
             glazer::KeyCode::Num1 => {
                 memory.view = View::Raw;
             }
@@ -82,6 +245,257 @@ pub fn handle_input(glazer::PlatformInput { memory, input }: glazer::PlatformInp
             glazer::KeyCode::Num5 => {
                 memory.view = View::AlignTriangles;
             }
+            glazer::KeyCode::Num6 => {
+                memory.view = View::Stack;
+            }
+            glazer::KeyCode::Num7 => {
+                memory.view = View::Background;
+            }
+            glazer::KeyCode::Num8 => {
+                memory.view = View::StarMask;
+            }
+            glazer::KeyCode::Num9 => {
+                memory.view = View::Stars;
+            }
+            glazer::KeyCode::UpArrow => {
+                let order = memory.images.distortion_order + 1;
+                memory.images.set_distortion_order(order);
+                memory.print_distortion_orders();
+            }
+            glazer::KeyCode::DownArrow => {
+                let order = memory.images.distortion_order.saturating_sub(1);
+                memory.images.set_distortion_order(order);
+                memory.print_distortion_orders();
+            }
+            glazer::KeyCode::R => {
+                // toggle between the selected image and automatic selection
+                let selected = memory.images.selected_image;
+                let reference = if memory.images.reference_override == Some(selected) {
+                    None
+                } else {
+                    Some(selected)
+                };
+                memory.images.set_reference_override(reference);
+            }
+            glazer::KeyCode::G => {
+                print!("{}", memory.images.grades);
+            }
+            glazer::KeyCode::W => {
+                memory.images.weighting = match memory.images.weighting {
+                    weight::Weighting::Equal => weight::Weighting::InverseVariance,
+                    weight::Weighting::InverseVariance => weight::Weighting::Snr,
+                    weight::Weighting::Snr => weight::Weighting::PsfSignal,
+                    weight::Weighting::PsfSignal | weight::Weighting::Expression(_) => {
+                        weight::Weighting::Equal
+                    }
+                };
+                println!("weighting: {}", memory.images.weighting);
+            }
+            glazer::KeyCode::N => {
+                memory.images.normalization = match memory.images.normalization {
+                    normalize::Normalization::None => normalize::Normalization::Additive,
+                    normalize::Normalization::Additive => normalize::Normalization::Multiplicative,
+                    normalize::Normalization::Multiplicative => {
+                        normalize::Normalization::AdditiveScaling
+                    }
+                    normalize::Normalization::AdditiveScaling => normalize::Normalization::None,
+                };
+                println!("normalization: {}", memory.images.normalization);
+            }
+            glazer::KeyCode::I => {
+                let stack = integrate::integrate(&memory.images);
+                memory.finish_stack("stack", stack);
+            }
+            glazer::KeyCode::S => {
+                let stack =
+                    stream::integrate(&memory.images, &image::frame_paths(DATA_DIR), STREAM_MEMORY);
+                memory.finish_stack("stack", stack);
+            }
+            glazer::KeyCode::L => {
+                memory.live = match memory.live {
+                    Some(_) => {
+                        println!("stopped live stacking");
+                        None
+                    }
+                    None => {
+                        println!("live stacking frames from {DATA_DIR}");
+                        memory.view = View::Stack;
+                        Some(live::LiveStack::new(DATA_DIR, &memory.images))
+                    }
+                };
+            }
+            glazer::KeyCode::A => {
+                memory.auto_stretch = !memory.auto_stretch;
+                println!("auto stretch: {}", memory.auto_stretch);
+            }
+            glazer::KeyCode::K => {
+                memory.linked_stretch = !memory.linked_stretch;
+                println!("linked stretch: {}", memory.linked_stretch);
+            }
+            glazer::KeyCode::Y => {
+                memory.images.stretch = match memory.images.stretch {
+                    stretch::Stretch::Histogram { .. } => stretch::Stretch::Arcsinh {
+                        black_point: 0.0,
+                        factor: 100.0,
+                    },
+                    stretch::Stretch::Arcsinh { .. } => stretch::Stretch::Ghs {
+                        stretch: 5.0,
+                        local: 0.0,
+                        symmetry: 0.0,
+                        shadows: 0.0,
+                        highlights: 1.0,
+                    },
+                    stretch::Stretch::Ghs { .. } => stretch::Stretch::Histogram {
+                        shadows: 0.0,
+                        midtones: 0.1,
+                        highlights: 1.0,
+                    },
+                };
+                println!("stretch: {}", memory.images.stretch);
+            }
+            glazer::KeyCode::T => match memory.images.stretch_stack() {
+                Ok(()) => {
+                    let stack = memory.images.stack.as_ref().unwrap();
+                    std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+                    let path = format!("{OUTPUT_DIR}/stretched.fits");
+                    fits::write(&path, &stack.channels, &stack.header).unwrap();
+                    println!("{} into {path}", memory.images.stretch);
+                    // the data is no longer linear
+                    memory.auto_stretch = false;
+                    memory.view = View::Stack;
+                }
+                Err(err) => println!("failed to stretch: {err}"),
+            },
+            glazer::KeyCode::C => match memory.images.calibrate_color(CATALOG) {
+                Ok(()) => memory.view = View::Stack,
+                Err(err) => println!("failed to calibrate color: {err}"),
+            },
+            glazer::KeyCode::X => match memory.images.deconvolve_stack() {
+                Ok(()) => memory.view = View::Stack,
+                Err(err) => println!("failed to deconvolve: {err}"),
+            },
+            glazer::KeyCode::Z => match memory.images.denoise_stack() {
+                Ok(()) => memory.view = View::Stack,
+                Err(err) => println!("failed to reduce noise: {err}"),
+            },
+            glazer::KeyCode::M => match memory.images.reduce_stars() {
+                Ok(()) => memory.view = View::Stack,
+                Err(err) => println!("failed to reduce stars: {err}"),
+            },
+            glazer::KeyCode::P => match memory.images.remove_stars() {
+                Ok(()) => {
+                    let stack = memory.images.stack.as_ref().unwrap();
+                    let stars = memory.images.stars.as_ref().unwrap();
+                    std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+                    let path = format!("{OUTPUT_DIR}/starless.fits");
+                    fits::write(&path, &stack.channels, &stack.header).unwrap();
+                    let path = format!("{OUTPUT_DIR}/stars.fits");
+                    fits::write(&path, &stars.channels, &stack.header).unwrap();
+                    println!("separated stars into {OUTPUT_DIR}");
+                    memory.view = View::Stack;
+                }
+                Err(err) => println!("failed to remove stars: {err}"),
+            },
+            glazer::KeyCode::U => match memory.images.restore_stars() {
+                Ok(()) => memory.view = View::Stack,
+                Err(err) => println!("failed to restore stars: {err}"),
+            },
+            glazer::KeyCode::O => {
+                // marks the moving object under the cursor
+                let frame = memory.images.selected_image;
+                let (x, y) = render::screen_to_image(
+                    WIDTH,
+                    HEIGHT,
+                    &memory.images.processed[&frame].raw,
+                    memory.cursor.0,
+                    memory.cursor.1,
+                );
+                memory.images.comet.mark(frame, x, y);
+                println!("marked the moving object at ({x:.1}, {y:.1}) in frame {frame}");
+            }
+            glazer::KeyCode::J => match comet::integrate(&memory.images, &memory.images.comet) {
+                Ok((stars, object)) => {
+                    memory.finish_stack("stack", Ok(stars));
+                    memory.finish_stack("comet", Ok(object));
+                }
+                Err(err) => println!("failed to integrate the moving object: {err}"),
+            },
+            glazer::KeyCode::V => {
+                let stack =
+                    mosaic::stitch(PANEL_DIR, memory.images.mosaic).map(|(stack, overlaps)| {
+                        for overlap in overlaps.iter() {
+                            println!(
+                                "{} overlaps {} with {} stars",
+                                overlap.a, overlap.b, overlap.stars
+                            );
+                        }
+                        stack
+                    });
+                memory.finish_stack("mosaic", stack);
+            }
+            glazer::KeyCode::F => match memory.images.plate_solve(INDEX, CATALOG) {
+                Ok(solved) => println!("solved {solved} of {} frames", memory.images.raw.len()),
+                Err(err) => println!("failed to plate solve: {err}"),
+            },
+            glazer::KeyCode::E => {
+                // marks the star under the cursor, the first is the target
+                let frame = memory.images.selected_image;
+                let (x, y) = render::screen_to_image(
+                    WIDTH,
+                    HEIGHT,
+                    &memory.images.processed[&frame].raw,
+                    memory.cursor.0,
+                    memory.cursor.1,
+                );
+                match memory.images.mark_photometry(frame, x, y) {
+                    Ok(true) => println!("marked {} stars", memory.images.photometry.stars.len()),
+                    Ok(false) => println!("unmarked the star"),
+                    Err(err) => println!("failed to mark: {err}"),
+                }
+                if memory.images.photometry.stars.len() >= 2 {
+                    std::fs::create_dir_all(OUTPUT_DIR).unwrap();
+                    let path = format!("{OUTPUT_DIR}/lightcurve.csv");
+                    let written =
+                        photometry::light_curve(&memory.images, &memory.images.photometry)
+                            .and_then(|points| {
+                                photometry::write_csv(&path, &memory.images, &points)?;
+                                Ok(points.len())
+                            });
+                    match written {
+                        Ok(count) => println!("wrote {count} points to {path}"),
+                        Err(err) => println!("failed to measure the light curve: {err}"),
+                    }
+                }
+            }
+            glazer::KeyCode::H => {
+                memory.grid = !memory.grid;
+                println!("coordinate grid: {}", memory.grid);
+            }
+            glazer::KeyCode::Q => match memory.view_wcs() {
+                Some((wcs, image)) => {
+                    let (x, y) = render::screen_to_image(
+                        WIDTH,
+                        HEIGHT,
+                        image,
+                        memory.cursor.0,
+                        memory.cursor.1,
+                    );
+                    let (ra, dec) = wcs.pixel_to_sky(x as f64, y as f64);
+                    println!(
+                        "({x:.1}, {y:.1}): {} (ra {ra:.5} dec {dec:.5})",
+                        wcs::sexagesimal(ra, dec)
+                    );
+                }
+                None => println!("the displayed image is not plate solved"),
+            },
+            glazer::KeyCode::B => match memory.images.extract_background() {
+                Ok(()) => memory.view = View::Background,
+                Err(err) => println!("failed to extract background: {err}"),
+            },
+            glazer::KeyCode::D => {
+                let stack = drizzle::drizzle(&memory.images, memory.images.drizzle);
+                memory.finish_stack("drizzle", stack);
+            }
             _ => {}
         }
     }
@@ -97,5 +511,12 @@ pub fn update_and_render(
         ..
     }: glazer::PlatformUpdate<Memory, Srgb>,
 ) {
+    memory.reload_settings();
+    if let Some(live) = &mut memory.live
+        && live.poll(&mut memory.images)
+    {
+        memory.view = View::Stack;
+        memory.stf_cache.clear();
+    }
     render::render(frame_buffer, width, height, memory);
 }
@@ -0,0 +1,56 @@
+// https://en.wikipedia.org/wiki/Linear_least_squares#Main_formulations
+pub fn least_squares(rows: &[Vec<f64>], rhs: &[f64]) -> Option<Vec<f64>> {
+    assert_eq!(rows.len(), rhs.len());
+    let n = rows.first()?.len();
+    if rows.len() < n {
+        return None;
+    }
+
+    // normal equations: (A^T A) x = A^T b
+    let mut ata = vec![0.0; n * n];
+    let mut atb = vec![0.0; n];
+    for (row, b) in rows.iter().zip(rhs.iter()) {
+        assert_eq!(row.len(), n);
+        for (i, ri) in row.iter().enumerate() {
+            atb[i] += ri * b;
+            for (j, rj) in row.iter().enumerate() {
+                ata[i * n + j] += ri * rj;
+            }
+        }
+    }
+    solve(ata, atb)
+}
+
+// https://en.wikipedia.org/wiki/Gaussian_elimination
+pub fn solve(mut a: Vec<f64>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    assert_eq!(a.len(), n * n);
+
+    for k in 0..n {
+        // partial pivoting
+        let pivot = (k..n).max_by(|i, j| a[i * n + k].abs().total_cmp(&a[j * n + k].abs()))?;
+        if a[pivot * n + k].abs() < 1e-12 {
+            return None;
+        }
+        if pivot != k {
+            for j in 0..n {
+                a.swap(k * n + j, pivot * n + j);
+            }
+            b.swap(k, pivot);
+        }
+        for i in k + 1..n {
+            let f = a[i * n + k] / a[k * n + k];
+            for j in k..n {
+                a[i * n + j] -= f * a[k * n + j];
+            }
+            b[i] -= f * b[k];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        let sum: f64 = (i + 1..n).map(|j| a[i * n + j] * x[j]).sum();
+        x[i] = (b[i] - sum) / a[i * n + i];
+    }
+    Some(x)
+}
@@ -0,0 +1,412 @@
+use crate::image::{self, Image, ImageMemory, NewFrame};
+use crate::integrate::{self, Integration};
+use crate::{align, normalize, trail, weight};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+
+/// Watches a directory for new frames and folds each one into a running
+/// integration as it arrives. Frames are read and warped on a worker thread, and
+/// the sums follow the grades as every new frame changes them. When what a stacked
+/// frame was warped with changes, the sums are rebuilt from every frame.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct LiveStack {
+    pub directory: String,
+    seen: HashSet<String>,
+    /// Size of new files at the last poll, a frame is only read once its size
+    /// stops changing so that partially written files are skipped.
+    pending: HashMap<String, u64>,
+    /// Failed reads of each file, given up after [`MAX_ATTEMPTS`].
+    attempts: HashMap<String, usize>,
+    #[serde(skip)]
+    last_poll: Option<std::time::Instant>,
+    #[serde(skip)]
+    jobs: Option<Jobs>,
+    /// Files being read and frames being warped.
+    #[serde(skip)]
+    reading: HashSet<String>,
+    #[serde(skip)]
+    warping: HashSet<usize>,
+    /// Normalization target of the reference frame it was measured on.
+    reference_estimates: Option<(usize, [normalize::Estimate; 3])>,
+    /// Weighted sums and total weight of each pixel.
+    sums: Option<[Image<f32>; 3]>,
+    total_weight: Option<Image<f32>>,
+    /// Weight and inputs each stacked frame was added with, to take it out again.
+    stacked: HashMap<usize, (f32, Inputs)>,
+}
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_ATTEMPTS: usize = 3;
+/// Jobs queued or running at once, each holds a whole frame.
+const MAX_JOBS: usize = 2;
+
+/// Everything the warped pixels of a frame depend on besides the frame itself.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Inputs {
+    reference: usize,
+    reference_estimates: [normalize::Estimate; 3],
+    normalization: normalize::Normalization,
+    transform: align::Transform,
+    trails: Vec<trail::Segment>,
+}
+
+/// A single worker thread, so that a burst of new files is read one at a time.
+struct Jobs {
+    sender: mpsc::Sender<Job>,
+    receiver: mpsc::Receiver<Done>,
+}
+
+enum Job {
+    Read {
+        path: String,
+        defects: Option<Image<bool>>,
+        reference: Vec<(f32, f32, f32)>,
+        order: usize,
+    },
+    Warp(Box<Warp>),
+}
+
+enum Done {
+    Read {
+        path: String,
+        frame: Result<Box<NewFrame>, String>,
+    },
+    Warped {
+        frame: usize,
+        inputs: Box<Inputs>,
+        warped: [Image<f32>; 3],
+    },
+}
+
+/// What warping a frame into the reference needs, owned to move to the worker.
+struct Warp {
+    frame: usize,
+    channels: [Image<f32>; 3],
+    inputs: Inputs,
+    width: usize,
+    height: usize,
+}
+
+impl Job {
+    fn run(self) -> Done {
+        match self {
+            Self::Read {
+                path,
+                defects,
+                reference,
+                order,
+            } => {
+                let frame =
+                    NewFrame::read(&path, defects.as_ref(), &reference, order).map(Box::new);
+                Done::Read { path, frame }
+            }
+            Self::Warp(warp) => {
+                let Warp {
+                    frame,
+                    mut channels,
+                    inputs,
+                    width,
+                    height,
+                } = *warp;
+                integrate::normalize_frame(
+                    &mut channels,
+                    &inputs.trails,
+                    &inputs.reference_estimates,
+                    inputs.normalization,
+                );
+                let warped = integrate::warp(&channels, &inputs.transform, width, height);
+                Done::Warped {
+                    frame,
+                    inputs: Box::new(inputs),
+                    warped,
+                }
+            }
+        }
+    }
+}
+
+impl LiveStack {
+    /// Starts from every accepted frame already in `images`.
+    pub fn new(directory: &str, images: &ImageMemory) -> Self {
+        let mut live = Self {
+            directory: directory.to_string(),
+            seen: images.paths.iter().cloned().collect(),
+            ..Default::default()
+        };
+        live.reconcile(images);
+        live
+    }
+
+    /// Returns true if the stack changed.
+    pub fn poll(&mut self, images: &mut ImageMemory) -> bool {
+        let mut changed = false;
+        let mut finished = false;
+        while let Ok(done) = self.jobs().receiver.try_recv() {
+            finished = true;
+            match done {
+                Done::Read { path, frame } => {
+                    self.reading.remove(&path);
+                    match frame {
+                        Ok(frame) => {
+                            self.seen.insert(path);
+                            images.add_frame(*frame);
+                        }
+                        Err(err) => self.failed(path, err),
+                    }
+                }
+                Done::Warped {
+                    frame,
+                    inputs,
+                    warped,
+                } => {
+                    self.warping.remove(&frame);
+                    // warped with what has changed since, it is warped again
+                    if Self::inputs(images, frame, self.reference_estimates)
+                        .is_some_and(|current| current == *inputs)
+                    {
+                        changed |= self.apply(images, frame, *inputs, &warped);
+                    }
+                }
+            }
+        }
+        if changed {
+            self.update_stack(images);
+        }
+
+        let due = self
+            .last_poll
+            .is_none_or(|last_poll| last_poll.elapsed() >= POLL_INTERVAL);
+        // grades and weights change with every frame and every setting, and a
+        // finished job leaves room for the next
+        if due || finished {
+            changed |= self.reconcile(images);
+        }
+        if !due {
+            return changed;
+        }
+        self.last_poll = Some(std::time::Instant::now());
+
+        for path in image::frame_paths(&self.directory) {
+            if self.seen.contains(&path) || self.reading.contains(&path) {
+                continue;
+            }
+            if self.reading.len() + self.warping.len() >= MAX_JOBS {
+                break;
+            }
+            let Ok(size) = std::fs::metadata(&path).map(|m| m.len()) else {
+                continue;
+            };
+            if self.pending.insert(path.clone(), size) != Some(size) {
+                continue;
+            }
+
+            self.pending.remove(&path);
+            self.reading.insert(path.clone());
+            let job = Job::Read {
+                path,
+                defects: images.defects.clone(),
+                reference: images.processed[&images.reference()]
+                    .local_max_points
+                    .clone(),
+                order: images.distortion_order,
+            };
+            self.jobs().sender.send(job).ok();
+        }
+        changed
+    }
+
+    fn jobs(&mut self) -> &Jobs {
+        self.jobs.get_or_insert_with(|| {
+            let (sender, jobs) = mpsc::channel::<Job>();
+            let (done, receiver) = mpsc::channel();
+            // ends once the stack and its sender are dropped
+            std::thread::spawn(move || {
+                for job in jobs {
+                    if done.send(job.run()).is_err() {
+                        break;
+                    }
+                }
+            });
+            Jobs { sender, receiver }
+        })
+    }
+
+    // a file that fails to read may still be being written, so it is read again
+    // once its size is stable until it has failed a few times
+    fn failed(&mut self, path: String, err: String) {
+        let attempts = self.attempts.entry(path.clone()).or_default();
+        *attempts += 1;
+        if *attempts >= MAX_ATTEMPTS {
+            println!("skipping {err}");
+            self.seen.insert(path);
+        } else {
+            println!("retrying {err}");
+        }
+    }
+
+    /// Weight `frame` should have in the sums, 0 if rejected.
+    fn target_weight(images: &ImageMemory, frame: usize) -> f32 {
+        let grade = &images.grades.grades[frame];
+        if grade.rejected() {
+            return 0.0;
+        }
+        match weight::weight(grade, &images.weighting) {
+            Ok(weight) => weight,
+            Err(err) => {
+                println!("failed to weight {}: {err}", images.paths[frame]);
+                0.0
+            }
+        }
+    }
+
+    /// What `frame` would be warped with now, `None` if it is not registered.
+    fn inputs(
+        images: &ImageMemory,
+        frame: usize,
+        reference_estimates: Option<(usize, [normalize::Estimate; 3])>,
+    ) -> Option<Inputs> {
+        let reference = images.reference();
+        let (_, reference_estimates) = reference_estimates.filter(|(r, _)| *r == reference)?;
+        let registration = images.registrations.get(&frame)?;
+        let processed = &images.processed[&frame];
+        Some(Inputs {
+            reference,
+            reference_estimates,
+            normalization: images.normalization,
+            transform: registration.transform.offset(processed.log_offset()),
+            trails: processed.masked_trails(),
+        })
+    }
+
+    /// Warps every frame whose weight in the sums is out of date, to be added, taken
+    /// out or reweighted. Returns true if the sums were cleared to be rebuilt.
+    fn reconcile(&mut self, images: &ImageMemory) -> bool {
+        let reference = images.reference();
+        let (width, height) = (
+            images.raw[reference][0].width,
+            images.raw[reference][0].height,
+        );
+        if self.reference_estimates.is_none_or(|(r, _)| r != reference) {
+            self.reference_estimates = Some((reference, integrate::reference_estimates(images)));
+        }
+
+        // a frame warped differently than it was added cannot be taken out again
+        let outdated = self.stacked.iter().any(|(frame, (_, inputs))| {
+            Self::inputs(images, *frame, self.reference_estimates).as_ref() != Some(inputs)
+        });
+        if outdated {
+            println!("restacking {} frames", self.stacked.len());
+            self.sums = None;
+            self.total_weight = None;
+            self.stacked.clear();
+        }
+
+        for frame in 0..images.raw.len() {
+            if self.reading.len() + self.warping.len() >= MAX_JOBS {
+                break;
+            }
+            let stacked = self.stacked.get(&frame).map_or(0.0, |(w, _)| *w);
+            if self.warping.contains(&frame) || stacked == Self::target_weight(images, frame) {
+                continue;
+            }
+            let Some(inputs) = Self::inputs(images, frame, self.reference_estimates) else {
+                continue;
+            };
+            let warp = Warp {
+                frame,
+                channels: images.raw[frame].clone(),
+                inputs,
+                width,
+                height,
+            };
+            self.warping.insert(frame);
+            self.jobs().sender.send(Job::Warp(Box::new(warp))).ok();
+        }
+        outdated
+    }
+
+    // Moves the weight of `frame` in the sums to its current one, returns whether
+    // it changed.
+    fn apply(
+        &mut self,
+        images: &ImageMemory,
+        frame: usize,
+        inputs: Inputs,
+        warped: &[Image<f32>; 3],
+    ) -> bool {
+        let target = Self::target_weight(images, frame);
+        let stacked = self.stacked.get(&frame).map_or(0.0, |(w, _)| *w);
+        if target == stacked {
+            return false;
+        }
+
+        let (width, height) = (warped[0].width, warped[0].height);
+        let empty = || Image {
+            pixels: vec![0.0; width * height],
+            width,
+            height,
+        };
+        let sums = self
+            .sums
+            .get_or_insert_with(|| std::array::from_fn(|_| empty()));
+        let total_weight = self.total_weight.get_or_insert_with(empty);
+        // warping the same inputs is deterministic, so the difference takes out what
+        // was added
+        let weight = target - stacked;
+        for (i, w) in total_weight.pixels.iter_mut().enumerate() {
+            if warped[0].pixels[i].is_nan() {
+                continue;
+            }
+            *w += weight;
+            for (sum, channel) in sums.iter_mut().zip(warped.iter()) {
+                sum.pixels[i] += channel.pixels[i] * weight;
+            }
+        }
+        if target > 0.0 {
+            self.stacked.insert(frame, (target, inputs));
+            println!("stacked {}", images.paths[frame]);
+        } else {
+            self.stacked.remove(&frame);
+            println!("removed {}", images.paths[frame]);
+        }
+        true
+    }
+
+    fn update_stack(&self, images: &mut ImageMemory) {
+        let (Some(sums), Some(total_weight)) = (&self.sums, &self.total_weight) else {
+            return;
+        };
+        if self.stacked.is_empty() {
+            images.stack = None;
+            return;
+        }
+        let channels = sums.clone().map(|mut channel| {
+            for (v, w) in channel.pixels.iter_mut().zip(total_weight.pixels.iter()) {
+                // all that is left where every frame was taken out is rounding
+                *v = if *w > 1e-6 { *v / w } else { 0.0 };
+            }
+            channel
+        });
+
+        // normalized like every other integration for the header
+        let max = self.stacked.values().map(|(w, _)| *w).fold(0.0, f32::max);
+        let mut weights: Vec<_> = self
+            .stacked
+            .iter()
+            .map(|(frame, (w, _))| (*frame, w / max))
+            .collect();
+        weights.sort_by_key(|(frame, _)| *frame);
+        let mut header = integrate::header(images, &images.paths, &weights);
+        header.history("live stack");
+
+        images.set_stack(Integration {
+            preview: image::channels_to_srgb(&channels),
+            channels,
+            weight_maps: Vec::new(),
+            weights,
+            header,
+            wcs: integrate::reference_wcs(images, 1.0),
+            star_scale: Some(1.0),
+        });
+    }
+}
@@ -0,0 +1,197 @@
+use crate::image::{Image, Luminance};
+use crate::stats;
+
+/// Half size of the window every star is measured in.
+pub const MEASURE_RADIUS: i32 = 8;
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Star {
+    /// Intensity weighted centroid in the raw frame.
+    pub x: f32,
+    pub y: f32,
+    /// Background subtracted sum within the measurement window.
+    pub flux: f32,
+    pub fwhm: f32,
+    /// 0 for a perfectly round star, approaching 1 as it elongates.
+    pub eccentricity: f32,
+    pub snr: f32,
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct FrameMetrics {
+    pub star_count: usize,
+    /// Median of all measured stars.
+    pub flux: f32,
+    /// Median of all measured stars.
+    pub fwhm: f32,
+    /// Median of all measured stars.
+    pub eccentricity: f32,
+    pub background: f32,
+    /// Standard deviation of the background.
+    pub noise: f32,
+    /// Median of all measured stars.
+    pub snr: f32,
+}
+
+/// Robust estimate of the background level and noise from a subsample of pixels.
+pub fn background<In: Luminance + Copy>(image: &Image<In>) -> (f32, f32) {
+    let stride = 7;
+    let mut values: Vec<f32> = image
+        .pixels
+        .iter()
+        .step_by(stride)
+        .map(|p| p.luminance())
+        .collect();
+    let background = stats::median(&mut values);
+    let noise = stats::mad(&mut values, background);
+    (background, noise)
+}
+
+// https://en.wikipedia.org/wiki/Image_moment#Central_moments
+//
+// `points` are peaks from `process::peak_local_max`, offset by `offset` pixels from
+// the raw frame. Every point produces a star so that indices are shared.
+pub fn measure_stars<In: Luminance + Copy>(
+    image: &Image<In>,
+    points: &[(f32, f32, f32)],
+    offset: f32,
+    background: f32,
+    noise: f32,
+) -> Vec<Star> {
+    assert_eq!(image.pixels.len(), image.width * image.height);
+
+    let radius = MEASURE_RADIUS;
+    let width = image.width as i32;
+    let height = image.height as i32;
+    points
+        .iter()
+        .map(|(px, py, _)| {
+            let cx = (px + offset) as i32;
+            let cy = (py + offset) as i32;
+
+            let mut flux = 0.0;
+            let mut mx = 0.0;
+            let mut my = 0.0;
+            let mut samples = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+            for y in (cy - radius).max(0)..=(cy + radius).min(height - 1) {
+                for x in (cx - radius).max(0)..=(cx + radius).min(width - 1) {
+                    let l = image.pixels[(y * width + x) as usize].luminance() - background;
+                    let l = l.max(0.0);
+                    flux += l;
+                    mx += l * x as f32;
+                    my += l * y as f32;
+                    samples.push((x as f32, y as f32, l));
+                }
+            }
+            if flux <= 0.0 {
+                return Star {
+                    x: cx as f32,
+                    y: cy as f32,
+                    flux: 0.0,
+                    fwhm: 0.0,
+                    eccentricity: 0.0,
+                    snr: 0.0,
+                };
+            }
+            let x = mx / flux;
+            let y = my / flux;
+
+            let mut mxx = 0.0;
+            let mut myy = 0.0;
+            let mut mxy = 0.0;
+            for (sx, sy, l) in samples.iter() {
+                mxx += l * (sx - x) * (sx - x);
+                myy += l * (sy - y) * (sy - y);
+                mxy += l * (sx - x) * (sy - y);
+            }
+            mxx /= flux;
+            myy /= flux;
+            mxy /= flux;
+
+            // eigenvalues of the covariance matrix
+            let trace = mxx + myy;
+            let det = mxx * myy - mxy * mxy;
+            let disc = (trace * trace / 4.0 - det).max(0.0).sqrt();
+            let major = trace / 2.0 + disc;
+            let minor = (trace / 2.0 - disc).max(0.0);
+
+            // https://en.wikipedia.org/wiki/Full_width_at_half_maximum
+            let sigma = (trace / 2.0).sqrt();
+            let fwhm = 2.0 * (2.0 * std::f32::consts::LN_2).sqrt() * sigma;
+            let eccentricity = if major > 0.0 {
+                (1.0 - minor / major).sqrt()
+            } else {
+                0.0
+            };
+            let snr = flux / (flux + samples.len() as f32 * noise * noise).sqrt();
+
+            Star {
+                x,
+                y,
+                flux,
+                fwhm,
+                eccentricity,
+                snr,
+            }
+        })
+        .collect()
+}
+
+pub fn frame_metrics(stars: &[Star], background: f32, noise: f32) -> FrameMetrics {
+    let stars: Vec<_> = stars.iter().filter(|s| s.flux > 0.0).collect();
+    let median_of = |f: fn(&Star) -> f32| {
+        let mut values: Vec<f32> = stars.iter().map(|s| f(s)).collect();
+        stats::median(&mut values)
+    };
+    FrameMetrics {
+        star_count: stars.len(),
+        flux: median_of(|s| s.flux),
+        fwhm: median_of(|s| s.fwhm),
+        eccentricity: median_of(|s| s.eccentricity),
+        background,
+        noise,
+        snr: median_of(|s| s.snr),
+    }
+}
+
+/// Scores every frame relative to the best value of each metric in the session
+/// and picks the highest: many, tight and round stars over a dark background.
+pub fn select_reference(metrics: &[FrameMetrics]) -> usize {
+    assert!(!metrics.is_empty());
+
+    let max_stars = metrics
+        .iter()
+        .map(|m| m.star_count)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f32;
+    let min_fwhm = metrics
+        .iter()
+        .map(|m| m.fwhm)
+        .filter(|f| *f > 0.0)
+        .fold(f32::MAX, f32::min);
+    let min_background = metrics
+        .iter()
+        .map(|m| m.background)
+        .filter(|b| *b > 0.0)
+        .fold(f32::MAX, f32::min);
+
+    let score = |m: &FrameMetrics| {
+        let stars = m.star_count as f32 / max_stars;
+        let fwhm = if m.fwhm > 0.0 { min_fwhm / m.fwhm } else { 0.0 };
+        let background = if m.background > 0.0 {
+            min_background / m.background
+        } else {
+            1.0
+        };
+        let roundness = 1.0 - m.eccentricity;
+        stars + fwhm + background + roundness
+    };
+
+    metrics
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| score(a).total_cmp(&score(b)))
+        .map(|(i, _)| i)
+        .unwrap()
+}
@@ -0,0 +1,433 @@
+use crate::align::{self, Similarity};
+use crate::image::{self, Image};
+use crate::integrate::Integration;
+use crate::{ALIGN_THRESHOLD, fits, linalg, process, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Mosaic {
+    /// Width of the blend across the seam between overlapping panels, in pixels.
+    pub feather: f32,
+    pub match_backgrounds: bool,
+}
+
+/// Two panels that were found to overlap.
+pub struct PanelOverlap {
+    pub a: String,
+    pub b: String,
+    /// Stars matched between them.
+    pub stars: usize,
+}
+
+/// Matched stars of two overlapping panels.
+struct Overlap {
+    a: usize,
+    b: usize,
+    /// `(a points index, b points index)`
+    pairs: Vec<(usize, usize)>,
+}
+
+struct Panel {
+    channels: [Image<f32>; 3],
+    /// Detections in panel pixels, brightest first.
+    points: Vec<(f32, f32, f32)>,
+}
+
+/// Stitches every panel in `directory` onto a canvas in the geometry of the first,
+/// returning the overlaps the panels were placed with.
+pub fn stitch(directory: &str, mosaic: Mosaic) -> Result<(Integration, Vec<PanelOverlap>), String> {
+    let paths = image::frame_paths(directory);
+    if paths.len() < 2 {
+        return Err(format!("{directory} needs at least 2 panels"));
+    }
+    let panels = paths
+        .iter()
+        .map(|path| load_panel(path))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut overlaps = Vec::new();
+    for i in 0..panels.len() {
+        for j in i + 1..panels.len() {
+            if let Some(pairs) = matches(&panels[i], &panels[j]) {
+                overlaps.push(Overlap { a: i, b: j, pairs });
+            }
+        }
+    }
+    let placements = place(&panels, &overlaps)?;
+
+    // canvas covering every placed panel
+    let (mut xmin, mut ymin, mut xmax, mut ymax) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+    for (panel, placement) in panels.iter().zip(placements.iter()) {
+        let (w, h) = (
+            panel.channels[0].width as f32,
+            panel.channels[0].height as f32,
+        );
+        for (x, y) in [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)] {
+            let (x, y) = placement.apply(x, y);
+            (xmin, ymin) = (xmin.min(x), ymin.min(y));
+            (xmax, ymax) = (xmax.max(x), ymax.max(y));
+        }
+    }
+    let width = (xmax - xmin).ceil() as usize;
+    let height = (ymax - ymin).ceil() as usize;
+    // canvas pixels to panel pixels
+    let inverses: Vec<_> = placements
+        .iter()
+        .map(|p| {
+            let mut p = *p;
+            p.tx -= xmin;
+            p.ty -= ymin;
+            p.inverse()
+        })
+        .collect();
+
+    let offsets = if mosaic.match_backgrounds {
+        background_offsets(&panels, &inverses, &overlaps, width, height)?
+    } else {
+        vec![[0.0; 3]; panels.len()]
+    };
+
+    let mut channels: [Image<f32>; 3] = std::array::from_fn(|_| Image {
+        pixels: vec![0.0; width * height],
+        width,
+        height,
+    });
+    let mut coverage = Image {
+        pixels: vec![0.0; width * height],
+        width,
+        height,
+    };
+    // (distance to the panel edge, background matched values) of every panel
+    // covering a pixel
+    let mut covering = Vec::with_capacity(panels.len());
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            covering.clear();
+            for ((panel, inverse), offset) in panels.iter().zip(inverses.iter()).zip(offsets.iter())
+            {
+                let (px, py) = inverse.apply(x as f32, y as f32);
+                let Some(edge) = edge_distance(&panel.channels[0], px, py) else {
+                    continue;
+                };
+                let values: Option<Vec<f32>> = panel
+                    .channels
+                    .iter()
+                    .zip(offset)
+                    .map(|(c, o)| {
+                        c.sample_bilinear(px, py)
+                            .filter(|v| !v.is_nan())
+                            .map(|v| v - o)
+                    })
+                    .collect();
+                if let Some(values) = values {
+                    covering.push((edge, values));
+                }
+            }
+            for (k, (edge, values)) in covering.iter().enumerate() {
+                let nearest = covering
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != k)
+                    .map(|(_, (edge, _))| *edge)
+                    .reduce(f32::max);
+                let weight = seam_weight(*edge, nearest, mosaic.feather);
+                for (channel, v) in channels.iter_mut().zip(values) {
+                    channel.pixels[i] += weight * v;
+                }
+                coverage.pixels[i] += weight;
+            }
+            for channel in channels.iter_mut() {
+                channel.pixels[i] = if coverage.pixels[i] > 0.0 {
+                    channel.pixels[i] / coverage.pixels[i]
+                } else {
+                    f32::NAN
+                };
+            }
+        }
+    }
+
+    let mut header = fits::Header::default();
+    header.push(
+        "NPANELS",
+        fits::Value::Integer(panels.len() as i64),
+        "mosaic panels",
+    );
+    for (i, path) in paths.iter().enumerate() {
+        header.push(
+            &format!("PANL{:04}", i + 1),
+            fits::Value::String(path.clone()),
+            "",
+        );
+    }
+    header.history(&format!(
+        "mosaic feather {} background matching {}",
+        mosaic.feather, mosaic.match_backgrounds
+    ));
+    let stack = Integration {
+        preview: image::channels_to_srgb(&channels),
+        channels,
+        weight_maps: vec![coverage],
+        weights: (0..panels.len()).map(|p| (p, 1.0)).collect(),
+        header,
+        wcs: None,
+        star_scale: None,
+    };
+    let overlaps = overlaps
+        .into_iter()
+        .map(|overlap| PanelOverlap {
+            a: paths[overlap.a].clone(),
+            b: paths[overlap.b].clone(),
+            stars: overlap.pairs.len(),
+        })
+        .collect();
+    Ok((stack, overlaps))
+}
+
+// Matched stars of `a` and `b` if they overlap. Panels that do not still match a
+// few stars by chance, with a poor fit.
+fn matches(a: &Panel, b: &Panel) -> Option<Vec<(usize, usize)>> {
+    let min_pairs = 5;
+    let max_rms = 2.0;
+    let size = [a, b]
+        .iter()
+        .map(|p| p.channels[0].width.max(p.channels[0].height))
+        .max()
+        .unwrap();
+    align::register(size, size, &a.points, &b.points, ALIGN_THRESHOLD, 1)
+        .filter(|r| r.pairs.len() >= min_pairs && r.residuals.rms <= max_rms)
+        .map(|r| r.pairs)
+}
+
+fn load_panel(path: &str) -> Result<Panel, String> {
+    let (channels, _) = image::load(path)?;
+    // panels only share the stars in their overlaps, so detect deeper than frames
+    let sigma = 2.0;
+    let percentile = 0.999;
+    let preview = image::channels_to_srgb(&channels);
+    let log: Image<f32> = process::laplacian_of_gaussian(&preview, sigma);
+    let dilate: Image<f32> = process::dilate(&log, (3.0 * sigma).ceil() as usize);
+    let offset = (preview.width - log.width) as f32 / 2.0;
+    let points = process::peak_local_max(&log, &dilate, percentile, None)
+        .into_iter()
+        .map(|(x, y, l)| (x + offset, y + offset, l))
+        .collect();
+    Ok(Panel { channels, points })
+}
+
+// https://en.wikipedia.org/wiki/Bundle_adjustment
+//
+// Similarity of every panel into the first, solved together so that errors do not
+// accumulate along chains of overlaps:
+//
+//   S_i(p) - S_j(q) = 0 for every matched star p in panel i and q in panel j
+//
+// which is linear in the (a, b, tx, ty) of each panel.
+fn place(panels: &[Panel], overlaps: &[Overlap]) -> Result<Vec<Similarity>, String> {
+    // the first panel is fixed at the identity
+    let unknowns = 4 * (panels.len() - 1);
+    let column = |panel: usize| (panel > 0).then(|| 4 * (panel - 1));
+    let mut rows = Vec::new();
+    let mut rhs = Vec::new();
+    for Overlap { a, b, pairs } in overlaps.iter() {
+        for (p, q) in pairs.iter() {
+            let (px, py, _) = panels[*a].points[*p];
+            let (qx, qy, _) = panels[*b].points[*q];
+            // x and y equations
+            for axis in 0..2 {
+                let mut row = vec![0.0; unknowns];
+                let mut constant = 0.0;
+                for (panel, (x, y), sign) in [(*a, (px, py), 1.0), (*b, (qx, qy), -1.0)] {
+                    let (x, y) = (x as f64, y as f64);
+                    let terms = if axis == 0 {
+                        [x, -y, 1.0, 0.0]
+                    } else {
+                        [y, x, 0.0, 1.0]
+                    };
+                    match column(panel) {
+                        Some(c) => {
+                            for (k, t) in terms.iter().enumerate() {
+                                row[c + k] += sign * t;
+                            }
+                        }
+                        // identity
+                        None => constant += sign * if axis == 0 { x } else { y },
+                    }
+                }
+                rows.push(row);
+                rhs.push(-constant);
+            }
+        }
+    }
+    let solution = linalg::least_squares(&rows, &rhs)
+        .ok_or("the panels do not overlap enough to be placed")?;
+    Ok((0..panels.len())
+        .map(|panel| match column(panel) {
+            Some(c) => Similarity {
+                a: solution[c] as f32,
+                b: solution[c + 1] as f32,
+                tx: solution[c + 2] as f32,
+                ty: solution[c + 3] as f32,
+            },
+            None => Similarity::IDENTITY,
+        })
+        .collect())
+}
+
+// Additive offset of each panel relative to the first from the median difference
+// within every overlap, solved together like the placement.
+fn background_offsets(
+    panels: &[Panel],
+    inverses: &[Similarity],
+    overlaps: &[Overlap],
+    width: usize,
+    height: usize,
+) -> Result<Vec<[f32; 3]>, String> {
+    // a sparse grid of the overlap is plenty for a median
+    let stride = 8;
+    let unknowns = panels.len() - 1;
+    let mut rows = Vec::new();
+    let mut rhs: [Vec<f64>; 3] = Default::default();
+    for Overlap { a, b, .. } in overlaps.iter() {
+        let mut differences: [Vec<f32>; 3] = Default::default();
+        for y in (0..height).step_by(stride) {
+            for x in (0..width).step_by(stride) {
+                let sample = |panel: usize, c: usize| {
+                    let (px, py) = inverses[panel].apply(x as f32, y as f32);
+                    panels[panel].channels[c]
+                        .sample_bilinear(px, py)
+                        .filter(|v| !v.is_nan())
+                };
+                for (c, differences) in differences.iter_mut().enumerate() {
+                    if let (Some(va), Some(vb)) = (sample(*a, c), sample(*b, c)) {
+                        differences.push(va - vb);
+                    }
+                }
+            }
+        }
+        if differences[0].is_empty() {
+            continue;
+        }
+        let mut row = vec![0.0; unknowns];
+        if *a > 0 {
+            row[*a - 1] = 1.0;
+        }
+        if *b > 0 {
+            row[*b - 1] = -1.0;
+        }
+        rows.push(row);
+        for (rhs, differences) in rhs.iter_mut().zip(differences.iter_mut()) {
+            rhs.push(stats::median(differences) as f64);
+        }
+    }
+
+    let solutions = rhs
+        .iter()
+        .map(|rhs| linalg::least_squares(&rows, rhs))
+        .collect::<Option<Vec<_>>>()
+        .ok_or("the panel overlaps do not constrain their backgrounds")?;
+    Ok((0..panels.len())
+        .map(|panel| {
+            std::array::from_fn(|c| {
+                if panel == 0 {
+                    0.0
+                } else {
+                    solutions[c][panel - 1] as f32
+                }
+            })
+        })
+        .collect())
+}
+
+// Distance from `(x, y)` to the nearest edge of `image`, `None` outside of it.
+fn edge_distance(image: &Image<f32>, x: f32, y: f32) -> Option<f32> {
+    let (w, h) = (image.width as f32 - 1.0, image.height as f32 - 1.0);
+    let edge = x.min(w - x).min(y).min(h - y);
+    (edge >= 0.0).then_some(edge)
+}
+
+// The seam between two panels runs where a pixel is as far inside both, away from
+// the edges where panels vignette and misalign. A panel's weight ramps across a
+// band `width` wide centered on its seam with the other panel `nearest` inside,
+// and is 1 where nothing else covers it.
+fn seam_weight(edge: f32, nearest: Option<f32>, width: f32) -> f32 {
+    match nearest {
+        Some(nearest) => ((edge - nearest) / width.max(1.0) + 0.5).clamp(0.0, 1.0),
+        None => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seams() {
+        // two panels side by side overlapping by 100 pixels, blended over 20
+        let weights = |x: f32| {
+            let (a, b) = (150.0 - x, x - 50.0);
+            (seam_weight(a, Some(b), 20.0), seam_weight(b, Some(a), 20.0))
+        };
+        assert_eq!(weights(60.0), (1.0, 0.0));
+        assert_eq!(weights(100.0), (0.5, 0.5));
+        assert_eq!(weights(140.0), (0.0, 1.0));
+        let (a, b) = weights(95.0);
+        assert!(a > 0.5 && (a + b - 1.0).abs() < 1e-6);
+        assert_eq!(seam_weight(3.0, None, 20.0), 1.0);
+    }
+
+    // stars of a 600 x 400 field, brightest first
+    fn field(seed: u64) -> Vec<(f32, f32, f32)> {
+        let mut state = seed;
+        let mut random = || {
+            // https://en.wikipedia.org/wiki/Linear_congruential_generator
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as f32 / (1u64 << 31) as f32
+        };
+        let mut stars: Vec<_> = (0..150)
+            .map(|_| (random() * 600.0, random() * 400.0, random()))
+            .collect();
+        stars.sort_by(|a, b| b.2.total_cmp(&a.2));
+        stars
+    }
+
+    // the stars of `field` between `x0` and `x0 + 400` in panel pixels
+    fn panel(field: &[(f32, f32, f32)], x0: f32) -> Panel {
+        let channel = Image {
+            pixels: vec![0.0; 400 * 400],
+            width: 400,
+            height: 400,
+        };
+        Panel {
+            channels: std::array::from_fn(|_| channel.clone()),
+            points: field
+                .iter()
+                .filter(|(x, _, _)| (x0..x0 + 400.0).contains(x))
+                .map(|(x, y, l)| (x - x0, *y, *l))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn overlaps() {
+        let sky = field(1);
+        let (left, right) = (panel(&sky, 0.0), panel(&sky, 200.0));
+        let pairs = matches(&left, &right).unwrap();
+        assert!(pairs.len() >= 5);
+        for (i, j) in pairs {
+            let (x, y, _) = left.points[i];
+            let (rx, ry, _) = right.points[j];
+            assert!((x - 200.0 - rx).abs() < 1e-3 && (y - ry).abs() < 1e-3);
+        }
+
+        // another part of the sky, where a few stars happen to form the same pattern
+        let mut elsewhere = panel(&field(2), 0.0);
+        let pattern = left.points[..4]
+            .iter()
+            .map(|(x, y, l)| (x + 50.0, y + 30.0, *l));
+        elsewhere.points.splice(0..0, pattern);
+        assert!(matches(&left, &elsewhere).is_none());
+        assert!(matches(&right, &elsewhere).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::image::Image;
+use crate::stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Normalization {
+    None,
+    /// Matches the background level, for gradients that shift the sky.
+    Additive,
+    /// Matches the background level by scaling, for changes in transparency.
+    Multiplicative,
+    /// Matches both the background level and its spread.
+    AdditiveScaling,
+}
+
+impl std::fmt::Display for Normalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Additive => write!(f, "additive"),
+            Self::Multiplicative => write!(f, "multiplicative"),
+            Self::AdditiveScaling => write!(f, "additive with scaling"),
+        }
+    }
+}
+
+/// Robust location and scale of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Estimate {
+    /// Median.
+    pub location: f32,
+    /// Median absolute deviation.
+    pub scale: f32,
+}
+
+/// Ignores NaN so that estimates can be taken on warped frames.
+pub fn estimate(image: &Image<f32>) -> Estimate {
+    let stride = 7;
+    let mut values: Vec<_> = image
+        .pixels
+        .iter()
+        .step_by(stride)
+        .copied()
+        .filter(|v| !v.is_nan())
+        .collect();
+    let location = stats::median(&mut values);
+    let scale = stats::mad(&mut values, location);
+    Estimate { location, scale }
+}
+
+/// Maps `image` with `estimate` onto `reference`.
+pub fn normalize(
+    image: &mut Image<f32>,
+    estimate: Estimate,
+    reference: Estimate,
+    normalization: Normalization,
+) {
+    // quantized data can have no measurable spread
+    let scale = if estimate.scale > 0.0 && reference.scale > 0.0 {
+        reference.scale / estimate.scale
+    } else {
+        1.0
+    };
+    let ratio = if estimate.location > 0.0 {
+        reference.location / estimate.location
+    } else {
+        1.0
+    };
+    for v in image.pixels.iter_mut() {
+        *v = match normalization {
+            Normalization::None => *v,
+            Normalization::Additive => *v - estimate.location + reference.location,
+            Normalization::Multiplicative => *v * ratio,
+            Normalization::AdditiveScaling => (*v - estimate.location) * scale + reference.location,
+        };
+    }
+}
@@ -0,0 +1,234 @@
+// https://www.aavso.org/sites/default/files/publications_files/ccd_photometry_guide/CCDPhotometryGuide.pdf
+
+use crate::image::{self, Image, ImageMemory};
+use crate::metrics::Star;
+use crate::stats;
+use std::fmt::Write;
+
+/// Radii in multiples of the median FWHM of each frame.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Aperture {
+    pub radius: f32,
+    /// Background annulus.
+    pub inner: f32,
+    pub outer: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Photometry {
+    pub aperture: Aperture,
+    /// Reference frame pixels of the target followed by the comparison stars.
+    pub stars: Vec<(f32, f32)>,
+}
+
+impl Photometry {
+    /// Marks the detected star nearest `(x, y)` in reference frame pixels, or
+    /// unmarks it if it is already marked. Returns whether it is now marked.
+    pub fn toggle(&mut self, stars: &[Star], x: f32, y: f32) -> Result<bool, String> {
+        let max_distance = 10.0;
+        let star = stars
+            .iter()
+            .filter(|s| s.flux > 0.0)
+            .min_by(|a, b| {
+                let da = (a.x - x).hypot(a.y - y);
+                let db = (b.x - x).hypot(b.y - y);
+                da.total_cmp(&db)
+            })
+            .filter(|s| (s.x - x).hypot(s.y - y) <= max_distance)
+            .ok_or("no star under the cursor")?;
+
+        let marked = self.stars.len();
+        self.stars
+            .retain(|(mx, my)| (mx - star.x).hypot(my - star.y) > max_distance);
+        if self.stars.len() < marked {
+            return Ok(false);
+        }
+        self.stars.push((star.x, star.y));
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub frame: usize,
+    /// Middle of the exposure in seconds since the Unix epoch.
+    pub time: f64,
+    /// Target minus the ensemble of comparison stars.
+    pub magnitude: f32,
+    pub error: f32,
+    /// Background subtracted flux of every star, in the order they were marked.
+    pub fluxes: Vec<f32>,
+}
+
+/// Differential photometry of the target against the summed flux of the
+/// comparison stars in every registered frame, sorted by time. Frames where a
+/// star leaves the image or saturates are skipped.
+pub fn light_curve(images: &ImageMemory, photometry: &Photometry) -> Result<Vec<Point>, String> {
+    if photometry.stars.len() < 2 {
+        return Err("mark a target and at least one comparison star".to_string());
+    }
+
+    let mut points = Vec::new();
+    for (&frame, registration) in images.registrations.iter() {
+        let time = images.headers[frame]
+            .time()
+            .ok_or_else(|| format!("{} has no DATE-OBS", images.paths[frame]))?;
+        let processed = &images.processed[&frame];
+        let transform = registration.transform.offset(processed.log_offset());
+        let fwhm = processed.metrics.fwhm;
+        let luminance = image::luminance(&images.raw[frame]);
+
+        let measurements: Option<Vec<_>> = photometry
+            .stars
+            .iter()
+            .map(|(x, y)| {
+                let (x, y) = transform.invert(*x, *y);
+                let (x, y) = centroid(&luminance, x, y, photometry.aperture.radius * fwhm);
+                measure(&luminance, x, y, fwhm, photometry.aperture)
+            })
+            .collect();
+        let Some(measurements) = measurements else {
+            continue;
+        };
+
+        let (target, target_error) = measurements[0];
+        let ensemble: f32 = measurements[1..].iter().map(|(f, _)| f).sum();
+        let ensemble_error = measurements[1..]
+            .iter()
+            .map(|(_, e)| e * e)
+            .sum::<f32>()
+            .sqrt();
+        if target <= 0.0 || ensemble <= 0.0 {
+            continue;
+        }
+        // 2.5 / ln(10) turns relative flux errors into magnitudes
+        let error =
+            1.0857 * ((target_error / target).powi(2) + (ensemble_error / ensemble).powi(2)).sqrt();
+        points.push(Point {
+            frame,
+            time,
+            magnitude: -2.5 * (target / ensemble).log10(),
+            error,
+            fluxes: measurements.iter().map(|(f, _)| *f).collect(),
+        });
+    }
+    if points.is_empty() {
+        return Err("no frame has every star measurable".to_string());
+    }
+    points.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(points)
+}
+
+/// One row per point, keyed by the DATE-OBS of its frame.
+pub fn write_csv(path: &str, images: &ImageMemory, points: &[Point]) -> Result<(), String> {
+    let comparisons = points.first().map_or(0, |p| p.fluxes.len() - 1);
+    let mut csv = String::from("date_obs,jd,frame,magnitude,error,target");
+    for i in 1..=comparisons {
+        write!(csv, ",comparison{i}").unwrap();
+    }
+    csv.push('\n');
+    for point in points.iter() {
+        let date = images.headers[point.frame]
+            .get("DATE-OBS")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        // https://en.wikipedia.org/wiki/Julian_day
+        let jd = point.time / 86400.0 + 2440587.5;
+        write!(
+            csv,
+            "{date},{jd:.6},{},{:.5},{:.5}",
+            point.frame, point.magnitude, point.error
+        )
+        .unwrap();
+        for flux in point.fluxes.iter() {
+            write!(csv, ",{flux}").unwrap();
+        }
+        csv.push('\n');
+    }
+    std::fs::write(path, csv).map_err(|err| format!("{path}: {err}"))
+}
+
+// Intensity weighted centroid above the local median, following a star that
+// drifts slightly from where the registration puts it.
+fn centroid(image: &Image<f32>, x: f32, y: f32, radius: f32) -> (f32, f32) {
+    let iterations = 3;
+    let (mut cx, mut cy) = (x, y);
+    for _ in 0..iterations {
+        let pixels = circle(image, cx, cy, 0.0, radius);
+        let mut values: Vec<_> = pixels.iter().map(|(_, _, v)| *v).collect();
+        let background = stats::median(&mut values);
+        let (mut sum, mut mx, mut my) = (0.0, 0.0, 0.0);
+        for (px, py, v) in pixels {
+            let v = (v - background).max(0.0);
+            sum += v;
+            mx += v * px;
+            my += v * py;
+        }
+        if sum <= 0.0 {
+            break;
+        }
+        (cx, cy) = (mx / sum, my / sum);
+    }
+    // a bright neighbor can pull the centroid away
+    if (cx - x).hypot(cy - y) > radius {
+        (x, y)
+    } else {
+        (cx, cy)
+    }
+}
+
+// `(flux, error)` inside the aperture, the error from the background noise only
+// as the data carries no gain.
+fn measure(
+    image: &Image<f32>,
+    x: f32,
+    y: f32,
+    fwhm: f32,
+    aperture: Aperture,
+) -> Option<(f32, f32)> {
+    let saturated = 0.99;
+    let radius = aperture.radius * fwhm;
+    let outer = aperture.outer * fwhm;
+    if x - outer < 0.0
+        || y - outer < 0.0
+        || x + outer >= image.width as f32
+        || y + outer >= image.height as f32
+    {
+        return None;
+    }
+
+    let mut annulus: Vec<_> = circle(image, x, y, aperture.inner * fwhm, outer)
+        .into_iter()
+        .map(|(_, _, v)| v)
+        .filter(|v| !v.is_nan())
+        .collect();
+    let background = stats::median(&mut annulus);
+    let noise = stats::mad(&mut annulus, background);
+
+    let pixels = circle(image, x, y, 0.0, radius);
+    if pixels.iter().any(|(_, _, v)| v.is_nan() || *v >= saturated) {
+        return None;
+    }
+    let flux: f32 = pixels.iter().map(|(_, _, v)| v - background).sum();
+    let n = pixels.len() as f32;
+    let error = noise * (n * (1.0 + n / annulus.len().max(1) as f32)).sqrt();
+    Some((flux, error))
+}
+
+// `(x, y, value)` of the pixels whose centers are between `inner` and `outer`.
+fn circle(image: &Image<f32>, x: f32, y: f32, inner: f32, outer: f32) -> Vec<(f32, f32, f32)> {
+    let x0 = (x - outer).floor().max(0.0) as usize;
+    let y0 = (y - outer).floor().max(0.0) as usize;
+    let x1 = ((x + outer).ceil() as usize).min(image.width - 1);
+    let y1 = ((y + outer).ceil() as usize).min(image.height - 1);
+    let mut pixels = Vec::new();
+    for py in y0..=y1 {
+        for px in x0..=x1 {
+            let r = (px as f32 - x).hypot(py as f32 - y);
+            if r >= inner && r <= outer {
+                pixels.push((px as f32, py as f32, image.pixels[py * image.width + px]));
+            }
+        }
+    }
+    pixels
+}
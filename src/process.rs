@@ -59,22 +59,51 @@ pub fn dilate<In: Luminance + Copy, Out: FromLuminance + Default + Clone>(
     output
 }
 
+// https://en.wikipedia.org/wiki/Erosion_(morphology)
+//
+// Dilation of the negated image, NaN is ignored.
+pub fn erode(image: &Image<f32>, size: usize) -> Image<f32> {
+    let negated = Image {
+        pixels: image
+            .pixels
+            .iter()
+            .map(|v| if v.is_nan() { f32::MIN } else { -v })
+            .collect(),
+        width: image.width,
+        height: image.height,
+    };
+    let mut output: Image<f32> = dilate(&negated, size);
+    for (v, original) in output.pixels.iter_mut().zip(image.pixels.iter()) {
+        *v = if original.is_nan() { f32::NAN } else { -*v };
+    }
+    output
+}
+
 // https://scikit-image.org/docs/stable/auto_examples/segmentation/plot_peak_local_max.html
 pub fn peak_local_max<In1: Luminance + Copy, In2: Luminance + Copy>(
     image: &Image<In1>,
     max: &Image<In2>,
     percentile: f32,
+    // pixels to ignore
+    mask: Option<&Image<bool>>,
 ) -> Vec<(f32, f32, f32)> {
     assert_eq!(image.width, max.width);
     assert_eq!(image.height, max.height);
     assert_eq!(image.pixels.len(), max.pixels.len());
+    if let Some(mask) = mask {
+        assert_eq!(image.pixels.len(), mask.pixels.len());
+    }
+    let masked = |i: usize| mask.is_some_and(|mask| mask.pixels[i]);
 
-    let min_luminance = compute_adaptive_threshold(image, percentile);
+    let min_luminance = compute_adaptive_threshold(image, percentile, masked);
     let mut points = Vec::new();
     let width = image.width;
     for y in 0..image.height {
         for x in 0..image.width {
             let i = y * width + x;
+            if masked(i) {
+                continue;
+            }
             let in_pixel = image.pixels[i];
             let max_pixel = max.pixels[i];
             if (in_pixel.luminance() - max_pixel.luminance()).abs() < 0.0001
@@ -89,11 +118,17 @@ pub fn peak_local_max<In1: Luminance + Copy, In2: Luminance + Copy>(
     points
 }
 
-fn compute_adaptive_threshold<In: Luminance + Copy>(image: &Image<In>, percentile: f32) -> f32 {
+fn compute_adaptive_threshold<In: Luminance + Copy>(
+    image: &Image<In>,
+    percentile: f32,
+    masked: impl Fn(usize) -> bool,
+) -> f32 {
     let mut values: Vec<f32> = image
         .pixels
         .iter()
-        .map(|p| p.luminance())
+        .enumerate()
+        .filter(|(i, _)| !masked(*i))
+        .map(|(_, p)| p.luminance())
         .filter(|v| *v > 0.0)
         .collect();
     if values.is_empty() {
@@ -104,6 +139,16 @@ fn compute_adaptive_threshold<In: Luminance + Copy>(image: &Image<In>, percentil
     values[idx]
 }
 
+pub fn gaussian_blur(image: &Image<f32>, sigma: f32) -> Image<f32> {
+    let (kernel, size) = generate_gaussian_kernel(sigma);
+    let kernel = Image {
+        pixels: kernel,
+        width: size,
+        height: size,
+    };
+    conv_same(image, &kernel)
+}
+
 // https://en.wikipedia.org/wiki/Gaussian_blur
 fn generate_gaussian_kernel(sigma: f32) -> (Vec<f32>, usize) {
     let kernel_size = (6.0 * sigma).ceil() as usize | 1;
@@ -125,6 +170,25 @@ fn generate_gaussian_kernel(sigma: f32) -> (Vec<f32>, usize) {
     (kernel, kernel_size)
 }
 
+/// [`conv`] with edge pixels repeated so that the output keeps the size of `image`.
+pub fn conv_same(image: &Image<f32>, kernel: &Image<f32>) -> Image<f32> {
+    let (px, py) = (kernel.width / 2, kernel.height / 2);
+    let width = image.width + kernel.width - 1;
+    let height = image.height + kernel.height - 1;
+    let padded = Image {
+        pixels: (0..width * height)
+            .map(|i| {
+                let x = (i % width).saturating_sub(px).min(image.width - 1);
+                let y = (i / width).saturating_sub(py).min(image.height - 1);
+                image.pixels[y * image.width + x]
+            })
+            .collect(),
+        width,
+        height,
+    };
+    conv(&padded, kernel)
+}
+
 fn conv<In1: Luminance + Copy, In2: Luminance + Copy, Out: FromLuminance + Default + Clone>(
     i1: &Image<In1>,
     i2: &Image<In2>,
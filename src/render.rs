@@ -1,37 +1,94 @@
-use crate::{HEIGHT, Memory, View, WIDTH, align, image::Image};
+use crate::{
+    ALIGN_THRESHOLD, HEIGHT, Memory, View, WIDTH, align, comet, image::Image, stretch::Stf, wcs,
+    wcs::Wcs,
+};
+use std::collections::HashMap;
 use tint::{Color, Srgb};
 
-pub fn render(frame_buffer: &mut [Srgb], width: usize, height: usize, memory: &Memory) {
+/// Auto stretched previews of displayed images by view, image and linking. Cleared
+/// whenever the images may have changed, on input, settings and live stack updates.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct StfCache {
+    #[serde(skip)]
+    previews: HashMap<(View, usize, bool), Image<Srgb>>,
+}
+
+impl StfCache {
+    fn get(&mut self, key: (View, usize, bool), channels: &[Image<f32>; 3]) -> &Image<Srgb> {
+        self.previews
+            .entry(key)
+            .or_insert_with(|| Stf::auto(channels, key.2).preview(channels))
+    }
+
+    pub fn clear(&mut self) {
+        self.previews.clear();
+    }
+}
+
+pub fn render(frame_buffer: &mut [Srgb], width: usize, height: usize, memory: &mut Memory) {
     frame_buffer.fill(Srgb::from_rgb(80, 80, 80));
 
     let key = memory.images.selected_image;
-    let selected_image = if let Some(processed) = memory.images.processed.get(&key) {
-        match memory.view {
-            View::Raw => &processed.raw,
-            View::LoG => &processed.log,
-            View::Dilate => &processed.dilate,
-            View::LocalMax => &processed.local_max,
-            View::AlignTriangles => &processed.raw,
-        }
-    } else {
-        &memory.images.raw[key]
+    let processed = &memory.images.processed[&key];
+    let raw = (View::Raw, &memory.images.raw[key]);
+    // the linear data behind the preview, if any, for the auto stretch
+    let (selected_image, linear) = match memory.view {
+        View::Raw => (&processed.raw, Some(raw)),
+        View::LoG => (&processed.log, None),
+        View::Dilate => (&processed.dilate, None),
+        View::LocalMax => (&processed.local_max, None),
+        View::AlignTriangles => (&processed.raw, Some(raw)),
+        View::Stack => match &memory.images.stack {
+            Some(stack) => (&stack.preview, Some((View::Stack, &stack.channels))),
+            None => (&processed.raw, Some(raw)),
+        },
+        View::Background => match &memory.images.extraction {
+            Some(extraction) => (&extraction.preview, None),
+            None => (&processed.raw, Some(raw)),
+        },
+        View::StarMask => match &memory.images.star_mask {
+            Some(mask) => (mask, None),
+            None => (&processed.raw, Some(raw)),
+        },
+        View::Stars => match &memory.images.stars {
+            Some(stars) => (&stars.preview, Some((View::Stars, &stars.channels))),
+            None => (&processed.raw, Some(raw)),
+        },
     };
 
     if matches!(memory.view, View::AlignTriangles) {
-        let next_index = memory.next_image_index();
+        let reference = memory.images.reference();
         let processed = &memory.images.processed[&memory.images.selected_image];
-        let processed2 = &memory.images.processed[&next_index];
+        let processed2 = &memory.images.processed[&reference];
         assert_eq!(processed.log.pixels.len(), processed2.log.pixels.len());
         let triangles = crate::align::align(
             processed.log.width,
             processed.log.height,
             &processed.local_max_points,
             &processed2.local_max_points,
-            0.0015,
+            ALIGN_THRESHOLD,
         );
+        let registration = memory
+            .images
+            .registrations
+            .get(&memory.images.selected_image);
 
-        render_image(frame_buffer, width, height, &processed.raw);
-        render_image_with_alpha(frame_buffer, width, height, &processed2.raw, 0.5);
+        // both frames as they look in the raw view
+        let linked = memory.linked_stretch;
+        if memory.auto_stretch {
+            let image = memory
+                .stf_cache
+                .get((View::Raw, key, linked), &memory.images.raw[key]);
+            render_image(frame_buffer, width, height, image);
+            let image = memory.stf_cache.get(
+                (View::Raw, reference, linked),
+                &memory.images.raw[reference],
+            );
+            render_image_with_alpha(frame_buffer, width, height, image, 0.5);
+        } else {
+            render_image(frame_buffer, width, height, &processed.raw);
+            render_image_with_alpha(frame_buffer, width, height, &processed2.raw, 0.5);
+        }
 
         for (t1, t2) in triangles.iter() {
             render_triangle(
@@ -53,8 +110,164 @@ pub fn render(frame_buffer: &mut [Srgb], width: usize, height: usize, memory: &M
                 Srgb::from_rgb(0, 255, 0),
             );
         }
+
+        // residual vectors, exaggerated so that sub-pixel errors are visible
+        if let Some(registration) = registration {
+            let exaggeration = 50.0;
+            for (i, j) in registration.pairs.iter() {
+                let (x, y, _) = processed.local_max_points[*i];
+                let (x, y) = registration.transform.apply(x, y);
+                let (rx, ry, _) = processed2.local_max_points[*j];
+                render_line(
+                    frame_buffer,
+                    width,
+                    height,
+                    &processed2.log,
+                    (rx, ry),
+                    (rx + (x - rx) * exaggeration, ry + (y - ry) * exaggeration),
+                    Srgb::from_rgb(255, 255, 0),
+                );
+            }
+        }
     } else {
-        render_image(frame_buffer, width, height, selected_image);
+        match linear.filter(|_| memory.auto_stretch) {
+            Some((view, channels)) => {
+                let linked = memory.linked_stretch;
+                let image = memory.stf_cache.get((view, key, linked), channels);
+                render_image(frame_buffer, width, height, image);
+            }
+            None => render_image(frame_buffer, width, height, selected_image),
+        }
+    }
+
+    if matches!(memory.view, View::Stack | View::Background)
+        && let Some(extraction) = &memory.images.extraction
+    {
+        for sample in extraction.samples.iter() {
+            let color = if sample.rejected {
+                Srgb::from_rgb(255, 0, 0)
+            } else {
+                Srgb::from_rgb(0, 255, 0)
+            };
+            render_cross(
+                frame_buffer,
+                width,
+                height,
+                selected_image,
+                (sample.x, sample.y),
+                color,
+            );
+        }
+    }
+
+    if matches!(memory.view, View::Raw)
+        && let Some(processed) = memory.images.processed.get(&key)
+    {
+        // structure that stays in place in the sky is not masked
+        for (trail, masked) in processed.trails.iter().zip(processed.masked.iter()) {
+            let color = if *masked {
+                Srgb::from_rgb(255, 0, 255)
+            } else {
+                Srgb::from_rgb(128, 128, 128)
+            };
+            render_line(
+                frame_buffer,
+                width,
+                height,
+                selected_image,
+                (trail.x1, trail.y1),
+                (trail.x2, trail.y2),
+                color,
+            );
+        }
+    }
+
+    // the moving object, where it was marked or interpolated to
+    if matches!(memory.view, View::Raw) && !memory.images.comet.marks.is_empty() {
+        let comet = &memory.images.comet;
+        let position = match comet.marks.iter().find(|m| m.frame == key) {
+            Some(mark) => Some((mark.x, mark.y)),
+            None => comet::positions(&memory.images, comet)
+                .ok()
+                .and_then(|positions| positions.get(&key).copied())
+                .and_then(|p| {
+                    let registration = memory.images.registrations.get(&key)?;
+                    let offset = memory.images.processed[&key].log_offset();
+                    Some(registration.transform.offset(offset).invert(p.0, p.1))
+                }),
+        };
+        if let Some(position) = position {
+            render_cross(
+                frame_buffer,
+                width,
+                height,
+                selected_image,
+                position,
+                Srgb::from_rgb(0, 255, 255),
+            );
+        }
+    }
+
+    // photometry target and comparison stars
+    if matches!(memory.view, View::Raw)
+        && let Some(registration) = memory.images.registrations.get(&key)
+    {
+        let offset = memory.images.processed[&key].log_offset();
+        let transform = registration.transform.offset(offset);
+        for (i, (x, y)) in memory.images.photometry.stars.iter().enumerate() {
+            let color = if i == 0 {
+                Srgb::from_rgb(255, 160, 0)
+            } else {
+                Srgb::from_rgb(0, 255, 0)
+            };
+            render_cross(
+                frame_buffer,
+                width,
+                height,
+                selected_image,
+                transform.invert(*x, *y),
+                color,
+            );
+        }
+    }
+
+    if memory.grid
+        && let Some((wcs, image)) = memory.view_wcs()
+    {
+        let color = Srgb::from_rgb(80, 160, 255);
+        render_grid(frame_buffer, width, height, image, wcs, color);
+
+        // coordinates under the cursor, next to it
+        let (cx, cy) = memory.cursor;
+        let (x, y) = screen_to_image(width, height, image, cx, cy);
+        if (0.0..image.width as f32).contains(&x) && (0.0..image.height as f32).contains(&y) {
+            let (ra, dec) = wcs.pixel_to_sky(x as f64, y as f64);
+            let position = (cx as usize + 12, cy as usize + 12);
+            render_text(
+                frame_buffer,
+                width,
+                height,
+                position,
+                &wcs::sexagesimal(ra, dec),
+                color,
+            );
+        }
+    }
+
+    if memory
+        .images
+        .grades
+        .grades
+        .get(key)
+        .is_some_and(|g| g.rejected())
+    {
+        render_outline(
+            frame_buffer,
+            width,
+            height,
+            selected_image,
+            Srgb::from_rgb(255, 0, 0),
+        );
     }
 
     // if (1.0 - memory.alpha).abs() > f32::EPSILON {
@@ -127,7 +340,6 @@ fn render_image(frame_buffer: &mut [Srgb], width: usize, height: usize, image: &
     );
 }
 
-#[allow(unused)]
 fn render_image_with_alpha(
     frame_buffer: &mut [Srgb],
     width: usize,
@@ -165,7 +377,6 @@ fn render_image_with_alpha(
     );
 }
 
-#[allow(unused)]
 #[derive(Clone, Copy)]
 struct AlphaTextureShader<'a> {
     shader: rast::TextureShader<'a, Srgb>,
@@ -212,19 +423,9 @@ fn render_triangle(
     let (p2x, p2y, _) = local_max_points[triangle.point_indices[1]];
     let (p3x, p3y, _) = local_max_points[triangle.point_indices[2]];
 
-    let (xmin, ymin, xmax, ymax) = image_bounding_box(width, height, image);
-    let xrange = xmax - xmin;
-    let yrange = ymax - ymin;
-
-    let w = image.width as f32;
-    let h = image.height as f32;
-
-    let p1x = p1x / w * xrange + xmin;
-    let p1y = p1y / h * yrange + ymin;
-    let p2x = p2x / w * xrange + xmin;
-    let p2y = p2y / h * yrange + ymin;
-    let p3x = p3x / w * xrange + xmin;
-    let p3y = p3y / h * yrange + ymin;
+    let (p1x, p1y) = image_to_screen(width, height, image, p1x, p1y);
+    let (p2x, p2y) = image_to_screen(width, height, image, p2x, p2y);
+    let (p3x, p3y) = image_to_screen(width, height, image, p3x, p3y);
 
     rast::rast_triangle_wireframe(
         frame_buffer,
@@ -239,3 +440,254 @@ fn render_triangle(
         color,
     );
 }
+
+fn render_line(
+    frame_buffer: &mut [Srgb],
+    width: usize,
+    height: usize,
+    image: &Image<Srgb>,
+    p1: (f32, f32),
+    p2: (f32, f32),
+    color: Srgb,
+) {
+    let (p1x, p1y) = image_to_screen(width, height, image, p1.0, p1.1);
+    let (p2x, p2y) = image_to_screen(width, height, image, p2.0, p2.1);
+    // degenerate triangle
+    rast::rast_triangle_wireframe(
+        frame_buffer,
+        width,
+        height,
+        p1x as i32,
+        p1y as i32,
+        p2x as i32,
+        p2y as i32,
+        p2x as i32,
+        p2y as i32,
+        color,
+    );
+}
+
+fn image_to_screen(width: usize, height: usize, image: &Image<Srgb>, x: f32, y: f32) -> (f32, f32) {
+    let (xmin, ymin, xmax, ymax) = image_bounding_box(width, height, image);
+    let xrange = xmax - xmin;
+    let yrange = ymax - ymin;
+    (
+        x / image.width as f32 * xrange + xmin,
+        y / image.height as f32 * yrange + ymin,
+    )
+}
+
+/// Inverse of [`image_to_screen`].
+pub fn screen_to_image(
+    width: usize,
+    height: usize,
+    image: &Image<Srgb>,
+    x: f32,
+    y: f32,
+) -> (f32, f32) {
+    let (xmin, ymin, xmax, ymax) = image_bounding_box(width, height, image);
+    (
+        (x - xmin) / (xmax - xmin) * image.width as f32,
+        (y - ymin) / (ymax - ymin) * image.height as f32,
+    )
+}
+
+fn render_outline(
+    frame_buffer: &mut [Srgb],
+    width: usize,
+    height: usize,
+    image: &Image<Srgb>,
+    color: Srgb,
+) {
+    let w = image.width as f32 - 1.0;
+    let h = image.height as f32 - 1.0;
+    let corners = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)];
+    for i in 0..corners.len() {
+        let p1 = corners[i];
+        let p2 = corners[(i + 1) % corners.len()];
+        render_line(frame_buffer, width, height, image, p1, p2, color);
+    }
+}
+
+// Lines of constant ra and dec, spaced so that a few of each cross the image.
+fn render_grid(
+    frame_buffer: &mut [Srgb],
+    width: usize,
+    height: usize,
+    image: &Image<Srgb>,
+    wcs: &Wcs,
+    color: Srgb,
+) {
+    let (w, h) = (image.width as f64, image.height as f64);
+    let inside = |(x, y): (f64, f64)| (0.0..w).contains(&x) && (0.0..h).contains(&y);
+
+    // sky covered by the border, ra unwrapped around the center
+    let center = wcs.pixel_to_sky(w / 2.0, h / 2.0);
+    let steps = 32;
+    let (mut ra_min, mut ra_max) = (f64::MAX, f64::MIN);
+    let (mut dec_min, mut dec_max) = (f64::MAX, f64::MIN);
+    for i in 0..steps {
+        let t = i as f64 / steps as f64;
+        for (x, y) in [
+            (t * w, 0.0),
+            (w, t * h),
+            ((1.0 - t) * w, h),
+            (0.0, (1.0 - t) * h),
+        ] {
+            let (ra, dec) = wcs.pixel_to_sky(x, y);
+            let ra = center.0 + (ra - center.0 + 180.0).rem_euclid(360.0) - 180.0;
+            (ra_min, ra_max) = (ra_min.min(ra), ra_max.max(ra));
+            (dec_min, dec_max) = (dec_min.min(dec), dec_max.max(dec));
+        }
+    }
+    // every ra meets at a pole inside the image
+    for pole in [-90.0, 90.0] {
+        if wcs.sky_to_pixel(0.0, pole).is_some_and(inside) {
+            (ra_min, ra_max) = (center.0 - 180.0, center.0 + 180.0);
+            (dec_min, dec_max) = (dec_min.min(pole), dec_max.max(pole));
+        }
+    }
+
+    let lines = 5.0;
+    let segments = 64;
+    let mut render_curve = |sky: &dyn Fn(f64) -> (f64, f64)| {
+        let points: Vec<_> = (0..=segments)
+            .map(|i| {
+                let (ra, dec) = sky(i as f64 / segments as f64);
+                wcs.sky_to_pixel(ra, dec).filter(|p| inside(*p))
+            })
+            .collect();
+        for pair in points.windows(2) {
+            if let [Some(p1), Some(p2)] = pair {
+                render_line(
+                    frame_buffer,
+                    width,
+                    height,
+                    image,
+                    (p1.0 as f32, p1.1 as f32),
+                    (p2.0 as f32, p2.1 as f32),
+                    color,
+                );
+            }
+        }
+    };
+    let step = grid_step((dec_max - dec_min) / lines);
+    let mut dec = (dec_min / step).ceil() * step;
+    while dec <= dec_max {
+        render_curve(&|t| (ra_min + t * (ra_max - ra_min), dec));
+        dec += step;
+    }
+    let step = grid_step((ra_max - ra_min) / lines);
+    let mut ra = (ra_min / step).ceil() * step;
+    while ra <= ra_max {
+        render_curve(&|t| (ra, dec_min + t * (dec_max - dec_min)));
+        ra += step;
+    }
+}
+
+// Smallest round number of degrees, minutes or seconds of at least `range`.
+fn grid_step(range: f64) -> f64 {
+    let steps = [1.0, 2.0, 5.0, 10.0, 15.0, 30.0];
+    steps
+        .iter()
+        .map(|s| s / 3600.0)
+        .chain(steps.iter().map(|s| s / 60.0))
+        .chain(steps.iter().copied())
+        .chain([45.0, 90.0])
+        .find(|s| *s >= range)
+        .unwrap_or(90.0)
+}
+
+// 3 x 5 pixel glyphs of the characters in coordinates, a row per byte with the
+// leftmost pixel in the highest of 3 bits.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
+
+// Text at `position` in screen pixels over a dark box, moved left and up to stay on
+// screen.
+fn render_text(
+    frame_buffer: &mut [Srgb],
+    width: usize,
+    height: usize,
+    position: (usize, usize),
+    text: &str,
+    color: Srgb,
+) {
+    let scale = 2;
+    let padding = 2;
+    let advance = 4 * scale;
+    let box_width = text.chars().count() * advance + 2 * padding;
+    let box_height = 5 * scale + 2 * padding;
+    let x0 = position.0.min(width.saturating_sub(box_width));
+    let y0 = position.1.min(height.saturating_sub(box_height));
+    for y in y0..(y0 + box_height).min(height) {
+        for x in x0..(x0 + box_width).min(width) {
+            frame_buffer[y * width + x] = Srgb::from_rgb(0, 0, 0);
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for (dx, dy) in (0..scale).flat_map(|dx| (0..scale).map(move |dy| (dx, dy))) {
+                    let x = x0 + padding + i * advance + column * scale + dx;
+                    let y = y0 + padding + row * scale + dy;
+                    if x < width && y < height {
+                        frame_buffer[y * width + x] = color;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn render_cross(
+    frame_buffer: &mut [Srgb],
+    width: usize,
+    height: usize,
+    image: &Image<Srgb>,
+    center: (f32, f32),
+    color: Srgb,
+) {
+    // constant size on screen
+    let (xmin, _, xmax, _) = image_bounding_box(width, height, image);
+    let size = 4.0 * image.width as f32 / (xmax - xmin);
+    let (x, y) = center;
+    render_line(
+        frame_buffer,
+        width,
+        height,
+        image,
+        (x - size, y),
+        (x + size, y),
+        color,
+    );
+    render_line(
+        frame_buffer,
+        width,
+        height,
+        image,
+        (x, y - size),
+        (x, y + size),
+        color,
+    );
+}
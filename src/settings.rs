@@ -0,0 +1,246 @@
+use crate::grade::{Metric, Rule};
+use crate::image::ImageMemory;
+use crate::weight::Weighting;
+use crate::{background, denoise, drizzle, starless, stretch};
+
+/// Options without a key, read from `key = value` lines. Blank lines and `#`
+/// comments are skipped. Keys that may repeat accumulate in order.
+///
+/// ```text
+/// # rejected frames are excluded from stacking
+/// reject = fwhm above 1.5
+/// reject = stars below 0.5
+/// reject = snr best 0.9
+/// # equal, inverse variance, snr, psf signal or an expression
+/// weighting = snr / (fwhm * fwhm)
+/// # background extraction
+/// background = rbf 0.5
+/// correction = divide
+/// # noise reduction of the stack
+/// denoise = nlm 1.0 2 7
+/// # filling the holes left by removed stars
+/// inpainting = patch 3 20
+/// # arcseconds per hour in ra times cos dec and dec, from an ephemeris
+/// comet rate = 35.2 -12.8
+/// # output scale and drop size
+/// drizzle = 2 0.7
+/// # permanent stretch of the stack
+/// stretch = ghs 5 2 0.1 0 0.9
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Settings {
+    /// Replace the grading rules if any are given.
+    pub rejections: Vec<Rule>,
+    pub weighting: Option<Weighting>,
+    pub background: Option<background::Model>,
+    pub correction: Option<background::Correction>,
+    pub denoise: Option<denoise::Method>,
+    pub inpainting: Option<starless::Inpainting>,
+    pub comet_rate: Option<(f32, f32)>,
+    pub drizzle: Option<drizzle::Drizzle>,
+    pub stretch: Option<stretch::Stretch>,
+}
+
+impl Settings {
+    pub fn read(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        Self::parse(&text).map_err(|err| format!("{path}:{err}"))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut settings = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{}: expected key = value", i + 1))?;
+            let value = value.trim();
+            let result = match key.trim() {
+                "reject" => rule(value).map(|rule| settings.rejections.push(rule)),
+                "weighting" => Weighting::parse(value).map(|w| settings.weighting = Some(w)),
+                "background" => {
+                    background::Model::parse(value).map(|m| settings.background = Some(m))
+                }
+                "correction" => {
+                    background::Correction::parse(value).map(|c| settings.correction = Some(c))
+                }
+                "denoise" => denoise::Method::parse(value).map(|m| settings.denoise = Some(m)),
+                "inpainting" => {
+                    starless::Inpainting::parse(value).map(|i| settings.inpainting = Some(i))
+                }
+                "comet rate" => rate(value).map(|r| settings.comet_rate = Some(r)),
+                "drizzle" => drizzle::Drizzle::parse(value).map(|d| settings.drizzle = Some(d)),
+                "stretch" => stretch::Stretch::parse(value).map(|s| settings.stretch = Some(s)),
+                key => Err(format!("unknown key {key}")),
+            };
+            result.map_err(|err| format!("{}: {err}", i + 1))?;
+        }
+        Ok(settings)
+    }
+
+    pub fn apply(self, images: &mut ImageMemory) {
+        if !self.rejections.is_empty() {
+            images.grading_rules = self.rejections;
+            images.regrade();
+        }
+        if let Some(weighting) = self.weighting {
+            images.weighting = weighting;
+        }
+        if let Some(model) = self.background {
+            images.background.model = model;
+        }
+        if let Some(correction) = self.correction {
+            images.background.correction = correction;
+        }
+        if let Some(method) = self.denoise {
+            images.noise_reduction.method = method;
+        }
+        if let Some(inpainting) = self.inpainting {
+            images.starless.inpainting = inpainting;
+        }
+        if let Some(rate) = self.comet_rate {
+            images.comet.rate = Some(rate);
+        }
+        if let Some(drizzle) = self.drizzle {
+            images.drizzle = drizzle;
+        }
+        if let Some(stretch) = self.stretch {
+            images.stretch = stretch;
+        }
+    }
+}
+
+// `metric above factor`, `metric below factor` or `metric best fraction`, with
+// the metric named as in the grading report.
+fn rule(value: &str) -> Result<Rule, String> {
+    let words: Vec<_> = value.split_whitespace().collect();
+    let [metric, kind, number] = words[..] else {
+        return Err(format!(
+            "expected metric above|below|best number, got {value}"
+        ));
+    };
+    let metric = Metric::ALL
+        .into_iter()
+        .find(|m| m.name() == metric)
+        .ok_or_else(|| format!("unknown metric {metric}"))?;
+    let number: f32 = number
+        .parse()
+        .map_err(|_| format!("{number} is not a number"))?;
+    match kind {
+        "above" => Ok(Rule::AboveMedian {
+            metric,
+            factor: number,
+        }),
+        "below" => Ok(Rule::BelowMedian {
+            metric,
+            factor: number,
+        }),
+        "best" => Ok(Rule::KeepBest {
+            metric,
+            fraction: number,
+        }),
+        _ => Err(format!("expected above, below or best, got {kind}")),
+    }
+}
+
+// `ra dec` in arcseconds per hour.
+fn rate(value: &str) -> Result<(f32, f32), String> {
+    let numbers: Vec<_> = value.split_whitespace().map(str::parse::<f32>).collect();
+    match numbers[..] {
+        [Ok(ra), Ok(dec)] => Ok((ra, dec)),
+        _ => Err(format!("expected ra and dec rates, got {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejections() {
+        let settings = Settings::parse(
+            "# comment\n\nreject = fwhm above 1.5\nreject = stars below 0.5 # few stars\nreject=snr best 0.9\nweighting = snr / fwhm\nbackground = rbf 0.5\ncorrection = divide\ndenoise = nlm 1.5 2 7\ninpainting = patch 3 20\ncomet rate = 35.2 -12.8\ndrizzle = 2 0.7\nstretch = arcsinh 0.01 50\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.rejections,
+            [
+                Rule::AboveMedian {
+                    metric: Metric::Fwhm,
+                    factor: 1.5
+                },
+                Rule::BelowMedian {
+                    metric: Metric::StarCount,
+                    factor: 0.5
+                },
+                Rule::KeepBest {
+                    metric: Metric::Snr,
+                    fraction: 0.9
+                },
+            ]
+        );
+        assert_eq!(
+            settings.weighting,
+            Some(Weighting::Expression("snr / fwhm".to_string()))
+        );
+        assert_eq!(
+            settings.background,
+            Some(background::Model::Rbf { smoothing: 0.5 })
+        );
+        assert_eq!(settings.correction, Some(background::Correction::Divide));
+        assert_eq!(
+            settings.denoise,
+            Some(denoise::Method::NonLocalMeans {
+                strength: 1.5,
+                patch: 2,
+                search: 7
+            })
+        );
+        assert_eq!(
+            settings.inpainting,
+            Some(starless::Inpainting::Patch {
+                patch: 3,
+                search: 20
+            })
+        );
+        assert_eq!(settings.comet_rate, Some((35.2, -12.8)));
+        assert_eq!(
+            settings.drizzle,
+            Some(drizzle::Drizzle {
+                scale: 2.0,
+                pixfrac: 0.7
+            })
+        );
+        assert_eq!(
+            settings.stretch,
+            Some(stretch::Stretch::Arcsinh {
+                black_point: 0.01,
+                factor: 50.0
+            })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Settings::parse("reject fwhm above 1.5").is_err());
+        assert!(Settings::parse("reject = fwhm over 1.5").is_err());
+        assert!(Settings::parse("reject = size above 1.5").is_err());
+        assert!(Settings::parse("reject = fwhm above wide").is_err());
+        assert!(Settings::parse("rejects = fwhm above 1.5").is_err());
+        assert!(Settings::parse("weighting = snr *").is_err());
+        assert!(Settings::parse("background = spline 2").is_err());
+        assert!(Settings::parse("background = polynomial two").is_err());
+        assert!(Settings::parse("correction = multiply").is_err());
+        assert!(Settings::parse("denoise = nlm 1.0 2 50").is_err());
+        assert!(Settings::parse("denoise = wavelet 4").is_err());
+        assert!(Settings::parse("inpainting = patch 3").is_err());
+        assert!(Settings::parse("comet rate = 35.2").is_err());
+        assert!(Settings::parse("drizzle = 0 0.7").is_err());
+        assert!(Settings::parse("drizzle = 2 1.5").is_err());
+        assert!(Settings::parse("drizzle = 2 NaN").is_err());
+        assert!(Settings::parse("stretch = ghs 5 2 0.1 0.2 0.9").is_err());
+    }
+}
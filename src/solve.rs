@@ -0,0 +1,293 @@
+// https://arxiv.org/abs/0910.2233
+//
+// Blind astrometry in the spirit of astrometry.net, with the triangles of
+// `align` as the geometric hash.
+
+use crate::align::{self, Triangle};
+use crate::catalog::{self, Entry};
+use crate::metrics::Star;
+use crate::wcs::{Sip, Wcs};
+use crate::{ALIGN_THRESHOLD, linalg};
+use std::collections::HashMap;
+
+/// Order of the distortion fitted to solutions with many matched stars.
+const SIP_ORDER: usize = 2;
+
+/// Triangles of the brightest catalog stars around points covering the sky.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Index {
+    /// Radius of each tile in degrees, at least the radius of a solved field.
+    pub radius: f64,
+    pub tiles: Vec<Tile>,
+    /// Sorted by the first edge ratio.
+    pub triangles: Vec<IndexTriangle>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Tile {
+    pub center: (f64, f64),
+    /// `(ra, dec)` brightest first.
+    pub stars: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct IndexTriangle {
+    /// Ratios of the sorted edge lengths, invariant to the projection scale.
+    pub ratios: [f32; 2],
+    pub tile: u32,
+    /// Tile stars opposite each sorted edge.
+    pub stars: [u16; 3],
+}
+
+impl Index {
+    /// Tiles of `radius` degrees spaced so that every field within `radius / 2`
+    /// of the catalog is covered by one, each with its `stars` brightest stars.
+    /// The index grows with the cube of `stars`.
+    pub fn build(catalog: &[Entry], radius: f64, stars: usize) -> Self {
+        assert!(stars <= u16::MAX as usize);
+        let spacing = radius / 2.0;
+        let mut tiles = Vec::new();
+        let mut triangles = Vec::new();
+        let mut dec: f64 = -90.0;
+        while dec <= 90.0 {
+            let ra_spacing = spacing / dec.to_radians().cos().max(spacing / 360.0);
+            let band: Vec<_> = catalog
+                .iter()
+                .map(|e| (e.ra, e.dec))
+                .filter(|(_, d)| (d - dec).abs() <= radius)
+                .collect();
+            let mut ra = 0.0;
+            while ra < 360.0 {
+                let center = (ra, dec);
+                ra += ra_spacing;
+                // the catalog is sorted brightest first
+                let tile_stars: Vec<_> = band
+                    .iter()
+                    .copied()
+                    .filter(|p| catalog::separation(center, *p) <= radius)
+                    .take(stars)
+                    .collect();
+                if tile_stars.len() < 3 {
+                    continue;
+                }
+
+                let tile = tiles.len() as u32;
+                let points = project(center, &tile_stars, 1.0);
+                for triangle in align::generate_all_triangles(1, 1, &points, stars) {
+                    let [a, b, c] = triangle.edge_lengths;
+                    triangles.push(IndexTriangle {
+                        ratios: [a / b, b / c],
+                        tile,
+                        stars: triangle.point_indices.map(|i| i as u16),
+                    });
+                }
+                tiles.push(Tile {
+                    center,
+                    stars: tile_stars,
+                });
+            }
+            dec += spacing;
+        }
+        triangles.sort_by(|a, b| a.ratios[0].total_cmp(&b.ratios[0]));
+        Self {
+            radius,
+            tiles,
+            triangles,
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+        bincode::serde::decode_from_slice(&bytes, bincode::config::standard())
+            .map(|(index, _)| index)
+            .map_err(|err| format!("{path}: {err}"))
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let bytes = bincode::serde::encode_to_vec(self, bincode::config::standard())
+            .map_err(|err| err.to_string())?;
+        std::fs::write(path, bytes).map_err(|err| format!("{path}: {err}"))
+    }
+}
+
+/// Solves a `width` by `height` frame from its detected stars, trying the tiles
+/// with the most matching triangles first.
+pub fn solve(index: &Index, width: usize, height: usize, stars: &[Star]) -> Option<Wcs> {
+    let mut order: Vec<_> = (0..stars.len()).collect();
+    order.sort_by(|a, b| stars[*b].flux.total_cmp(&stars[*a].flux));
+    // equal luminance so that only geometry is matched
+    let detections: Vec<_> = order
+        .iter()
+        .map(|i| (stars[*i].x, stars[*i].y, 1.0))
+        .collect();
+
+    // frame triangles paired with index triangles of the same shape, per tile
+    let size = width.max(height);
+    let take = 30;
+    let mut matches: HashMap<u32, Vec<(Triangle, Triangle)>> = HashMap::new();
+    for triangle in align::generate_all_triangles(size, size, &detections, take) {
+        let [a, b, c] = triangle.edge_lengths;
+        let ratios = [a / b, b / c];
+        let start = index
+            .triangles
+            .partition_point(|t| t.ratios[0] < ratios[0] - ALIGN_THRESHOLD);
+        for candidate in index.triangles[start..]
+            .iter()
+            .take_while(|t| t.ratios[0] <= ratios[0] + ALIGN_THRESHOLD)
+            .filter(|t| (t.ratios[1] - ratios[1]).abs() < ALIGN_THRESHOLD)
+        {
+            let tile_triangle = Triangle {
+                point_indices: candidate.stars.map(|s| s as usize),
+                ..triangle
+            };
+            matches
+                .entry(candidate.tile)
+                .or_default()
+                .push((tile_triangle, triangle));
+        }
+    }
+    let mut candidates: Vec<_> = matches.into_iter().collect();
+    candidates.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+
+    let max_tiles = 10;
+    candidates
+        .into_iter()
+        .take(max_tiles)
+        .find_map(|(tile, triangles)| {
+            verify(
+                &index.tiles[tile as usize],
+                &detections,
+                &align::correspondences(&triangles),
+                width,
+                height,
+            )
+        })
+}
+
+// Fits the matched tile stars in both parities, accepting a solution that many
+// stars agree with.
+fn verify(
+    tile: &Tile,
+    detections: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+    width: usize,
+    height: usize,
+) -> Option<Wcs> {
+    let min_stars = 6;
+    let max_rms = 2.0;
+    let radius = 3.0;
+    [1.0, -1.0]
+        .into_iter()
+        .filter_map(|parity| {
+            let points = project(tile.center, &tile.stars, parity);
+            let pairs = align::consensus(&points, detections, pairs);
+            let fit = align::fit_transform(&points, detections, &pairs, 1)?;
+            let pairs = align::match_points(&points, detections, &fit.transform, radius);
+            let fit = align::fit_transform(&points, detections, &pairs, 1)?;
+
+            // recenter on the frame, the projection is most accurate near its center
+            let inverse = fit.transform.similarity.inverse();
+            let (u, v) = inverse.apply(width as f32 / 2.0, height as f32 / 2.0);
+            let center = catalog::unproject(tile.center, parity * u as f64, v as f64);
+            let points = project(center, &tile.stars, parity);
+            let fit = align::fit_transform(&points, detections, &fit.pairs, 1)?;
+            (fit.pairs.len() >= min_stars && fit.residuals.rms <= max_rms)
+                .then_some((fit, center, parity))
+        })
+        .max_by_key(|(fit, _, _)| fit.pairs.len())
+        .map(|(fit, center, parity)| {
+            let similarity = fit.transform.similarity;
+            let inverse = similarity.inverse();
+            let (a, b) = (inverse.a as f64, inverse.b as f64);
+            let wcs = Wcs {
+                crval: center,
+                crpix: (similarity.tx as f64, similarity.ty as f64),
+                cd: [[parity * a, -parity * b], [b, a]],
+                sip: None,
+            };
+            fit_distortion(&wcs, &tile.stars, detections, &fit.pairs).unwrap_or(wcs)
+        })
+}
+
+// Refits the solution as a general linear map plus distortion around the same
+// reference pixel, when enough stars constrain it.
+fn fit_distortion(
+    wcs: &Wcs,
+    stars: &[(f64, f64)],
+    detections: &[(f32, f32, f32)],
+    pairs: &[(usize, usize)],
+) -> Option<Wcs> {
+    let terms = Sip::terms(SIP_ORDER);
+    let min_stars = 3 * (terms.len() + 3);
+    if pairs.len() < min_stars {
+        return None;
+    }
+
+    // standard coordinates as polynomials of the pixel offsets, moving the
+    // reference point onto the constant term until it vanishes
+    let rows: Vec<Vec<f64>> = pairs
+        .iter()
+        .map(|(_, j)| {
+            let x = detections[*j].0 as f64 - wcs.crpix.0;
+            let y = detections[*j].1 as f64 - wcs.crpix.1;
+            [1.0, x, y]
+                .into_iter()
+                .chain(
+                    terms
+                        .iter()
+                        .map(|(p, q)| x.powi(*p as i32) * y.powi(*q as i32)),
+                )
+                .collect()
+        })
+        .collect();
+    let mut crval = wcs.crval;
+    let fit = |crval: (f64, f64)| {
+        let (xi, eta): (Vec<_>, Vec<_>) = pairs
+            .iter()
+            .map(|(i, _)| catalog::project(crval, stars[*i].0, stars[*i].1))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        Some((
+            linalg::least_squares(&rows, &xi)?,
+            linalg::least_squares(&rows, &eta)?,
+        ))
+    };
+    let iterations = 3;
+    for _ in 0..iterations {
+        let (xi, eta) = fit(crval)?;
+        crval = catalog::unproject(crval, xi[0], eta[0]);
+    }
+    let (xi, eta) = fit(crval)?;
+
+    // cd (u + a(u, v), v + b(u, v)) reproduces the polynomials
+    let cd = [[xi[1], xi[2]], [eta[1], eta[2]]];
+    let det = cd[0][0] * cd[1][1] - cd[0][1] * cd[1][0];
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    for (k, (p, q)) in terms.iter().enumerate() {
+        let (x, e) = (xi[k + 3], eta[k + 3]);
+        a.push((*p, *q, (cd[1][1] * x - cd[0][1] * e) / det));
+        b.push((*p, *q, (cd[0][0] * e - cd[1][0] * x) / det));
+    }
+    Some(Wcs {
+        crval,
+        crpix: wcs.crpix,
+        cd,
+        sip: Some(Sip {
+            order: SIP_ORDER,
+            a,
+            b,
+        }),
+    })
+}
+
+// Standard coordinates in degrees, with `xi` flipped by a negative `parity`.
+fn project(center: (f64, f64), stars: &[(f64, f64)], parity: f64) -> Vec<(f32, f32, f32)> {
+    stars
+        .iter()
+        .map(|(ra, dec)| {
+            let (xi, eta) = catalog::project(center, *ra, *dec).unwrap_or((f64::NAN, f64::NAN));
+            ((parity * xi) as f32, eta as f32, 1.0)
+        })
+        .collect()
+}
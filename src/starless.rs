@@ -0,0 +1,293 @@
+use crate::image::{self, Image};
+use crate::metrics::Star;
+use crate::starmask::{self, StarMask};
+use tint::Srgb;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Inpainting {
+    // https://en.wikipedia.org/wiki/Inpainting#Partial_differential_equations
+    //
+    /// Fills holes from their edges inwards, then smooths them with `iterations`
+    /// of heat diffusion.
+    Diffusion { iterations: usize },
+    // https://www.irisa.fr/vista/Papers/2004_ip_criminisi.pdf
+    //
+    /// Copies the pixel whose surrounding `patch` radius best matches the known
+    /// pixels around the hole, from within a `search` radius.
+    Patch { patch: usize, search: usize },
+}
+
+impl Inpainting {
+    /// `diffusion <iterations>` or `patch <patch> <search>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let words: Vec<_> = value.split_whitespace().collect();
+        let count = |word: &str| {
+            word.parse::<usize>()
+                .map_err(|_| format!("{word} is not a count"))
+        };
+        match words[..] {
+            ["diffusion", iterations] => Ok(Self::Diffusion {
+                iterations: count(iterations)?,
+            }),
+            ["patch", patch, search] => Ok(Self::Patch {
+                patch: count(patch)?,
+                search: count(search)?,
+            }),
+            _ => Err(format!(
+                "expected diffusion <iterations> or patch <patch> <search>, got {value}"
+            )),
+        }
+    }
+}
+
+/// How the stars are added back onto the starless image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Blend {
+    /// Exact inverse of the separation, for linear data.
+    Add,
+    // https://en.wikipedia.org/wiki/Blend_modes#Screen
+    //
+    /// Never clips, for stretched data.
+    Screen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Starless {
+    pub mask: StarMask,
+    pub inpainting: Inpainting,
+    pub blend: Blend,
+}
+
+impl std::fmt::Display for Starless {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} inpainting, mask radius {} grow {}",
+            self.inpainting, self.mask.radius, self.mask.grow
+        )
+    }
+}
+
+/// Stars removed from an image.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Stars {
+    pub channels: [Image<f32>; 3],
+    pub preview: Image<Srgb>,
+}
+
+/// Replaces the stars of `channels` with inpainted background, returning the stars.
+/// `stars` are in pixels of a frame `star_scale` times smaller than `channels`.
+pub fn separate(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    starless: Starless,
+) -> Stars {
+    let (width, height) = (channels[0].width, channels[0].height);
+    // the holes need hard edges, feathering would leave star light at the border
+    let mask = starmask::mask(
+        width,
+        height,
+        stars,
+        star_scale,
+        StarMask {
+            feather: 0.0,
+            ..starless.mask
+        },
+    );
+    let hole: Vec<bool> = mask.pixels.iter().map(|m| *m > 0.5).collect();
+
+    let original = channels.clone();
+    match starless.inpainting {
+        Inpainting::Diffusion { iterations } => diffusion(channels, &hole, iterations),
+        Inpainting::Patch { patch, search } => patch_match(channels, &hole, patch, search),
+    }
+
+    let residual: [Image<f32>; 3] = std::array::from_fn(|c| Image {
+        pixels: original[c]
+            .pixels
+            .iter()
+            .zip(channels[c].pixels.iter())
+            .map(|(o, s)| o - s)
+            .collect(),
+        width,
+        height,
+    });
+    Stars {
+        preview: image::channels_to_srgb(&residual),
+        channels: residual,
+    }
+}
+
+/// Adds `stars` back onto `channels`, which must be the size they were separated at.
+pub fn recombine(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Image<f32>; 3],
+    blend: Blend,
+) -> Result<(), String> {
+    let size = |c: &Image<f32>| (c.width, c.height);
+    if size(&channels[0]) != size(&stars[0]) {
+        return Err(format!(
+            "the stars are {:?} pixels, the image {:?}",
+            size(&stars[0]),
+            size(&channels[0])
+        ));
+    }
+    for (channel, stars) in channels.iter_mut().zip(stars.iter()) {
+        for (v, s) in channel.pixels.iter_mut().zip(stars.pixels.iter()) {
+            *v = match blend {
+                Blend::Add => *v + s,
+                Blend::Screen => 1.0 - (1.0 - *v) * (1.0 - s.max(0.0)),
+            };
+        }
+    }
+    Ok(())
+}
+
+// Hole pixels grouped by their distance from the edge of the hole, so that each
+// layer only depends on known pixels and the layers before it.
+fn layers(hole: &[bool], width: usize, height: usize) -> Vec<Vec<usize>> {
+    let mut known: Vec<bool> = hole.iter().map(|h| !h).collect();
+    let mut remaining: Vec<usize> = (0..hole.len()).filter(|i| hole[*i]).collect();
+    let mut layers = Vec::new();
+    while !remaining.is_empty() {
+        let (layer, rest): (Vec<usize>, Vec<usize>) = remaining
+            .iter()
+            .partition(|i| neighbors(**i, width, height, 1).any(|n| known[n]));
+        if layer.is_empty() {
+            // nothing known to fill from
+            break;
+        }
+        for i in layer.iter() {
+            known[*i] = true;
+        }
+        layers.push(layer);
+        remaining = rest;
+    }
+    layers
+}
+
+fn neighbors(i: usize, width: usize, height: usize, radius: i32) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % width) as i32, (i / width) as i32);
+    (-radius..=radius)
+        .flat_map(move |dy| (-radius..=radius).map(move |dx| (x + dx, y + dy)))
+        .filter(move |(nx, ny)| {
+            (*nx, *ny) != (x, y)
+                && *nx >= 0
+                && *ny >= 0
+                && *nx < width as i32
+                && *ny < height as i32
+        })
+        .map(move |(nx, ny)| ny as usize * width + nx as usize)
+}
+
+fn diffusion(channels: &mut [Image<f32>; 3], hole: &[bool], iterations: usize) {
+    let (width, height) = (channels[0].width, channels[0].height);
+    let layers = layers(hole, width, height);
+    let mut known: Vec<bool> = hole.iter().map(|h| !h).collect();
+    for layer in layers.iter() {
+        for i in layer.iter() {
+            for channel in channels.iter_mut() {
+                let (sum, count) = neighbors(*i, width, height, 1)
+                    .filter(|n| known[*n] && !channel.pixels[*n].is_nan())
+                    .fold((0.0, 0), |(s, c), n| (s + channel.pixels[n], c + 1));
+                if count > 0 {
+                    channel.pixels[*i] = sum / count as f32;
+                }
+            }
+        }
+        for i in layer.iter() {
+            known[*i] = true;
+        }
+    }
+
+    // https://en.wikipedia.org/wiki/Jacobi_method
+    let filled: Vec<usize> = layers.into_iter().flatten().collect();
+    for channel in channels.iter_mut() {
+        for _ in 0..iterations {
+            let next: Vec<f32> = filled
+                .iter()
+                .map(|i| {
+                    let (sum, count) = neighbors(*i, width, height, 1)
+                        .filter(|n| !channel.pixels[*n].is_nan())
+                        .fold((0.0, 0), |(s, c), n| (s + channel.pixels[n], c + 1));
+                    if count > 0 {
+                        sum / count as f32
+                    } else {
+                        channel.pixels[*i]
+                    }
+                })
+                .collect();
+            for (i, v) in filled.iter().zip(next) {
+                channel.pixels[*i] = v;
+            }
+        }
+    }
+}
+
+fn patch_match(channels: &mut [Image<f32>; 3], hole: &[bool], patch: usize, search: usize) {
+    let (width, height) = (channels[0].width, channels[0].height);
+    let (patch, search) = (patch as i32, search as i32);
+    let at = |x: i32, y: i32| {
+        (x >= 0 && y >= 0 && x < width as i32 && y < height as i32)
+            .then(|| y as usize * width + x as usize)
+    };
+
+    let mut known: Vec<bool> = hole.iter().map(|h| !h).collect();
+    for layer in layers(hole, width, height) {
+        for i in layer.iter() {
+            let (x, y) = ((i % width) as i32, (i / width) as i32);
+            let mut best = None;
+            let mut best_distance = f32::MAX;
+            for sy in y - search..=y + search {
+                for sx in x - search..=x + search {
+                    // sources are whole patches of original pixels
+                    let Some(source) = at(sx, sy) else {
+                        continue;
+                    };
+                    if hole[source] {
+                        continue;
+                    }
+                    let mut distance = 0.0;
+                    let mut count = 0;
+                    let mut valid = true;
+                    'patch: for py in -patch..=patch {
+                        for px in -patch..=patch {
+                            let (Some(s), target) = (at(sx + px, sy + py), at(x + px, y + py))
+                            else {
+                                valid = false;
+                                break 'patch;
+                            };
+                            if hole[s] {
+                                valid = false;
+                                break 'patch;
+                            }
+                            let Some(t) = target.filter(|t| known[*t]) else {
+                                continue;
+                            };
+                            for channel in channels.iter() {
+                                let d = channel.pixels[s] - channel.pixels[t];
+                                if !d.is_nan() {
+                                    distance += d * d;
+                                }
+                            }
+                            count += 1;
+                        }
+                    }
+                    if valid && count > 0 && distance / (count as f32) < best_distance {
+                        best_distance = distance / count as f32;
+                        best = Some(source);
+                    }
+                }
+            }
+            if let Some(source) = best {
+                for channel in channels.iter_mut() {
+                    channel.pixels[*i] = channel.pixels[source];
+                }
+            }
+        }
+        for i in layer.iter() {
+            known[*i] = true;
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::image::Image;
+use crate::metrics::Star;
+use crate::{process, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StarMask {
+    /// Radius of a median star in multiples of its FWHM, brighter stars get larger
+    /// radii to cover their halos.
+    pub radius: f32,
+    /// Pixels to grow the mask by.
+    pub grow: usize,
+    /// Sigma of the blur that softens the edges, in pixels.
+    pub feather: f32,
+}
+
+// https://pixinsight.com/doc/tools/MorphologicalTransformation/MorphologicalTransformation.html
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StarReduction {
+    pub mask: StarMask,
+    /// Width of the erosion structuring element in pixels.
+    pub size: usize,
+    pub iterations: usize,
+    /// Blend from the input at 0 to the fully eroded stars at 1.
+    pub amount: f32,
+}
+
+impl std::fmt::Display for StarReduction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "erosion size {} iterations {} amount {}, mask radius {} grow {} feather {}",
+            self.size,
+            self.iterations,
+            self.amount,
+            self.mask.radius,
+            self.mask.grow,
+            self.mask.feather
+        )
+    }
+}
+
+/// Mask from 0 to 1 covering `stars`, which are in pixels of a frame `star_scale`
+/// times smaller than the mask.
+pub fn mask(
+    width: usize,
+    height: usize,
+    stars: &[Star],
+    star_scale: f32,
+    settings: StarMask,
+) -> Image<f32> {
+    let mut fluxes: Vec<_> = stars.iter().map(|s| s.flux).collect();
+    let median_flux = stats::median(&mut fluxes).max(f32::EPSILON);
+
+    let mut mask = Image {
+        pixels: vec![0.0f32; width * height],
+        width,
+        height,
+    };
+    for star in stars.iter() {
+        // halos grow with the magnitude of a star
+        let size = 1.0 + (star.flux / median_flux).log10().max(0.0);
+        let radius = settings.radius * star.fwhm * star_scale * size;
+        let (cx, cy) = (star.x * star_scale, star.y * star_scale);
+        let x0 = (cx - radius).floor().max(0.0) as usize;
+        let y0 = (cy - radius).floor().max(0.0) as usize;
+        let x1 = ((cx + radius).ceil().max(0.0) as usize).min(width - 1);
+        let y1 = ((cy + radius).ceil().max(0.0) as usize).min(height - 1);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                if (x as f32 - cx).hypot(y as f32 - cy) <= radius {
+                    mask.pixels[y * width + x] = 1.0;
+                }
+            }
+        }
+    }
+
+    if settings.grow > 0 {
+        mask = process::dilate(&mask, 2 * settings.grow + 1);
+    }
+    if settings.feather > 0.0 {
+        mask = process::gaussian_blur(&mask, settings.feather);
+    }
+    mask
+}
+
+/// Shrinks stars by eroding `channels` through the star mask.
+pub fn reduce(
+    channels: &mut [Image<f32>; 3],
+    stars: &[Star],
+    star_scale: f32,
+    reduction: StarReduction,
+) -> Image<f32> {
+    let mask = mask(
+        channels[0].width,
+        channels[0].height,
+        stars,
+        star_scale,
+        reduction.mask,
+    );
+    for channel in channels.iter_mut() {
+        let mut eroded = channel.clone();
+        for _ in 0..reduction.iterations {
+            eroded = process::erode(&eroded, reduction.size);
+        }
+        for ((v, e), m) in channel
+            .pixels
+            .iter_mut()
+            .zip(eroded.pixels.iter())
+            .zip(mask.pixels.iter())
+        {
+            if !e.is_nan() {
+                *v += reduction.amount * m * (e - *v);
+            }
+        }
+    }
+    mask
+}
@@ -0,0 +1,20 @@
+// Reorders `values`.
+pub fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mid = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+    *median
+}
+
+// https://en.wikipedia.org/wiki/Median_absolute_deviation
+//
+// Scaled to estimate the standard deviation of normally distributed values.
+// Reorders `values`.
+pub fn mad(values: &mut [f32], median: f32) -> f32 {
+    for v in values.iter_mut() {
+        *v = (*v - median).abs();
+    }
+    1.4826 * self::median(values)
+}
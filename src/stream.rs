@@ -0,0 +1,390 @@
+use crate::align;
+use crate::image::{self, Image, ImageMemory};
+use crate::integrate::{self, Integration};
+use crate::metrics::{self, FrameMetrics};
+use crate::wcs::Wcs;
+use crate::{ALIGN_THRESHOLD, fits, grade, normalize, trail};
+use std::io::{Read, Seek, Write};
+
+const SPOOL_DIR: &str = "output/.spool";
+
+/// What is kept of a frame between passes, everything but its pixels.
+struct Frame {
+    path: String,
+    metrics: FrameMetrics,
+    points: Vec<(f32, f32, f32)>,
+    trails: Vec<trail::Segment>,
+    log_size: (usize, usize),
+    log_offset: f32,
+}
+
+/// Integrates `paths` like [`integrate::integrate`] with the settings of `images`,
+/// without holding whole frames in memory.
+///
+/// Frames are read from disk one at a time: once to detect stars, grade and
+/// register them, once more to normalize and warp the accepted ones. The output is
+/// built in bands of rows sized so that the warped rows every frame contributes to
+/// one band fit in `max_memory` bytes. Without rejection the bands are summed as
+/// they are warped, with rejection they are spooled to disk grouped by band.
+pub fn integrate(
+    images: &ImageMemory,
+    paths: &[String],
+    max_memory: usize,
+) -> Result<Integration, String> {
+    let mut frames = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        let (channels, _) = match image::load_corrected(path, images.defects.as_ref()) {
+            Ok(frame) => frame,
+            Err(err) => {
+                println!("skipping {err}");
+                continue;
+            }
+        };
+        let processed = image::process_image(&channels);
+        frames.push(Frame {
+            path: path.clone(),
+            metrics: processed.metrics,
+            log_size: (processed.log.width, processed.log.height),
+            log_offset: processed.log_offset(),
+            points: processed.local_max_points,
+            trails: processed.trails,
+        });
+    }
+    if frames.is_empty() {
+        return Err("no frames to stream".to_string());
+    }
+
+    // the reference of `images` if it is one of the frames
+    let reference_path = &images.paths[images.reference()];
+    let reference = frames
+        .iter()
+        .position(|f| &f.path == reference_path)
+        .unwrap_or_else(|| {
+            let metrics: Vec<_> = frames.iter().map(|f| f.metrics).collect();
+            metrics::select_reference(&metrics)
+        });
+    let registrations: Vec<_> = frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            if i == reference {
+                return Some(align::Registration {
+                    transform: align::Similarity::IDENTITY.into(),
+                    pairs: Vec::new(),
+                    residuals: align::Residuals::default(),
+                });
+            }
+            align::register(
+                frame.log_size.0,
+                frame.log_size.1,
+                &frame.points,
+                &frames[reference].points,
+                ALIGN_THRESHOLD,
+                images.distortion_order,
+            )
+        })
+        .collect();
+    let trails: Vec<_> = frames
+        .iter()
+        .zip(registrations.iter())
+        .map(|(f, r)| {
+            let transform = r.as_ref().map(|r| r.transform.offset(f.log_offset));
+            (f.trails.as_slice(), transform)
+        })
+        .collect();
+    let masked = trail::moving(&trails);
+
+    let graded: Vec<_> = frames
+        .iter()
+        .zip(registrations.iter())
+        .map(|(f, r)| (f.metrics, r.as_ref().map(|r| r.residuals.rms)))
+        .collect();
+    let grades = grade::grade(&graded, &images.grading_rules);
+    let weights = integrate::accepted_weights(&grades, &images.weighting)?;
+
+    let (reference_channels, reference_header) =
+        image::load_corrected(&frames[reference].path, images.defects.as_ref())?;
+    let (width, height) = (reference_channels[0].width, reference_channels[0].height);
+    let reference_estimates = reference_channels.each_ref().map(normalize::estimate);
+    drop(reference_channels);
+
+    let frame_weights: Vec<_> = weights.iter().map(|(_, w)| *w).collect();
+    let rows = band_rows(weights.len(), width, height, max_memory);
+    let bands: Vec<_> = (0..height)
+        .step_by(rows)
+        .map(|y0| (y0, (y0 + rows).min(height)))
+        .collect();
+    let zeros = || -> [Image<f32>; 3] {
+        std::array::from_fn(|_| Image {
+            pixels: vec![0.0; width * height],
+            width,
+            height,
+        })
+    };
+    let mut pending = match images.rejection {
+        Some(_) => {
+            std::fs::create_dir_all(SPOOL_DIR).map_err(|err| err.to_string())?;
+            let path = format!("{SPOOL_DIR}/bands.f32");
+            Pending::Spool(Spool::create(&path, width, height, weights.len())?)
+        }
+        None => Pending::Sums(zeros(), zeros()),
+    };
+    for (k, (i, weight)) in weights.iter().enumerate() {
+        let frame = &frames[*i];
+        let (mut channels, _) = image::load_corrected(&frame.path, images.defects.as_ref())?;
+        integrate::normalize_frame(
+            &mut channels,
+            &trail::flagged(&frame.trails, &masked[*i]),
+            &reference_estimates,
+            images.normalization,
+        );
+
+        // only registered frames are accepted
+        let registration = registrations[*i].as_ref().unwrap();
+        let transform = registration.transform.offset(frame.log_offset);
+        for (y0, y1) in bands.iter().copied() {
+            let warped = integrate::warp_rows(&channels, 0, &transform, width, y0, y1);
+            match &mut pending {
+                Pending::Spool(spool) => {
+                    spool.write(k, y0, &warped).map_err(|err| err.to_string())?;
+                }
+                Pending::Sums(sums, weight_sums) => {
+                    let rows = y0 * width..y1 * width;
+                    for c in 0..3 {
+                        for ((sum, weight_sum), v) in sums[c].pixels[rows.clone()]
+                            .iter_mut()
+                            .zip(weight_sums[c].pixels[rows.clone()].iter_mut())
+                            .zip(warped[c].pixels.iter())
+                            .filter(|(_, v)| !v.is_nan())
+                        {
+                            *sum += v * weight;
+                            *weight_sum += weight;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut output = zeros();
+    match pending {
+        Pending::Spool(mut spool) => {
+            for (y0, y1) in bands.iter().copied() {
+                let layers = spool.read_band(y0, y1).map_err(|err| err.to_string())?;
+                for (c, output) in output.iter_mut().enumerate() {
+                    let layers: Vec<_> = layers.iter().map(|l| &l[c]).collect();
+                    let band = integrate::combine(&layers, &frame_weights, images.rejection);
+                    output.pixels[y0 * width..y1 * width].copy_from_slice(&band.pixels);
+                }
+            }
+        }
+        Pending::Sums(sums, weight_sums) => {
+            for ((output, sums), weight_sums) in output.iter_mut().zip(sums).zip(weight_sums) {
+                for ((v, sum), weight) in output
+                    .pixels
+                    .iter_mut()
+                    .zip(sums.pixels)
+                    .zip(weight_sums.pixels)
+                {
+                    *v = if weight > 0.0 { sum / weight } else { 0.0 };
+                }
+            }
+        }
+    }
+
+    let paths: Vec<_> = frames.into_iter().map(|f| f.path).collect();
+    let mut header = integrate::header(images, &paths, &weights);
+    if let Some(rejection) = images.rejection {
+        header.history(&format!(
+            "sigma clipping low {} high {}",
+            rejection.low, rejection.high
+        ));
+    }
+    header.push(
+        "NBANDS",
+        fits::Value::Integer(bands.len() as i64),
+        "number of streamed bands",
+    );
+    // a solution found in memory or one the reference was written with
+    let wcs = images
+        .paths
+        .iter()
+        .position(|p| *p == paths[reference])
+        .and_then(|frame| images.solutions.get(&frame).cloned())
+        .or_else(|| Wcs::from_header(&reference_header));
+
+    Ok(Integration {
+        preview: image::channels_to_srgb(&output),
+        channels: output,
+        weight_maps: Vec::new(),
+        weights,
+        header,
+        wcs,
+        // stars are those of the reference of `images`
+        star_scale: (&paths[reference] == reference_path).then_some(1.0),
+    })
+}
+
+/// Where warped rows go until the output is combined.
+enum Pending {
+    /// Weighted sums and sums of weights of a running mean, enough without rejection.
+    Sums([Image<f32>; 3], [Image<f32>; 3]),
+    Spool(Spool),
+}
+
+/// Warped rows of every frame on disk, little endian, grouped by band so that one
+/// band of all frames is a single read. Removed when dropped.
+struct Spool {
+    path: String,
+    file: std::fs::File,
+    width: usize,
+    frames: usize,
+}
+
+impl Spool {
+    /// Writes the whole spool up front, so that a disk too small for it is
+    /// reported before any frame is warped.
+    fn create(path: &str, width: usize, height: usize, frames: usize) -> Result<Self, String> {
+        let size = (frames * 3 * width * height * std::mem::size_of::<f32>()) as u64;
+        let fail =
+            |err: std::io::Error| format!("failed to reserve {} MiB for {path}: {err}", size >> 20);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(fail)?;
+        // created first so that a partially written spool is removed too
+        let mut spool = Self {
+            path: path.to_string(),
+            file,
+            width,
+            frames,
+        };
+        let zeros = vec![0; 1 << 20];
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(zeros.len() as u64);
+            spool.file.write_all(&zeros[..n as usize]).map_err(fail)?;
+            left -= n;
+        }
+        // some file systems only run out of space when the data is flushed
+        spool.file.sync_all().map_err(fail)?;
+        Ok(spool)
+    }
+
+    // Bands in order, frames within a band and channels within a frame.
+    fn offset(&self, frame: usize, channel: usize, y0: usize, y1: usize) -> u64 {
+        let rows = y0 * self.frames * 3 + (frame * 3 + channel) * (y1 - y0);
+        (rows * self.width * std::mem::size_of::<f32>()) as u64
+    }
+
+    /// Stores the rows of `frame` for the band starting at `y0`.
+    fn write(&mut self, frame: usize, y0: usize, rows: &[Image<f32>; 3]) -> std::io::Result<()> {
+        let y1 = y0 + rows[0].height;
+        for (c, channel) in rows.iter().enumerate() {
+            let bytes: Vec<u8> = channel
+                .pixels
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            let offset = self.offset(frame, c, y0, y1);
+            self.file.seek(std::io::SeekFrom::Start(offset))?;
+            self.file.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Rows `y0..y1` of every frame, a band written with the same bounds.
+    fn read_band(&mut self, y0: usize, y1: usize) -> std::io::Result<Vec<[Image<f32>; 3]>> {
+        let size = std::mem::size_of::<f32>();
+        let mut bytes = vec![0; self.frames * 3 * (y1 - y0) * self.width * size];
+        let offset = self.offset(0, 0, y0, y1);
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        let mut channels = bytes
+            .chunks_exact((y1 - y0) * self.width * size)
+            .map(|c| Image {
+                pixels: c
+                    .chunks_exact(size)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+                width: self.width,
+                height: y1 - y0,
+            });
+        Ok((0..self.frames)
+            .map(|_| std::array::from_fn(|_| channels.next().unwrap()))
+            .collect())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            println!("failed to remove {}: {err}", self.path);
+        }
+    }
+}
+
+/// Rows of the largest band whose warped rows of every frame fit in `max_memory`,
+/// at least one.
+fn band_rows(frames: usize, width: usize, height: usize, max_memory: usize) -> usize {
+    let row_bytes = frames * width * 3 * std::mem::size_of::<f32>();
+    (max_memory / row_bytes.max(1)).clamp(1, height.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(frame: usize, y0: usize, y1: usize, width: usize) -> [Image<f32>; 3] {
+        std::array::from_fn(|c| Image {
+            pixels: (y0 * width..y1 * width)
+                .map(|i| (frame * 1000 + c * 100 + i) as f32)
+                .collect(),
+            width,
+            height: y1 - y0,
+        })
+    }
+
+    #[test]
+    fn spooled_bands() {
+        let (width, height, frames) = (3, 5, 2);
+        let path = std::env::temp_dir().join(format!("spack-{}-bands.f32", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut spool = Spool::create(&path, width, height, frames).unwrap();
+        // reserved before anything is written
+        let size = frames * 3 * width * height * 4;
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+
+        let bands = [(0, 2), (2, 4), (4, 5)];
+        for frame in 0..frames {
+            for (y0, y1) in bands {
+                spool.write(frame, y0, &rows(frame, y0, y1, width)).unwrap();
+            }
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+        for (y0, y1) in bands {
+            let band = spool.read_band(y0, y1).unwrap();
+            assert_eq!(band.len(), frames);
+            for (frame, channels) in band.iter().enumerate() {
+                for (read, written) in channels.iter().zip(rows(frame, y0, y1, width)) {
+                    assert_eq!(read.height, y1 - y0);
+                    assert_eq!(read.pixels, written.pixels);
+                }
+            }
+        }
+        drop(spool);
+        assert!(!std::fs::exists(&path).unwrap());
+    }
+
+    #[test]
+    fn bands_fit_in_memory() {
+        let row_bytes = 200 * 3 * 4;
+        assert_eq!(band_rows(10, 200, 1000, 100 * row_bytes), 10);
+        assert_eq!(band_rows(10, 200, 1000, 10_000 * row_bytes), 1000);
+        // one row even when it does not fit
+        assert_eq!(band_rows(10, 200, 1000, 1), 1);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::image::Image;
+use crate::stats;
+use tint::Srgb;
+
+// https://pixinsight.com/doc/tools/HistogramTransformation/HistogramTransformation.html
+//
+/// Midtones transfer function: maps `0 -> 0`, `midtones -> 0.5` and `1 -> 1`.
+pub fn mtf(midtones: f32, x: f32) -> f32 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        (midtones - 1.0) * x / ((2.0 * midtones - 1.0) * x - midtones)
+    }
+}
+
+/// Screen transfer function, a display only histogram transformation per channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stf {
+    pub shadows: [f32; 3],
+    pub midtones: [f32; 3],
+}
+
+impl Stf {
+    // https://pixinsight.com/tutorials/24-bit-stf/
+    //
+    /// Clips shadows below the background and brings the background to a fixed
+    /// brightness. Linked channels share the averaged parameters, which preserves
+    /// the color balance of the data.
+    pub fn auto(channels: &[Image<f32>; 3], linked: bool) -> Self {
+        let shadows_clipping = -2.8;
+        let target_background = 0.25;
+
+        // enough samples for stable statistics at interactive rates
+        let stride = (channels[0].pixels.len() / 100_000).max(1);
+        let mut medians = [0.0; 3];
+        let mut deviations = [0.0; 3];
+        for (c, channel) in channels.iter().enumerate() {
+            let mut values: Vec<f32> = channel.pixels.iter().step_by(stride).copied().collect();
+            medians[c] = stats::median(&mut values);
+            deviations[c] = stats::mad(&mut values, medians[c]);
+        }
+        if linked {
+            medians = [medians.iter().sum::<f32>() / 3.0; 3];
+            deviations = [deviations.iter().sum::<f32>() / 3.0; 3];
+        }
+
+        let shadows: [f32; 3] = std::array::from_fn(|c| {
+            (medians[c] + shadows_clipping * deviations[c]).clamp(0.0, 1.0)
+        });
+        Self {
+            midtones: std::array::from_fn(|c| mtf(target_background, medians[c] - shadows[c])),
+            shadows,
+        }
+    }
+
+    pub fn apply(&self, channel: usize, x: f32) -> f32 {
+        let shadows = self.shadows[channel];
+        let x = ((x - shadows) / (1.0 - shadows).max(f32::EPSILON)).clamp(0.0, 1.0);
+        mtf(self.midtones[channel], x)
+    }
+
+    /// Stretches linear `channels` for display. The stretched values are what the
+    /// screen shows, so they are stored as sRGB codes rather than encoded again.
+    pub fn preview(&self, channels: &[Image<f32>; 3]) -> Image<Srgb> {
+        let code = |c: usize, x: f32| (self.apply(c, x) * 255.0).round() as u8;
+        Image {
+            pixels: (0..channels[0].pixels.len())
+                .map(|i| {
+                    let [r, g, b] = std::array::from_fn(|c| code(c, channels[c].pixels[i]));
+                    Srgb::from_rgb(r, g, b)
+                })
+                .collect(),
+            width: channels[0].width,
+            height: channels[0].height,
+        }
+    }
+}
+
+/// Permanent stretches of linear data for final output.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Stretch {
+    /// Clips below `shadows` and above `highlights`, then applies [`mtf`].
+    Histogram {
+        shadows: f32,
+        midtones: f32,
+        highlights: f32,
+    },
+    // https://ui.adsabs.harvard.edu/abs/2004PASP..116..133L/abstract
+    //
+    /// Scales every channel of a pixel by the same factor so that star color is
+    /// preserved.
+    Arcsinh { black_point: f32, factor: f32 },
+    // https://ghsastro.co.uk/doc/tools/GeneralizedHyperbolicStretch/GeneralizedHyperbolicStretch.html
+    //
+    /// Generalized hyperbolic stretch: `stretch` is the strength, `local` focuses it
+    /// around `symmetry` (logarithmic at -1, exponential at 0, harmonic at 1), and
+    /// the curve is linear below `shadows` and above `highlights`.
+    Ghs {
+        stretch: f32,
+        local: f32,
+        symmetry: f32,
+        shadows: f32,
+        highlights: f32,
+    },
+}
+
+impl Stretch {
+    /// `histogram <shadows> <midtones> <highlights>`, `arcsinh <black point>
+    /// <factor>` or `ghs <D> <b> <SP> <LP> <HP>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let words: Vec<_> = value.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<f32>()
+                .map_err(|_| format!("{word} is not a number"))
+        };
+        let stretch = match words[..] {
+            ["histogram", shadows, midtones, highlights] => Self::Histogram {
+                shadows: number(shadows)?,
+                midtones: number(midtones)?,
+                highlights: number(highlights)?,
+            },
+            ["arcsinh", black_point, factor] => Self::Arcsinh {
+                black_point: number(black_point)?,
+                factor: number(factor)?,
+            },
+            ["ghs", stretch, local, symmetry, shadows, highlights] => Self::Ghs {
+                stretch: number(stretch)?,
+                local: number(local)?,
+                symmetry: number(symmetry)?,
+                shadows: number(shadows)?,
+                highlights: number(highlights)?,
+            },
+            _ => {
+                return Err(format!(
+                    "expected histogram <shadows> <midtones> <highlights>, arcsinh <black point> <factor> or ghs <D> <b> <SP> <LP> <HP>, got {value}"
+                ));
+            }
+        };
+        stretch.validate()?;
+        Ok(stretch)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            Self::Histogram {
+                shadows,
+                midtones,
+                highlights,
+            } => {
+                (0.0..highlights).contains(&shadows)
+                    && highlights <= 1.0
+                    && (0.0..1.0).contains(&midtones)
+                    && midtones > 0.0
+            }
+            Self::Arcsinh {
+                black_point,
+                factor,
+            } => (0.0..1.0).contains(&black_point) && factor > 0.0,
+            Self::Ghs {
+                stretch,
+                local,
+                symmetry,
+                shadows,
+                highlights,
+            } => {
+                stretch >= 0.0
+                    && local.is_finite()
+                    && (0.0..=symmetry).contains(&shadows)
+                    && (symmetry..=1.0).contains(&highlights)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid stretch {self}"))
+        }
+    }
+}
+
+impl std::fmt::Display for Stretch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Histogram {
+                shadows,
+                midtones,
+                highlights,
+            } => write!(
+                f,
+                "histogram shadows {shadows} midtones {midtones} highlights {highlights}"
+            ),
+            Self::Arcsinh {
+                black_point,
+                factor,
+            } => write!(f, "arcsinh black point {black_point} factor {factor}"),
+            Self::Ghs {
+                stretch,
+                local,
+                symmetry,
+                shadows,
+                highlights,
+            } => write!(
+                f,
+                "ghs D {stretch} b {local} SP {symmetry} LP {shadows} HP {highlights}"
+            ),
+        }
+    }
+}
+
+pub fn stretch(channels: &mut [Image<f32>; 3], stretch: Stretch) -> Result<(), String> {
+    stretch.validate()?;
+    match stretch {
+        Stretch::Histogram {
+            shadows,
+            midtones,
+            highlights,
+        } => {
+            let range = (highlights - shadows).max(f32::EPSILON);
+            for v in channels.iter_mut().flat_map(|c| c.pixels.iter_mut()) {
+                *v = mtf(midtones, ((*v - shadows) / range).clamp(0.0, 1.0));
+            }
+        }
+        Stretch::Arcsinh {
+            black_point,
+            factor,
+        } => {
+            let [r, g, b] = channels;
+            for ((r, g), b) in r
+                .pixels
+                .iter_mut()
+                .zip(g.pixels.iter_mut())
+                .zip(b.pixels.iter_mut())
+            {
+                let mut rgb =
+                    [*r, *g, *b].map(|v| ((v - black_point) / (1.0 - black_point)).max(0.0));
+                let luminance = rgb.iter().sum::<f32>() / 3.0;
+                if luminance > 0.0 {
+                    let scale = (factor * luminance).asinh() / (luminance * factor.asinh());
+                    rgb = rgb.map(|v| v * scale);
+                }
+                // clip by the brightest channel to keep the hue
+                let max = rgb.iter().copied().fold(1.0, f32::max);
+                [*r, *g, *b] = rgb.map(|v| v / max);
+            }
+        }
+        Stretch::Ghs {
+            stretch,
+            local,
+            symmetry,
+            shadows,
+            highlights,
+        } => {
+            if stretch == 0.0 {
+                return Ok(());
+            }
+            let curve = |x: f32| ghs(x, stretch, local, symmetry, shadows, highlights);
+            let (low, high) = (curve(0.0), curve(1.0));
+            for v in channels.iter_mut().flat_map(|c| c.pixels.iter_mut()) {
+                *v = ((curve(*v) - low) / (high - low)).clamp(0.0, 1.0);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unnormalized curve, point symmetric around `symmetry`.
+fn ghs(x: f32, d: f32, b: f32, symmetry: f32, shadows: f32, highlights: f32) -> f32 {
+    // curve and slope at a distance `u` above the symmetry point
+    let curve = |u: f32| -> (f32, f32) {
+        if b == -1.0 {
+            ((1.0 + d * u).ln(), d / (1.0 + d * u))
+        } else if b < 0.0 {
+            let base = 1.0 - b * d * u;
+            (
+                (1.0 - base.powf((b + 1.0) / b)) / (d * (b + 1.0)),
+                base.powf(1.0 / b),
+            )
+        } else if b == 0.0 {
+            (1.0 - (-d * u).exp(), d * (-d * u).exp())
+        } else {
+            let base = 1.0 + b * d * u;
+            (1.0 - base.powf(-1.0 / b), d * base.powf(-1.0 / b - 1.0))
+        }
+    };
+    let symmetric = |x: f32| {
+        let (y, slope) = curve((x - symmetry).abs());
+        ((x - symmetry).signum() * y, slope)
+    };
+
+    if x < shadows {
+        let (y, slope) = symmetric(shadows);
+        y + slope * (x - shadows)
+    } else if x > highlights {
+        let (y, slope) = symmetric(highlights);
+        y + slope * (x - highlights)
+    } else {
+        symmetric(x).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tint::Color;
+
+    #[test]
+    fn faint_linear_data() {
+        // a background far below one 8 bit step with noise smaller still
+        let channel = |seed: usize| Image {
+            pixels: (0..100 * 100)
+                .map(|i| 0.002 + 0.0002 * (((i * 7919 + seed) % 201) as f32 / 100.0 - 1.0))
+                .collect(),
+            width: 100,
+            height: 100,
+        };
+        let channels = [channel(0), channel(1), channel(2)];
+        for linked in [false, true] {
+            let stf = Stf::auto(&channels, linked);
+            assert!(stf.midtones.iter().all(|m| *m > 0.0 && *m < 0.5));
+            assert!((stf.apply(1, 0.002) - 0.25).abs() < 0.02);
+
+            // the noise spreads over many display levels instead of a few
+            let preview = stf.preview(&channels);
+            let levels: std::collections::HashSet<_> = preview
+                .pixels
+                .iter()
+                .map(|p| p.to_linear().g().to_bits())
+                .collect();
+            assert!(levels.len() > 20, "{}", levels.len());
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Stretch::parse("ghs 5 2 0.1 0 0.9"),
+            Ok(Stretch::Ghs {
+                stretch: 5.0,
+                local: 2.0,
+                symmetry: 0.1,
+                shadows: 0.0,
+                highlights: 0.9
+            })
+        );
+        assert_eq!(
+            Stretch::parse("arcsinh 0.01 50"),
+            Ok(Stretch::Arcsinh {
+                black_point: 0.01,
+                factor: 50.0
+            })
+        );
+        assert!(Stretch::parse("histogram 0.1 0.2 1").is_ok());
+        for value in [
+            "ghs 5 2 0.1 0.2 0.9",
+            "ghs 5 2 0.95 0 0.9",
+            "ghs -1 2 0.1 0 0.9",
+            "ghs 5 NaN 0.1 0 0.9",
+            "arcsinh 0 0",
+            "arcsinh 1 10",
+            "histogram 0.5 0.2 0.4",
+            "histogram 0 1 1",
+            "histogram 0 0.5",
+            "levels 0 0.5 1",
+        ] {
+            assert!(Stretch::parse(value).is_err(), "{value}");
+        }
+    }
+}
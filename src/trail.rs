@@ -0,0 +1,378 @@
+use crate::align::Transform;
+use crate::image::{Image, Luminance};
+use crate::stats;
+
+/// Satellite or airplane trail in raw frame coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Segment {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    /// Full width of the masked region.
+    pub width: f32,
+}
+
+impl Segment {
+    pub fn distance(&self, x: f32, y: f32) -> f32 {
+        let dx = self.x2 - self.x1;
+        let dy = self.y2 - self.y1;
+        let length2 = dx * dx + dy * dy;
+        let t = if length2 > 0.0 {
+            (((x - self.x1) * dx + (y - self.y1) * dy) / length2).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (x - (self.x1 + t * dx)).hypot(y - (self.y1 + t * dy))
+    }
+
+    pub fn covers(&self, x: f32, y: f32) -> bool {
+        self.distance(x, y) <= self.width / 2.0
+    }
+}
+
+// https://en.wikipedia.org/wiki/Hough_transform
+//
+// Bright pixels of a binned, background subtracted frame vote for every line through
+// them. Stars only contribute a handful of votes to any line, while a trail
+// contributes its whole length. Nebula edges and gradients collect as many votes,
+// so a line is only a trail if it is a narrow ridge over the sky on both sides.
+pub fn detect<In: Luminance + Copy>(
+    image: &Image<In>,
+    background: f32,
+    noise: f32,
+) -> Vec<Segment> {
+    let bin = 4;
+    let sigma = 3.0;
+    let max_trails = 8;
+    let thetas = 360;
+    // in binned pixels
+    let min_length = (image.width.min(image.height) / bin) as f32 / 4.0;
+    let max_gap = 8.0;
+    let line_width = 1.5;
+
+    let binned = bin_image(image, bin);
+    let threshold = (sigma * noise / bin as f32).max(f32::EPSILON);
+    let mut points: Vec<(f32, f32)> = Vec::new();
+    for y in 0..binned.height {
+        for x in 0..binned.width {
+            if binned.pixels[y * binned.width + x] - background > threshold {
+                points.push((x as f32, y as f32));
+            }
+        }
+    }
+
+    let diagonal = (binned.width as f32).hypot(binned.height as f32);
+    let rhos = 2 * diagonal.ceil() as usize + 1;
+    let trig: Vec<_> = (0..thetas)
+        .map(|t| (std::f32::consts::PI * t as f32 / thetas as f32).sin_cos())
+        .collect();
+    let rho_index = |(x, y): (f32, f32), (sin, cos): (f32, f32)| {
+        (x * cos + y * sin + diagonal).round() as usize
+    };
+
+    let mut accumulator = vec![0u32; thetas * rhos];
+    let vote = |accumulator: &mut [u32], point: (f32, f32), delta: i32| {
+        for (t, sc) in trig.iter().enumerate() {
+            let i = t * rhos + rho_index(point, *sc);
+            accumulator[i] = accumulator[i].saturating_add_signed(delta);
+        }
+    };
+    for point in points.iter() {
+        vote(&mut accumulator, *point, 1);
+    }
+
+    let mut segments = Vec::new();
+    for _ in 0..max_trails {
+        let (peak, votes) = accumulator
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, v)| **v)
+            .map(|(i, v)| (i, *v))
+            .unwrap();
+        if (votes as f32) < min_length {
+            break;
+        }
+        let (sin, cos) = trig[peak / rhos];
+        let rho = (peak % rhos) as f32 - diagonal;
+
+        // walk along the line, splitting runs of points at gaps
+        let (on_line, rest): (Vec<_>, Vec<_>) = points
+            .iter()
+            .partition(|(x, y)| (x * cos + y * sin - rho).abs() <= line_width);
+        let mut along: Vec<_> = on_line
+            .iter()
+            .map(|(x, y)| (-x * sin + y * cos, x * cos + y * sin - rho))
+            .collect();
+        along.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut best = 0..0;
+        let mut start = 0;
+        for i in 1..=along.len() {
+            if i == along.len() || along[i].0 - along[i - 1].0 > max_gap {
+                let length = along[i - 1].0 - along[start].0;
+                if best.is_empty() || length > along[best.end - 1].0 - along[best.start].0 {
+                    best = start..i;
+                }
+                start = i;
+            }
+        }
+
+        for point in on_line.iter() {
+            vote(&mut accumulator, *point, -1);
+        }
+        points = rest;
+
+        let run = &along[best];
+        let (Some(first), Some(last)) = (run.first(), run.last()) else {
+            continue;
+        };
+        if last.0 - first.0 < min_length {
+            continue;
+        }
+        let spread = run.iter().map(|(_, d)| d.abs()).fold(0.0, f32::max);
+        if !ridge(
+            &binned,
+            (sin, cos, rho),
+            (first.0, last.0),
+            spread,
+            threshold,
+        ) {
+            continue;
+        }
+        let to_frame = |t: f32| {
+            let x = rho * cos - t * sin;
+            let y = rho * sin + t * cos;
+            ((x + 0.5) * bin as f32, (y + 0.5) * bin as f32)
+        };
+        let (x1, y1) = to_frame(first.0);
+        let (x2, y2) = to_frame(last.0);
+        segments.push(Segment {
+            x1,
+            y1,
+            x2,
+            y2,
+            // margin for the faint wings of the trail
+            width: (2.0 * spread + 2.0) * bin as f32,
+        });
+    }
+    segments
+}
+
+// Whether the line at `rho` is brighter than the sky on both sides by more than
+// `threshold`, from the median profile across it between `t0` and `t1`. The sides
+// are sampled just outside `spread`, so anything wider than a few binned pixels is
+// as bright there and rejected too.
+fn ridge(
+    binned: &Image<f32>,
+    (sin, cos, rho): (f32, f32, f32),
+    (t0, t1): (f32, f32),
+    spread: f32,
+    threshold: f32,
+) -> bool {
+    let half = spread.ceil() as i32 + 1;
+    let flank = half + 1..=half + 4;
+    let step = 2.0;
+    let samples = ((t1 - t0) / step) as usize + 1;
+    // median over positions along the line, robust to stars crossing it
+    let mut column = Vec::with_capacity(samples);
+    let mut profile = |d: i32| {
+        column.clear();
+        for i in 0..samples {
+            let t = t0 + i as f32 * step;
+            let r = rho + d as f32;
+            let (x, y) = (r * cos - t * sin, r * sin + t * cos);
+            column.extend(binned.sample_bilinear(x, y));
+        }
+        (!column.is_empty()).then(|| stats::median(&mut column))
+    };
+    let mut side = |sign: i32| {
+        let values: Option<Vec<_>> = flank.clone().map(|d| profile(sign * d)).collect();
+        let values = values?;
+        Some(values.iter().sum::<f32>() / values.len() as f32)
+    };
+    let (Some(left), Some(right)) = (side(-1), side(1)) else {
+        return false;
+    };
+    let center: Option<Vec<_>> = (-half..=half).map(&mut profile).collect();
+    let Some(center) = center.and_then(|c| c.into_iter().reduce(f32::max)) else {
+        return false;
+    };
+    // an edge has sky on one side only
+    let contrast = center - left.max(right);
+    contrast > threshold && (left - right).abs() < contrast / 2.0
+}
+
+/// Flags the segments of every frame to mask as trails: all of them in frames
+/// without a transform into the reference, otherwise those that no other
+/// registered frame has in the same place once mapped into the reference. Trails
+/// move from frame to frame, structure in the sky does not.
+pub fn moving(frames: &[(&[Segment], Option<Transform>)]) -> Vec<Vec<bool>> {
+    let mapped: Vec<Option<Vec<_>>> = frames
+        .iter()
+        .map(|(segments, transform)| {
+            let transform = transform.as_ref()?;
+            let mapped = segments
+                .iter()
+                .map(|s| {
+                    let (x1, y1) = transform.apply(s.x1, s.y1);
+                    let (x2, y2) = transform.apply(s.x2, s.y2);
+                    Segment {
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        ..*s
+                    }
+                })
+                .collect();
+            Some(mapped)
+        })
+        .collect();
+    // both segments lie on each other
+    let same = |a: &Segment, b: &Segment| {
+        let tolerance = a.width.max(b.width) / 2.0 + 4.0;
+        [(a, b), (b, a)].iter().all(|(a, b)| {
+            b.distance(a.x1, a.y1) <= tolerance && b.distance(a.x2, a.y2) <= tolerance
+        })
+    };
+    mapped
+        .iter()
+        .zip(frames.iter())
+        .enumerate()
+        .map(|(i, (segments, (unmapped, _)))| {
+            let Some(segments) = segments else {
+                return vec![true; unmapped.len()];
+            };
+            segments
+                .iter()
+                .map(|a| {
+                    !mapped
+                        .iter()
+                        .enumerate()
+                        .any(|(j, other)| j != i && other.iter().flatten().any(|b| same(a, b)))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The segments flagged by [`moving`].
+pub fn flagged(segments: &[Segment], flags: &[bool]) -> Vec<Segment> {
+    segments
+        .iter()
+        .zip(flags.iter())
+        .filter(|(_, flag)| **flag)
+        .map(|(segment, _)| *segment)
+        .collect()
+}
+
+/// True where any segment covers a pixel, for an image offset by `offset` pixels
+/// from the raw frame.
+pub fn mask(segments: &[Segment], width: usize, height: usize, offset: f32) -> Image<bool> {
+    let mut mask = Image {
+        pixels: vec![false; width * height],
+        width,
+        height,
+    };
+    for segment in segments.iter() {
+        let margin = segment.width / 2.0 + 1.0;
+        let xmin = (segment.x1.min(segment.x2) - margin - offset).max(0.0) as usize;
+        let xmax = ((segment.x1.max(segment.x2) + margin - offset).max(0.0) as usize).min(width);
+        let ymin = (segment.y1.min(segment.y2) - margin - offset).max(0.0) as usize;
+        let ymax = ((segment.y1.max(segment.y2) + margin - offset).max(0.0) as usize).min(height);
+        for y in ymin..ymax {
+            for x in xmin..xmax {
+                if segment.covers(x as f32 + offset, y as f32 + offset) {
+                    mask.pixels[y * width + x] = true;
+                }
+            }
+        }
+    }
+    mask
+}
+
+fn bin_image<In: Luminance + Copy>(image: &Image<In>, bin: usize) -> Image<f32> {
+    let width = image.width / bin;
+    let height = image.height / bin;
+    let mut output = Image {
+        pixels: vec![0.0; width * height],
+        width,
+        height,
+    };
+    for y in 0..height * bin {
+        for x in 0..width * bin {
+            output.pixels[(y / bin) * width + x / bin] +=
+                image.pixels[y * image.width + x].luminance();
+        }
+    }
+    let n = (bin * bin) as f32;
+    for v in output.pixels.iter_mut() {
+        *v /= n;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sky(f: impl Fn(usize, usize) -> f32) -> Image<f32> {
+        let (width, height) = (256, 256);
+        Image {
+            pixels: (0..width * height)
+                .map(|i| 0.1 + f(i % width, i / width))
+                .collect(),
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn ridges() {
+        let trail = sky(|x, y| {
+            if x.abs_diff(y / 2 + 40) <= 1 {
+                0.5
+            } else {
+                0.0
+            }
+        });
+        let segments = detect(&trail, 0.1, 0.01);
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].covers(100.0, 120.0));
+
+        // one sided and wide features collect votes but are not trails
+        let edge = sky(|x, _| if x > 128 { 0.4 } else { 0.0 });
+        assert!(detect(&edge, 0.1, 0.01).is_empty());
+        let band = sky(|x, _| if x.abs_diff(128) <= 20 { 0.4 } else { 0.0 });
+        assert!(detect(&band, 0.1, 0.01).is_empty());
+    }
+
+    #[test]
+    fn moving_segments() {
+        let segment = |x1, x2| Segment {
+            x1,
+            y1: 0.0,
+            x2,
+            y2: 100.0,
+            width: 8.0,
+        };
+        let shift = |dx| {
+            Transform::from(crate::align::Similarity {
+                a: 1.0,
+                b: 0.0,
+                tx: dx,
+                ty: 0.0,
+            })
+        };
+        // the same edge seen through a dither, and a trail that moved
+        let first = [segment(10.0, 20.0), segment(50.0, 90.0)];
+        let second = [segment(15.0, 25.0), segment(200.0, 240.0)];
+        let moving = moving(&[
+            (&first, Some(shift(0.0))),
+            (&second, Some(shift(-5.0))),
+            (&first, None),
+        ]);
+        // nothing is known of the unregistered frame
+        assert_eq!(moving, [[false, true], [false, true], [true, true]]);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::{catalog, fits};
+
+// https://fits.gsfc.nasa.gov/fits_wcs.html
+//
+/// Gnomonic (TAN) world coordinate system, in the top-down pixel coordinates of
+/// [`crate::image::Image`] with the origin at the center of the first pixel.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Wcs {
+    /// `(ra, dec)` of the reference pixel in degrees.
+    pub crval: (f64, f64),
+    pub crpix: (f64, f64),
+    /// Pixel offsets from `crpix` to standard coordinates in degrees.
+    pub cd: [[f64; 2]; 2],
+    pub sip: Option<Sip>,
+}
+
+// https://irsa.ipac.caltech.edu/data/SPITZER/docs/files/spitzer/shupeADASS.pdf
+//
+/// Polynomial distortion added to the pixel offsets from `crpix` before `cd`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Sip {
+    pub order: usize,
+    /// `(p, q, coefficient)` of `u^p v^q` for `2 <= p + q <= order`.
+    pub a: Vec<(usize, usize, f64)>,
+    pub b: Vec<(usize, usize, f64)>,
+}
+
+impl Sip {
+    /// Every `(p, q)` of a distortion polynomial.
+    pub fn terms(order: usize) -> Vec<(usize, usize)> {
+        (2..=order)
+            .flat_map(|n| (0..=n).rev().map(move |p| (p, n - p)))
+            .collect()
+    }
+
+    fn distort(&self, u: f64, v: f64) -> (f64, f64) {
+        let polynomial = |coefficients: &[(usize, usize, f64)]| {
+            coefficients
+                .iter()
+                .map(|(p, q, c)| c * u.powi(*p as i32) * v.powi(*q as i32))
+                .sum::<f64>()
+        };
+        (u + polynomial(&self.a), v + polynomial(&self.b))
+    }
+
+    // fixed point iteration, the distortion is small compared to the offsets
+    fn undistort(&self, u: f64, v: f64) -> (f64, f64) {
+        let (mut x, mut y) = (u, v);
+        for _ in 0..20 {
+            let (du, dv) = self.distort(x, y);
+            x += u - du;
+            y += v - dv;
+        }
+        (x, y)
+    }
+}
+
+impl Wcs {
+    pub fn pixel_to_sky(&self, x: f64, y: f64) -> (f64, f64) {
+        let (u, v) = (x - self.crpix.0, y - self.crpix.1);
+        let (u, v) = match &self.sip {
+            Some(sip) => sip.distort(u, v),
+            None => (u, v),
+        };
+        let xi = self.cd[0][0] * u + self.cd[0][1] * v;
+        let eta = self.cd[1][0] * u + self.cd[1][1] * v;
+        catalog::unproject(self.crval, xi, eta)
+    }
+
+    /// `None` on the far side of the sky.
+    pub fn sky_to_pixel(&self, ra: f64, dec: f64) -> Option<(f64, f64)> {
+        let (xi, eta) = catalog::project(self.crval, ra, dec)?;
+        let [[a, b], [c, d]] = self.cd;
+        let det = a * d - b * c;
+        let (u, v) = ((d * xi - b * eta) / det, (a * eta - c * xi) / det);
+        let (u, v) = match &self.sip {
+            Some(sip) => sip.undistort(u, v),
+            None => (u, v),
+        };
+        Some((self.crpix.0 + u, self.crpix.1 + v))
+    }
+
+    /// The same sky on pixels `scale` times smaller, with the image edges in
+    /// place.
+    pub fn scaled(&self, scale: f64) -> Self {
+        // u' = s u, so a coefficient of u^p v^q scales by s^(1 - p - q)
+        let scale_terms = |coefficients: &[(usize, usize, f64)]| {
+            coefficients
+                .iter()
+                .map(|(p, q, c)| (*p, *q, c * scale.powi(1 - (p + q) as i32)))
+                .collect()
+        };
+        Self {
+            crval: self.crval,
+            crpix: (
+                (self.crpix.0 + 0.5) * scale - 0.5,
+                (self.crpix.1 + 0.5) * scale - 0.5,
+            ),
+            cd: self.cd.map(|row| row.map(|v| v / scale)),
+            sip: self.sip.as_ref().map(|sip| Sip {
+                order: sip.order,
+                a: scale_terms(&sip.a),
+                b: scale_terms(&sip.b),
+            }),
+        }
+    }
+
+    /// Arcseconds per pixel.
+    pub fn scale(&self) -> f64 {
+        let [[a, b], [c, d]] = self.cd;
+        (a * d - b * c).abs().sqrt() * 3600.0
+    }
+
+    /// Position angle of the top of the image in degrees east of north.
+    pub fn rotation(&self) -> f64 {
+        let (xi, eta) = (-self.cd[0][1], -self.cd[1][1]);
+        xi.atan2(eta).to_degrees().rem_euclid(360.0)
+    }
+
+    /// Whether east and west are swapped relative to the sky seen with north up.
+    pub fn mirrored(&self) -> bool {
+        let [[a, b], [c, d]] = self.cd;
+        a * d - b * c < 0.0
+    }
+
+    /// Reads a TAN projection with a CD matrix or CDELT and CROTA2, and SIP
+    /// distortion if present. FITS pixels start at 1 and rows are stored top
+    /// down by [`fits::write`].
+    pub fn from_header(header: &fits::Header) -> Option<Self> {
+        let real = |key: &str| header.get(key).and_then(fits::Value::as_f64);
+        let ctype = header.get("CTYPE1")?.as_str()?;
+        if !ctype.starts_with("RA---TAN") {
+            return None;
+        }
+        let cd = match (real("CD1_1"), real("CD1_2"), real("CD2_1"), real("CD2_2")) {
+            (Some(a), b, c, Some(d)) => [[a, b.unwrap_or(0.0)], [c.unwrap_or(0.0), d]],
+            _ => {
+                let (cdelt1, cdelt2) = (real("CDELT1")?, real("CDELT2")?);
+                let rotation = real("CROTA2").unwrap_or(0.0).to_radians();
+                let (cos, sin) = (rotation.cos(), rotation.sin());
+                [[cdelt1 * cos, -cdelt2 * sin], [cdelt1 * sin, cdelt2 * cos]]
+            }
+        };
+        let sip = ctype.ends_with("-SIP").then(|| {
+            let order = real("A_ORDER").unwrap_or(0.0) as usize;
+            let coefficients = |prefix: &str| {
+                Sip::terms(order)
+                    .into_iter()
+                    .filter_map(|(p, q)| Some((p, q, real(&format!("{prefix}_{p}_{q}"))?)))
+                    .collect()
+            };
+            Sip {
+                order,
+                a: coefficients("A"),
+                b: coefficients("B"),
+            }
+        });
+        Some(Self {
+            crval: (real("CRVAL1")?, real("CRVAL2")?),
+            crpix: (real("CRPIX1")? - 1.0, real("CRPIX2")? - 1.0),
+            cd,
+            sip,
+        })
+    }
+
+    pub fn write_header(&self, header: &mut fits::Header) {
+        use fits::Value::{Integer, Real, String};
+        let suffix = if self.sip.is_some() { "-SIP" } else { "" };
+        header.push("CTYPE1", String(format!("RA---TAN{suffix}")), "");
+        header.push("CTYPE2", String(format!("DEC--TAN{suffix}")), "");
+        header.push(
+            "CRVAL1",
+            Real(self.crval.0),
+            "[deg] ra of the reference pixel",
+        );
+        header.push(
+            "CRVAL2",
+            Real(self.crval.1),
+            "[deg] dec of the reference pixel",
+        );
+        header.push("CRPIX1", Real(self.crpix.0 + 1.0), "");
+        header.push("CRPIX2", Real(self.crpix.1 + 1.0), "");
+        for (i, row) in self.cd.iter().enumerate() {
+            for (j, v) in row.iter().enumerate() {
+                header.push(&format!("CD{}_{}", i + 1, j + 1), Real(*v), "");
+            }
+        }
+        if let Some(sip) = &self.sip {
+            for (prefix, coefficients) in [("A", &sip.a), ("B", &sip.b)] {
+                header.push(&format!("{prefix}_ORDER"), Integer(sip.order as i64), "");
+                for (p, q, c) in coefficients.iter() {
+                    header.push(&format!("{prefix}_{p}_{q}"), Real(*c), "");
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Wcs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3}\"/px, rotation {:.2}{}{}",
+            self.scale(),
+            self.rotation(),
+            if self.mirrored() { ", mirrored" } else { "" },
+            match &self.sip {
+                Some(sip) => format!(", sip order {}", sip.order),
+                None => String::new(),
+            }
+        )
+    }
+}
+
+/// `hh:mm:ss.s +dd:mm:ss`
+pub fn sexagesimal(ra: f64, dec: f64) -> String {
+    let split = |v: f64, decimals: usize| {
+        let seconds = v.abs() * 3600.0;
+        let (h, m) = ((seconds / 3600.0).floor(), (seconds / 60.0 % 60.0).floor());
+        format!(
+            "{:02}:{:02}:{:0width$.decimals$}",
+            h,
+            m,
+            seconds % 60.0,
+            width = 3 + decimals
+        )
+    };
+    let sign = if dec < 0.0 { '-' } else { '+' };
+    format!("{} {sign}{}", split(ra / 15.0, 1), split(dec, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+
+    fn solution() -> Wcs {
+        // 1.2"/px, north up and east left rotated by 30 degrees, with some
+        // distortion
+        let scale = 1.2 / 3600.0;
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        Wcs {
+            crval: (83.82, -5.39),
+            crpix: (511.5, 383.5),
+            cd: [[-scale * cos, scale * sin], [-scale * sin, -scale * cos]],
+            sip: Some(Sip {
+                order: 2,
+                a: vec![(2, 0, 2e-6), (1, 1, -1e-6), (0, 2, 3e-6)],
+                b: vec![(2, 0, -1e-6), (1, 1, 2e-6), (0, 2, 1e-6)],
+            }),
+        }
+    }
+
+    fn assert_same_sky(a: &Wcs, b: &Wcs, to_b: impl Fn(f64, f64) -> (f64, f64)) {
+        for (x, y) in [(0.0, 0.0), (1023.0, 0.0), (511.5, 383.5), (100.0, 700.0)] {
+            let (bx, by) = to_b(x, y);
+            let separation = catalog::separation(a.pixel_to_sky(x, y), b.pixel_to_sky(bx, by));
+            // acos resolves a few milliarcseconds
+            assert!(separation * 3600.0 < 0.01, "{separation} at {x} {y}");
+        }
+    }
+
+    #[test]
+    fn pixel_sky_round_trip() {
+        let wcs = solution();
+        for (x, y) in [(0.0, 0.0), (1023.0, 767.0), (511.5, 383.5), (900.0, 20.0)] {
+            let (ra, dec) = wcs.pixel_to_sky(x, y);
+            let (px, py) = wcs.sky_to_pixel(ra, dec).unwrap();
+            assert!((px - x).abs() < 1e-6 && (py - y).abs() < 1e-6, "{px} {py}");
+        }
+        assert!((wcs.scale() - 1.2).abs() < 1e-9);
+        assert!(!wcs.mirrored());
+        // the far side of the sky
+        assert_eq!(wcs.sky_to_pixel(83.82 + 180.0, 5.39), None);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let wcs = solution();
+        let mut header = fits::Header::default();
+        wcs.write_header(&mut header);
+        assert_eq!(Wcs::from_header(&header), Some(wcs.clone()));
+
+        // through the text of a file
+        let path = std::env::temp_dir().join(format!("spack-{}-wcs.fits", std::process::id()));
+        let path = path.to_str().unwrap();
+        let image = Image {
+            pixels: vec![0.0; 4],
+            width: 2,
+            height: 2,
+        };
+        fits::write(path, &[image], &header).unwrap();
+        let (read, _) = fits::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let read = Wcs::from_header(&read).unwrap();
+        assert_eq!(read.sip.as_ref().map(|s| s.a.len()), Some(3));
+        assert_same_sky(&wcs, &read, |x, y| (x, y));
+    }
+
+    #[test]
+    fn cdelt_headers() {
+        use fits::Value::{Real, String};
+        let mut header = fits::Header::default();
+        header.push("CTYPE1", String("RA---TAN".to_string()), "");
+        header.push("CTYPE2", String("DEC--TAN".to_string()), "");
+        for (key, value) in [
+            ("CRVAL1", 10.0),
+            ("CRVAL2", 40.0),
+            ("CRPIX1", 51.0),
+            ("CRPIX2", 41.0),
+            ("CDELT1", -0.001),
+            ("CDELT2", 0.001),
+            ("CROTA2", 90.0),
+        ] {
+            header.push(key, Real(value), "");
+        }
+        let wcs = Wcs::from_header(&header).unwrap();
+        // FITS pixels start at 1
+        assert_eq!(wcs.crpix, (50.0, 40.0));
+        assert_eq!(wcs.pixel_to_sky(50.0, 40.0), (10.0, 40.0));
+        assert!((wcs.scale() - 3.6).abs() < 1e-9);
+        // the top of the image points east
+        assert!((wcs.rotation() - 90.0).abs() < 1e-6, "{}", wcs.rotation());
+
+        let mut other = fits::Header::default();
+        other.push("CTYPE1", String("RA---SIN".to_string()), "");
+        assert_eq!(Wcs::from_header(&other), None);
+    }
+
+    #[test]
+    fn scaled_keeps_the_sky() {
+        let wcs = solution();
+        for scale in [0.5, 2.0, 3.0] {
+            let scaled = wcs.scaled(scale);
+            // pixel edges stay in place
+            assert_same_sky(&wcs, &scaled, |x, y| {
+                ((x + 0.5) * scale - 0.5, (y + 0.5) * scale - 0.5)
+            });
+            assert!((scaled.scale() - 1.2 / scale).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::grade::FrameGrade;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Weighting {
+    Equal,
+    /// `1 / noise^2`
+    InverseVariance,
+    /// Median star SNR.
+    Snr,
+    /// Peak amplitude of the median star over the background noise.
+    PsfSignal,
+    /// Arithmetic over the frame metrics, e.g. `snr / (fwhm * fwhm)`.
+    ///
+    /// Variables: `stars`, `flux`, `fwhm`, `ecc`, `bg`, `noise`, `snr`, `residual`.
+    Expression(String),
+}
+
+impl std::fmt::Display for Weighting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Equal => write!(f, "equal"),
+            Self::InverseVariance => write!(f, "inverse variance"),
+            Self::Snr => write!(f, "snr"),
+            Self::PsfSignal => write!(f, "psf signal"),
+            Self::Expression(expression) => write!(f, "{expression}"),
+        }
+    }
+}
+
+impl Weighting {
+    /// One of the names [`Weighting`] displays, otherwise an expression that is
+    /// checked against empty metrics.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let weighting = match value.trim() {
+            "equal" => Self::Equal,
+            "inverse variance" => Self::InverseVariance,
+            "snr" => Self::Snr,
+            "psf signal" => Self::PsfSignal,
+            expression => Self::Expression(expression.to_string()),
+        };
+        if let Self::Expression(expression) = &weighting {
+            let grade = FrameGrade {
+                frame: 0,
+                metrics: Default::default(),
+                residual: Some(0.0),
+                rejected_by: Vec::new(),
+            };
+            evaluate(expression, &grade)?;
+        }
+        Ok(weighting)
+    }
+}
+
+/// Weights of each frame normalized so that the largest is 1.
+pub fn weights(grades: &[&FrameGrade], weighting: &Weighting) -> Result<Vec<f32>, String> {
+    let mut weights = grades
+        .iter()
+        .map(|grade| weight(grade, weighting))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let max = weights.iter().copied().fold(0.0, f32::max);
+    if max > 0.0 {
+        for w in weights.iter_mut() {
+            *w /= max;
+        }
+    }
+    Ok(weights)
+}
+
+/// Unnormalized weight of a single frame.
+pub fn weight(grade: &FrameGrade, weighting: &Weighting) -> Result<f32, String> {
+    let m = &grade.metrics;
+    let weight = match weighting {
+        Weighting::Equal => 1.0,
+        Weighting::InverseVariance => 1.0 / (m.noise * m.noise).max(f32::EPSILON),
+        Weighting::Snr => m.snr,
+        Weighting::PsfSignal => {
+            // https://en.wikipedia.org/wiki/Gaussian_function#Two-dimensional_Gaussian_function
+            let sigma = m.fwhm / (2.0 * (2.0 * std::f32::consts::LN_2).sqrt());
+            let amplitude = m.flux / (std::f32::consts::TAU * sigma * sigma).max(1.0);
+            amplitude / m.noise.max(f32::EPSILON)
+        }
+        Weighting::Expression(expression) => evaluate(expression, grade)?,
+    };
+    Ok(if weight.is_finite() {
+        weight.max(0.0)
+    } else {
+        0.0
+    })
+}
+
+// https://en.wikipedia.org/wiki/Recursive_descent_parser
+//
+// expr   = term (('+' | '-') term)*
+// term   = factor (('*' | '/') factor)*
+// factor = '-' factor | number | variable | '(' expr ')'
+fn evaluate(expression: &str, grade: &FrameGrade) -> Result<f32, String> {
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        grade,
+    };
+    let value = parser.expr()?;
+    if parser.position != tokens.len() {
+        return Err(format!(
+            "unexpected {:?} in `{expression}`",
+            tokens[parser.position]
+        ));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Variable(String),
+    Op(char),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek()
+                && (c.is_ascii_digit() || c == '.')
+            {
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| format!("invalid number `{number}`"))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() {
+            let mut variable = String::new();
+            while let Some(&c) = chars.peek()
+                && (c.is_ascii_alphanumeric() || c == '_')
+            {
+                variable.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Variable(variable));
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected `{c}` in `{expression}`"));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    grade: &'a FrameGrade,
+}
+
+impl Parser<'_> {
+    fn next_op(&mut self, ops: &str) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Op(op)) if ops.contains(*op) => {
+                self.position += 1;
+                Some(*op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> Result<f32, String> {
+        let mut value = self.term()?;
+        while let Some(op) = self.next_op("+-") {
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f32, String> {
+        let mut value = self.factor()?;
+        while let Some(op) = self.next_op("*/") {
+            let rhs = self.factor()?;
+            value = if op == '*' { value * rhs } else { value / rhs };
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<f32, String> {
+        if self.next_op("-").is_some() {
+            return Ok(-self.factor()?);
+        }
+        if self.next_op("(").is_some() {
+            let value = self.expr()?;
+            if self.next_op(")").is_none() {
+                return Err("expected `)`".to_string());
+            }
+            return Ok(value);
+        }
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        let m = &self.grade.metrics;
+        match token {
+            Some(Token::Number(number)) => Ok(number),
+            Some(Token::Variable(variable)) => match variable.as_str() {
+                "stars" => Ok(m.star_count as f32),
+                "flux" => Ok(m.flux),
+                "fwhm" => Ok(m.fwhm),
+                "ecc" => Ok(m.eccentricity),
+                "bg" => Ok(m.background),
+                "noise" => Ok(m.noise),
+                "snr" => Ok(m.snr),
+                "residual" => Ok(self.grade.residual.unwrap_or(f32::INFINITY)),
+                _ => Err(format!("unknown variable `{variable}`")),
+            },
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::FrameMetrics;
+
+    #[test]
+    fn expression() {
+        let grade = FrameGrade {
+            frame: 0,
+            metrics: FrameMetrics {
+                fwhm: 2.0,
+                snr: 40.0,
+                noise: 0.5,
+                ..Default::default()
+            },
+            residual: Some(0.25),
+            rejected_by: Vec::new(),
+        };
+        let weight = |value: &str| weight(&grade, &Weighting::parse(value).unwrap()).unwrap();
+        assert_eq!(weight("snr / (fwhm * fwhm)"), 10.0);
+        assert_eq!(weight("1 - -residual * 4 + 2 * 3"), 8.0);
+        assert_eq!(weight("inverse variance"), 4.0);
+        assert_eq!(weight("snr"), 40.0);
+        assert_eq!(weight("noise - 1"), 0.0);
+
+        assert!(Weighting::parse("snr /").is_err());
+        assert!(Weighting::parse("(snr").is_err());
+        assert!(Weighting::parse("size * 2").is_err());
+        assert!(Weighting::parse("snr ^ 2").is_err());
+    }
+}